@@ -0,0 +1,17 @@
+//! Feed arbitrary lines to the request parser, which must return an error rather than panic.
+//! Requests that parse must be displayable and copyable.
+
+#![no_main]
+
+use elephantine::request::parse;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(line) = std::str::from_utf8(data) else {
+        return;
+    };
+    if let Ok(req) = parse(line) {
+        let _ = req.to_string();
+        let _ = req.into_owned();
+    }
+});
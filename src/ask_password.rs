@@ -0,0 +1,156 @@
+//! Getting PINs from the systemd password agents with `systemd-ask-password`.
+
+use crate::{
+    config::Config,
+    provider::{self, PinProvider},
+    secret::SecretString,
+    GetPinError, State,
+};
+use std::{mem, time::Duration};
+use zeroize::Zeroize;
+
+/// The command run to ask for the PIN, followed by its options and the prompt.
+const ASK_PASSWORD_COMMAND: &str = "systemd-ask-password";
+
+/// The prompt shown if the client didn't set one with `SETPROMPT` or `OPTION default-prompt`.
+const DEFAULT_PROMPT: &str = "PIN:";
+
+/// A provider that asks for the PIN with `systemd-ask-password`, so that it is shown by whichever
+/// password agent is running, e.g. on the console or in the desktop session.
+///
+/// Only the prompt is shown. If `systemd-ask-password` fails or the PIN is empty, the dialog is
+/// treated as cancelled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AskPasswordProvider {
+    command: Vec<String>,
+    timeout: Option<Duration>,
+    clean_env: bool,
+}
+
+impl Default for AskPasswordProvider {
+    fn default() -> Self {
+        Self {
+            command: vec![ASK_PASSWORD_COMMAND.to_string()],
+            timeout: None,
+            clean_env: false,
+        }
+    }
+}
+
+impl AskPasswordProvider {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run `command` instead of `systemd-ask-password`. It is passed the same arguments.
+    #[must_use]
+    pub fn command(mut self, command: Vec<String>) -> Self {
+        self.command = command;
+        self
+    }
+
+    /// Kill `systemd-ask-password` if the user has not entered the PIN after `timeout`.
+    #[must_use]
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Whether to run `systemd-ask-password` with only the variables needed to show a dialog.
+    #[must_use]
+    pub fn clean_env(mut self, clean: bool) -> Self {
+        self.clean_env = clean;
+        self
+    }
+}
+
+impl From<&Config> for AskPasswordProvider {
+    fn from(config: &Config) -> Self {
+        Self::new()
+            .timeout(config.timeout)
+            .clean_env(config.clean_env)
+    }
+}
+
+impl PinProvider for AskPasswordProvider {
+    /// Get the PIN using `systemd-ask-password`
+    ///
+    /// # Errors
+    /// `GetPinError::Setup` if `systemd-ask-password` could not be run
+    /// `GenPinError::Output` if the PIN is not valid UTF-8
+    /// `GenPinError::Timeout` if the user did not enter the PIN before the timeout
+    /// `GenPinError::Canceled` if `systemd-ask-password` failed or the PIN is empty
+    fn get_pin(&self, state: &State) -> Result<SecretString, GetPinError> {
+        let prompt = state.effective_labels().prompt.unwrap_or(DEFAULT_PROMPT);
+        let mut command = self.command.clone();
+        command.extend([
+            // We enforce the timeout ourselves, instead of the default of 90s
+            "--timeout=0".to_string(),
+            "--".to_string(),
+            // Escaped, as arguments are searched for placeholders
+            prompt.replace('{', "{{").replace('}', "}}"),
+        ]);
+        let mut output = provider::run(&command, state, self.timeout, self.clean_env, &[])?;
+
+        let pin = if output.status.success() {
+            SecretString::try_from(mem::take(&mut output.stdout))
+                .map_err(GetPinError::Output)
+                .map(|mut pin| {
+                    pin.trim_trailing_newline();
+                    pin
+                })
+                .and_then(|pin| {
+                    if pin.expose().is_empty() {
+                        Err(GetPinError::Canceled)
+                    } else {
+                        Ok(pin)
+                    }
+                })
+        } else {
+            Err(GetPinError::Canceled)
+        };
+        output.stdout.zeroize();
+
+        pin
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AskPasswordProvider;
+    use crate::{provider::PinProvider, test_support::state, GetPinError};
+
+    /// A stub of `systemd-ask-password` that runs `script` with its arguments.
+    fn provider(script: &str) -> AskPasswordProvider {
+        AskPasswordProvider::new().command(vec![
+            "sh".to_string(),
+            "-c".to_string(),
+            script.to_string(),
+            "systemd-ask-password".to_string(),
+        ])
+    }
+
+    #[test]
+    fn get_pin() {
+        let test_cases = vec![
+            (vec![], r#"echo "$*""#, Some("--timeout=0 -- PIN:")),
+            (
+                vec!["SETPROMPT Passphrase {x}:"],
+                r#"echo "$3""#,
+                Some("Passphrase {x}:"),
+            ),
+            (vec![], "printf 1234", Some("1234")),
+            (vec![], "echo", None),
+            (vec![], "echo 1234; exit 1", None),
+        ];
+
+        for (requests, script, expected) in test_cases {
+            let pin = provider(script).get_pin(&state(&requests));
+            match expected {
+                Some(expected) => assert_eq!(pin.unwrap().expose(), expected, "{script}"),
+                None => assert!(matches!(pin, Err(GetPinError::Canceled)), "{script}"),
+            }
+        }
+    }
+}
@@ -0,0 +1,83 @@
+//! Error codes used in Assuan `ERR` responses.
+//!
+//! An error code combines a `libgpg-error` error source in the top byte with the error code in the
+//! low 16 bits. gpg-agent inspects both, so it is important to send the right combination, e.g. to
+//! tell a cancelled dialog from one that failed.
+
+/// The `GPG_ERR_SOURCE_PINENTRY` error source.
+pub const SOURCE_PINENTRY: u32 = 5;
+
+/// The `GPG_ERR_SOURCE_USER_1` error source, which libassuan uses for protocol errors.
+pub const SOURCE_USER_1: u32 = 32;
+
+/// The `GPG_ERR_GENERAL` error code.
+pub const GENERAL: u32 = 1;
+
+/// The `GPG_ERR_BAD_PASSPHRASE` error code.
+pub const BAD_PASSPHRASE: u32 = 11;
+
+/// The `GPG_ERR_TIMEOUT` error code.
+pub const TIMEOUT: u32 = 62;
+
+/// The `GPG_ERR_NOT_IMPLEMENTED` error code.
+pub const NOT_IMPLEMENTED: u32 = 69;
+
+/// The `GPG_ERR_CANCELED` error code.
+pub const CANCELED: u32 = 99;
+
+/// The `GPG_ERR_NOT_CONFIRMED` error code.
+pub const NOT_CONFIRMED: u32 = 114;
+
+/// The `GPG_ERR_ASS_LINE_TOO_LONG` error code.
+pub const LINE_TOO_LONG: u32 = 263;
+
+/// The `GPG_ERR_ASS_SYNTAX` error code.
+pub const SYNTAX: u32 = 276;
+
+/// Compose the full error code sent in an `ERR` response from an error source and code.
+///
+/// # Examples
+/// ```
+/// use elephantine::assuan::{error_code, CANCELED, SOURCE_PINENTRY};
+///
+/// assert_eq!(error_code(SOURCE_PINENTRY, CANCELED), 83_886_179);
+/// ```
+#[must_use]
+#[allow(clippy::cast_possible_wrap)]
+pub const fn error_code(source: u32, code: u32) -> i32 {
+    (((source & 0x7f) << 24) | (code & 0xffff)) as i32
+}
+
+/// Compose the full error code for an error from the pinentry source.
+#[must_use]
+pub const fn pinentry_error(code: u32) -> i32 {
+    error_code(SOURCE_PINENTRY, code)
+}
+
+/// Compose the full error code for an error in the Assuan protocol itself.
+#[must_use]
+pub const fn protocol_error(code: u32) -> i32 {
+    error_code(SOURCE_USER_1, code)
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn error_code() {
+        use super::*;
+
+        let test_cases = vec![
+            (BAD_PASSPHRASE, 83_886_091),
+            (TIMEOUT, 83_886_142),
+            (CANCELED, 83_886_179),
+            (NOT_CONFIRMED, 83_886_194),
+        ];
+
+        for (code, expected) in test_cases {
+            assert_eq!(pinentry_error(code), expected);
+        }
+
+        assert_eq!(protocol_error(NOT_IMPLEMENTED), 536_870_981);
+        assert_eq!(protocol_error(LINE_TOO_LONG), 536_871_175);
+    }
+}
@@ -0,0 +1,503 @@
+//! Serving clients on a tokio runtime, so that waiting for a dialog doesn't block a thread.
+//!
+//! Requests are handled as by the blocking [`Listener::listen`], but dialogs are shown by an
+//! [`AsyncPinProvider`]. A fallback pinentry is never used, and passphrases are never rated, even
+//! if `SETQUALITYBAR` was set, as the providers have no way to show a rating.
+
+use crate::{
+    connection::{write_inquire, write_response, InquireReply, LineBuffer},
+    observer,
+    provider::{self, PinProvider},
+    request::Request,
+    response::Response,
+    secret::SecretString,
+    Action, Dispatch, Error, GetPinError, Listener, Reply, State,
+};
+use std::{borrow::Cow, future::Future, io, ops::ControlFlow, process::Output, time::Duration};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+use zeroize::{Zeroize, Zeroizing};
+
+/// A backend that asks the user for a PIN without blocking the runtime.
+///
+/// The async sibling of [`PinProvider`](crate::provider::PinProvider), with the same contract:
+/// it only ever asks for a single PIN, and reports that the user dismissed the dialog with
+/// [`GetPinError::Canceled`].
+pub trait AsyncPinProvider {
+    /// Get a PIN from the user, using `state` to describe the dialog.
+    ///
+    /// # Errors
+    /// If the PIN could not be obtained, e.g. because the user cancelled the dialog.
+    fn get_pin(
+        &self,
+        state: &State,
+    ) -> impl Future<Output = Result<SecretString, GetPinError>> + Send;
+}
+
+/// Run a dialog command with the given state and wait for it to exit, like
+/// [`provider::run`]. The command is killed if it is still running after the timeout.
+///
+/// # Errors
+/// `GetPinError::Setup` if there was a failure to setup the process
+/// `GenPinError::Timeout` if the command did not exit before the timeout
+pub(crate) async fn run(
+    command: &[String],
+    state: &State,
+    timeout: Option<Duration>,
+    clean_env: bool,
+    env: &[(&str, &str)],
+) -> Result<Output, GetPinError> {
+    let child = provider::command(command, state, clean_env, env)?;
+    run_command(child, command, state, timeout).await
+}
+
+/// Run a dialog command prepared by [`provider::command`] and wait for it to exit, like
+/// [`provider::run_command`].
+///
+/// # Errors
+/// `GetPinError::Setup` if there was a failure to setup the process
+/// `GenPinError::Timeout` if the command did not exit before the timeout
+pub(crate) async fn run_command(
+    child: std::process::Command,
+    command: &[String],
+    state: &State,
+    timeout: Option<Duration>,
+) -> Result<Output, GetPinError> {
+    let setup_err = |e| GetPinError::Setup(e, command.to_vec());
+    let timeout = state.timeout(timeout);
+    let mut child = tokio::process::Command::from(child);
+    let spawned = child.kill_on_drop(true).spawn();
+    // Closes our copy of any pipe the command inherited, so that it can be read to the end
+    drop(child);
+    let child = spawned.map_err(setup_err)?;
+
+    // Dropping the child when the timeout expires kills it
+    let output = child.wait_with_output();
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, output)
+            .await
+            .map_err(|_| GetPinError::Timeout(timeout))?,
+        None => output.await,
+    }
+    .map_err(setup_err)
+}
+
+/// Get a PIN from a blocking `provider` on a thread of its own, so that waiting for the user
+/// doesn't stall the runtime.
+///
+/// # Errors
+/// Any error from the provider
+/// `GetPinError::Canceled` if the runtime shut down before the provider ran
+pub(crate) async fn spawn_blocking<P: PinProvider + Send + 'static>(
+    provider: P,
+    state: &State,
+) -> Result<SecretString, GetPinError> {
+    let state = state.clone();
+    tokio::task::spawn_blocking(move || provider.get_pin(&state))
+        .await
+        .unwrap_or_else(|e| match e.try_into_panic() {
+            Ok(panic) => std::panic::resume_unwind(panic),
+            Err(_) => Err(GetPinError::Canceled),
+        })
+}
+
+/// A connection to a client, like [`Connection`](crate::connection::Connection), read with an
+/// idle timeout.
+struct Connection<R, W> {
+    input: R,
+    output: W,
+    /// Responses that have not been written to `output` yet. They may hold a PIN, so they are
+    /// wiped when they are flushed.
+    buffer: Zeroizing<Vec<u8>>,
+    max_line_length: usize,
+    idle_timeout: Option<Duration>,
+}
+
+impl<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin> Connection<R, W> {
+    /// Read the next line from the client, without its line ending. Returns `None` at the end of
+    /// the input.
+    ///
+    /// # Errors
+    /// `TimedOut` if no input arrived within the idle timeout, or a [`LineTooLong`] error if the
+    /// line was skipped.
+    ///
+    /// [`LineTooLong`]: crate::connection::LineTooLong
+    async fn read_line(&mut self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        Ok(self.read_line_into(&mut line).await?.then_some(line))
+    }
+
+    /// Read the next line like [`read_line`](Self::read_line), but into `line`, replacing what it
+    /// held and reusing its allocation. Returns `false` at the end of the input.
+    async fn read_line_into(&mut self, line: &mut String) -> io::Result<bool> {
+        self.flush().await?;
+        let mut buffer = LineBuffer::reuse(std::mem::take(line), self.max_line_length);
+        loop {
+            let available = match self.idle_timeout {
+                Some(timeout) => tokio::time::timeout(timeout, self.input.fill_buf())
+                    .await
+                    .map_err(|_| {
+                        io::Error::new(io::ErrorKind::TimedOut, "No input before the idle timeout")
+                    })?,
+                None => self.input.fill_buf().await,
+            }?;
+            if available.is_empty() {
+                if buffer.is_empty() {
+                    return Ok(false);
+                }
+                break;
+            }
+            let (consumed, done) = buffer.push(available);
+            self.input.consume(consumed);
+            if done {
+                break;
+            }
+        }
+        *line = buffer.finish()?;
+        Ok(true)
+    }
+
+    fn write(&mut self, resp: &Response) -> io::Result<()> {
+        write_response(&mut self.buffer, resp)
+    }
+
+    /// Write the buffered responses to the client.
+    async fn flush(&mut self) -> io::Result<()> {
+        let written = self.output.write_all(&self.buffer).await;
+        self.buffer.zeroize();
+        written?;
+        self.output.flush().await
+    }
+
+    /// Send `INQUIRE <keyword> <args>` to the client and wait for the data it sends back.
+    /// Returns `None` if the client cancelled the inquiry.
+    async fn inquire(&mut self, keyword: &str, args: &str) -> io::Result<Option<SecretString>> {
+        write_inquire(&mut self.buffer, keyword, args)?;
+
+        let mut reply = InquireReply::default();
+        loop {
+            let Some(line) = self.read_line().await?.map(Zeroizing::new) else {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            };
+            if let ControlFlow::Break(data) = reply.push(line)? {
+                return Ok(data);
+            }
+        }
+    }
+}
+
+impl<P: AsyncPinProvider> Listener<P> {
+    /// Listen for Assuan requests and respond to them, like [`listen`](Listener::listen), but
+    /// without blocking the runtime while a dialog is shown.
+    ///
+    /// Input is read with the idle timeout from the config, as the reader can't be wrapped in an
+    /// [`IdleTimeout`](crate::connection::IdleTimeout).
+    ///
+    /// # Errors
+    /// If there was an error reading from `input` or writing to `output`.
+    pub async fn listen_async(
+        &mut self,
+        input: impl AsyncBufRead + Unpin,
+        output: &mut (impl AsyncWrite + Unpin),
+    ) -> Result<(), Error> {
+        let result = self.serve_connection_async(input, output).await;
+        self.end_session(result)
+    }
+
+    async fn serve_connection_async(
+        &mut self,
+        input: impl AsyncBufRead + Unpin,
+        output: &mut (impl AsyncWrite + Unpin),
+    ) -> io::Result<()> {
+        let mut connection = Connection {
+            input,
+            output,
+            buffer: Zeroizing::new(Vec::new()),
+            max_line_length: self.config.max_line_length,
+            idle_timeout: self.config.idle_timeout,
+        };
+        self.observer.on_session();
+        if let Some(greeting) = self.greeting() {
+            self.respond_async(&mut connection, &greeting)?;
+        }
+        log::debug!("Started Assuan server...");
+
+        // Every request is read into the same buffer, which is wiped once the session ends
+        let mut line = Zeroizing::new(String::new());
+        loop {
+            let reply = match connection.read_line_into(&mut line).await {
+                Ok(true) if crate::skipped(&line) => continue,
+                Ok(true) => match self.parse_request(&line) {
+                    Ok(req) => self.handle_req_async(req, &mut connection).await,
+                    Err(resp) => Action::Next(vec![resp]).into(),
+                },
+                Ok(false) => {
+                    log::debug!("Client disconnected without saying goodbye");
+                    break;
+                }
+                Err(e) => Self::read_error(e)?.into(),
+            };
+            let stop = reply.send(|resp| self.respond_async(&mut connection, resp))?;
+            connection.flush().await?;
+            if stop {
+                return Ok(());
+            }
+        }
+        connection.flush().await
+    }
+
+    fn respond_async<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(
+        &self,
+        connection: &mut Connection<R, W>,
+        resp: &Response,
+    ) -> io::Result<()> {
+        observer::observe_response(&*self.observer, resp);
+        connection.write(resp)
+    }
+
+    async fn handle_req_async<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(
+        &mut self,
+        req: Request<'_>,
+        connection: &mut Connection<R, W>,
+    ) -> Reply {
+        match self.dispatch(req) {
+            Dispatch::Done(action) => action.into(),
+            Dispatch::GetPin => {
+                if let Some(reply) = self.responses_without_dialog() {
+                    return reply;
+                }
+                let pin = self.get_pin_async(connection).await;
+                self.pin_responses(pin)
+            }
+            Dispatch::Confirm { one_button } => {
+                let (command, env) = self.confirm_command(one_button);
+                let confirmed = run(
+                    &command,
+                    &self.state,
+                    self.config.timeout,
+                    self.config.clean_env,
+                    env,
+                )
+                .await
+                .and_then(|output| self.confirmed(&output));
+                self.confirmation(one_button, confirmed).into()
+            }
+            Dispatch::Message => {
+                let (command, env) = self.message_command();
+                let acknowledged = run(
+                    &command,
+                    &self.state,
+                    self.config.timeout,
+                    self.config.clean_env,
+                    env,
+                )
+                .await
+                .and_then(|output| self.confirmed(&output).map(|_| ()));
+                self.acknowledgement(acknowledged).into()
+            }
+        }
+    }
+
+    /// Get the PIN from the provider like the blocking listener does, asking for it again if
+    /// `SETREPEAT` was set and fetching a generated PIN with `INQUIRE GENPIN`.
+    ///
+    /// # Errors
+    /// Any error from the provider
+    /// `GenPinError::RepeatMismatch` if the repeated PIN did not match the first on every attempt
+    async fn get_pin_async<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(
+        &self,
+        connection: &mut Connection<R, W>,
+    ) -> Result<SecretString, GetPinError> {
+        let mut state = Cow::Borrowed(&self.state);
+        // `validate` rejects 0, but a config that was never validated still asks once
+        for attempt in 1..=self.config.repeat_attempts.max(1) {
+            let pin = self.provider.get_pin(&state).await;
+            let pin = match settle(pin, &state, connection).await? {
+                ControlFlow::Break(pin) => return Ok(pin),
+                ControlFlow::Continue(pin) => pin,
+            };
+
+            let Some(repeat_state) = state.for_repeat() else {
+                return Ok(pin);
+            };
+            let repeated = self.provider.get_pin(&repeat_state).await;
+            let repeated = match settle(repeated, &state, connection).await? {
+                ControlFlow::Break(pin) => return Ok(pin),
+                ControlFlow::Continue(repeated) => repeated,
+            };
+            // Compared in constant time
+            if pin == repeated {
+                return Ok(pin);
+            }
+            log::debug!("The repeated PIN did not match on attempt {attempt}");
+            state.to_mut().mismatch = true;
+        }
+
+        Err(GetPinError::RepeatMismatch)
+    }
+}
+
+/// Fetch the PIN from the client if the user asked for it to be generated on either prompt of
+/// the dialog described by `state`, like [`Listener::settle`]. A generated PIN doesn't need to be
+/// repeated, so it is returned as `Break`, while a PIN the user entered is `Continue`.
+///
+/// # Errors
+/// Any other error from the provider or the client
+async fn settle<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(
+    pin: Result<SecretString, GetPinError>,
+    state: &State,
+    connection: &mut Connection<R, W>,
+) -> Result<ControlFlow<SecretString, SecretString>, GetPinError> {
+    match pin {
+        Err(GetPinError::Generate) if state.genpin.is_some() => connection
+            .inquire("GENPIN", "")
+            .await
+            .map_err(GetPinError::Inquire)?
+            .ok_or(GetPinError::Canceled)
+            .map(ControlFlow::Break),
+        pin => pin.map(ControlFlow::Continue),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        assuan::{pinentry_error, CANCELED, TIMEOUT},
+        config::Config,
+        provider::CommandProvider,
+        Listener,
+    };
+    use std::time::Duration;
+    use tokio::io::{AsyncWriteExt, BufReader};
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    fn listener(script: &str) -> Listener<CommandProvider> {
+        let command = vec!["sh".to_string(), "-c".to_string(), script.to_string()];
+        let config = Config {
+            command: command.iter().cloned().collect(),
+            ..Config::default()
+        };
+        Listener::new(
+            config,
+            CommandProvider::new(command).timeout(Some(Duration::from_millis(200))),
+        )
+    }
+
+    #[test]
+    fn listen_async() {
+        let test_cases = vec![
+            (
+                "printf 1234",
+                "GETPIN\nBYE\n",
+                "D 1234\nOK\nOK closing connection\n".to_string(),
+            ),
+            (
+                "exit 1",
+                "GETPIN\n",
+                format!("ERR {} Operation cancelled\n", pinentry_error(CANCELED)),
+            ),
+            (
+                "sleep 5",
+                "GETPIN\n",
+                format!("ERR {} Timeout\n", pinentry_error(TIMEOUT)),
+            ),
+            (
+                "printf 1234",
+                "SETREPEAT Again\nGETPIN\n",
+                "OK\nS PIN_REPEATED 1\nD 1234\nOK\n".to_string(),
+            ),
+            (
+                "exit 10",
+                "SETGENPIN Generate\nGETPIN\nD 42\nEND\n",
+                "OK\nINQUIRE GENPIN\nD 42\nOK\n".to_string(),
+            ),
+            (
+                r#"[ -n "$PINENTRY_REPEAT" ] && exit 10; printf 1234"#,
+                "SETGENPIN Generate\nSETREPEAT Again\nGETPIN\nD 42\nEND\n",
+                "OK\nOK\nINQUIRE GENPIN\nS PIN_REPEATED 1\nD 42\nOK\n".to_string(),
+            ),
+            ("exit 0", "CONFIRM\n", "OK\n".to_string()),
+            ("exit 1", "CONFIRM --one-button\n", "OK\n".to_string()),
+            (
+                "test \"$PINENTRY_MESSAGE\" = 1",
+                "MESSAGE\n",
+                "OK\n".to_string(),
+            ),
+        ];
+
+        for (script, input, expected) in test_cases {
+            let mut output = vec![];
+            block_on(listener(script).listen_async(input.as_bytes(), &mut output)).unwrap();
+            assert_eq!(
+                String::from_utf8(output).unwrap(),
+                format!("OK Greetings from Elephantine\n{expected}"),
+                "{script}: {input}"
+            );
+        }
+    }
+
+    #[test]
+    fn listen_async_writes_cached_pin_last() {
+        let command = vec!["printf".to_string(), "1234".to_string()];
+        let config = Config {
+            command: command.iter().cloned().collect(),
+            cache: true,
+            ..Config::default()
+        };
+        let mut listener = Listener::new(config, CommandProvider::new(command));
+        let input = "OPTION allow-external-password-cache\nSETKEYINFO n/FPR\nGETPIN\nGETPIN\n";
+        let mut output = vec![];
+        block_on(listener.listen_async(input.as_bytes(), &mut output)).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "OK Greetings from Elephantine\nOK\nOK\nD 1234\nOK\nS PASSWORD_FROM_CACHE\nD 1234\nOK\n"
+        );
+    }
+
+    #[test]
+    fn spawn_blocking() {
+        use crate::{provider::PinProvider, secret::SecretString, GetPinError, State};
+
+        /// Answers with the name of the thread it ran on.
+        struct ThreadName;
+
+        impl PinProvider for ThreadName {
+            fn get_pin(&self, _state: &State) -> Result<SecretString, GetPinError> {
+                let name = std::thread::current().name().map(ToString::to_string);
+                Ok(SecretString::from(name.unwrap_or_default()))
+            }
+        }
+
+        let pin = block_on(super::spawn_blocking(ThreadName, &State::default())).unwrap();
+        assert_ne!(Some(pin.expose()), std::thread::current().name());
+    }
+
+    #[test]
+    fn listen_async_idle_timeout() {
+        let mut listener = listener("true");
+        listener.config.idle_timeout = Some(Duration::from_millis(100));
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut output = vec![];
+        block_on(async {
+            client.write_all(b"NOP\n").await.unwrap();
+            // The client stays connected without sending anything else
+            listener
+                .listen_async(BufReader::new(server), &mut output)
+                .await
+        })
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!(
+                "OK Greetings from Elephantine\nOK\nERR {} Timeout\n",
+                pinentry_error(TIMEOUT)
+            )
+        );
+    }
+}
@@ -0,0 +1,51 @@
+//! Information about how elephantine was built, e.g. for an application to log which elephantine
+//! it linked.
+
+use crate::build_info;
+
+/// The version of the elephantine crate, e.g. `0.1.0`.
+#[must_use]
+pub fn version() -> &'static str {
+    build_info::PKG_VERSION
+}
+
+/// The short hash of the commit elephantine was built from, or `None` if it wasn't built from a
+/// git repository.
+#[must_use]
+pub fn git_hash() -> Option<&'static str> {
+    build_info::GIT_COMMIT_HASH_SHORT
+}
+
+/// Whether the git repository elephantine was built from had uncommitted changes, or `None` if it
+/// wasn't built from a git repository.
+#[must_use]
+pub fn git_dirty() -> Option<bool> {
+    build_info::GIT_DIRTY
+}
+
+/// When elephantine was built, in RFC 2822 format, e.g. `Thu, 01 Jan 2026 00:00:00 +0000`.
+/// `SOURCE_DATE_EPOCH` overrides it for reproducible builds.
+#[must_use]
+pub fn built_at() -> &'static str {
+    build_info::BUILT_TIME_UTC
+}
+
+/// The cargo features elephantine was built with, in lowercase, e.g. `systemd`.
+#[must_use]
+pub fn features() -> &'static [&'static str] {
+    &build_info::FEATURES_LOWERCASE
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn build() {
+        assert_eq!(super::version(), env!("CARGO_PKG_VERSION"));
+        assert!(super::git_hash().is_none_or(|hash| hash.len() >= 7));
+        assert_eq!(
+            super::features().contains(&"systemd"),
+            cfg!(feature = "systemd")
+        );
+        assert!(super::built_at().ends_with("+0000"));
+    }
+}
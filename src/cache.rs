@@ -0,0 +1,121 @@
+//! An in-memory cache of PINs, keyed by the key they unlock.
+
+use crate::secret::SecretString;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// A cache that can be shared by the listeners of several connections.
+pub type SharedCache = Arc<Mutex<Cache>>;
+
+/// Remembers PINs for a limited time. PINs are wiped from memory when they are evicted.
+#[derive(Debug)]
+pub struct Cache {
+    ttl: Option<Duration>,
+    entries: HashMap<String, Entry>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    pin: SecretString,
+    inserted: Instant,
+}
+
+impl Cache {
+    /// Create a cache whose entries expire after `ttl`, or never if it is `None`.
+    #[must_use]
+    pub fn new(ttl: Option<Duration>) -> Self {
+        Self {
+            ttl,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Get the PIN for `key` if it has not expired.
+    pub fn get(&mut self, key: &str) -> Option<SecretString> {
+        self.evict_expired();
+        self.entries.get(key).map(|e| e.pin.clone())
+    }
+
+    /// Remember the PIN for `key`, replacing any PIN already cached for it.
+    pub fn insert(&mut self, key: &str, pin: SecretString) {
+        self.evict_expired();
+        let inserted = Instant::now();
+        self.entries
+            .insert(key.to_string(), Entry { pin, inserted });
+    }
+
+    /// Forget the PIN for `key`. Returns whether there was one.
+    pub fn remove(&mut self, key: &str) -> bool {
+        self.entries.remove(key).is_some()
+    }
+
+    /// Forget every PIN.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Expire entries after `ttl` instead, or never if it is `None`, counting from when each was
+    /// inserted, e.g. after the config was reloaded.
+    pub fn set_ttl(&mut self, ttl: Option<Duration>) {
+        self.ttl = ttl;
+        self.evict_expired();
+    }
+
+    fn evict_expired(&mut self) {
+        let Some(ttl) = self.ttl else {
+            return;
+        };
+        let now = Instant::now();
+        self.entries
+            .retain(|_, e| now.saturating_duration_since(e.inserted) < ttl);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Cache;
+    use crate::secret::SecretString;
+    use std::time::Duration;
+
+    #[test]
+    fn get() {
+        let mut cache = Cache::new(None);
+        assert_eq!(cache.get("key"), None);
+
+        cache.insert("key", SecretString::from("1234"));
+        assert_eq!(cache.get("key"), Some(SecretString::from("1234")));
+        assert_eq!(cache.get("other"), None);
+
+        cache.insert("key", SecretString::from("5678"));
+        assert_eq!(cache.get("key"), Some(SecretString::from("5678")));
+
+        assert!(cache.remove("key"));
+        assert!(!cache.remove("key"));
+        assert_eq!(cache.get("key"), None);
+    }
+
+    #[test]
+    fn set_ttl() {
+        let mut cache = Cache::new(None);
+        cache.insert("key", SecretString::from("1234"));
+        cache.set_ttl(Some(Duration::from_mins(1)));
+        assert_eq!(cache.get("key"), Some(SecretString::from("1234")));
+        cache.set_ttl(Some(Duration::ZERO));
+        assert!(cache.entries.is_empty());
+
+        cache.insert("key", SecretString::from("1234"));
+        cache.clear();
+        assert_eq!(cache.get("key"), None);
+    }
+
+    #[test]
+    fn get_expired() {
+        let mut cache = Cache::new(Some(Duration::ZERO));
+        cache.insert("key", SecretString::from("1234"));
+        assert_eq!(cache.get("key"), None);
+        assert!(cache.entries.is_empty());
+    }
+}
@@ -1,16 +1,62 @@
-use clap_serde_derive::ClapSerde;
-use color_eyre::Result;
-use serde::{Deserialize, Serialize};
-use std::{fs, path::PathBuf, time::Duration};
+use clap_serde_derive::{
+    clap::{CommandFactory, ValueEnum},
+    ClapSerde,
+};
+use color_eyre::{eyre::WrapErr, Result};
+use serde::{
+    de::{self, DeserializeOwned},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::{
+    borrow::Cow,
+    fmt::{self, Display, Formatter, Write},
+    fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
 
-#[allow(clippy::module_name_repetitions)]
-#[derive(ClapSerde, Serialize, Deserialize, Debug, PartialEq, Eq)]
+/// The config file shared by all users, which the user's own config file is layered on.
+pub const SYSTEM_CONFIG_FILE: &str = "/etc/elephantine.toml";
+
+/// The flavor reported by `GETINFO flavor` if none is configured.
+pub const DEFAULT_FLAVOR: &str = "elephantine";
+
+/// The text of the `OK` a connection starts with if none is configured.
+const DEFAULT_GREETING: &str = "Greetings from Elephantine";
+
+/// The command run to show dialogs if none is configured.
+const DEFAULT_COMMAND: &str = "walker --password";
+
+/// The timeout for dialogs if none is configured.
+const DEFAULT_TIMEOUT_SECS: u64 = 300;
+
+/// How long cached PINs are remembered if no TTL is configured.
+const DEFAULT_CACHE_TTL_SECS: u64 = 600;
+
+/// The longest request line accepted if no limit is configured, in bytes.
+const DEFAULT_MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// How many times the user may try to repeat a PIN if none is configured.
+const DEFAULT_REPEAT_ATTEMPTS: u32 = 3;
+
+/// The exit code dmenu-style tools use when the user presses Escape.
+pub const DEFAULT_CANCEL_EXIT_CODE: i32 = 1;
+
+/// The variable the `env` backend reads the PIN from if none is configured.
+const DEFAULT_PIN_ENV_VAR: &str = "ELEPHANTINE_TEST_PIN";
+const DEFAULT_PASS_ENTRY: &str = "elephantine/{keyinfo}";
+
+#[allow(clippy::module_name_repetitions, clippy::struct_excessive_bools)]
+#[derive(ClapSerde, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct Config {
     /// The X display to use for the dialog.
     #[arg(short = 'D', long, env = "PINENTRY_DISPLAY", value_name = "DISPLAY")]
     pub display: Option<String>,
 
-    /// The tty terminal node name
+    /// The tty terminal node name. If neither this nor `OPTION ttyname` is set, the terminal in
+    /// `GPG_TTY` is used.
     #[arg(short = 'T', long, env = "TTYNAME", value_name = "FILE")]
     pub ttyname: Option<String>,
 
@@ -26,23 +72,31 @@ pub struct Config {
     #[arg(short = 'M', long, env = "LC_MESSAGES", value_name = "STRING")]
     pub lc_messages: Option<String>,
 
-    /// Timeout in seconds for requests that show dialogs to the user.
-    /// E.g. GETPIN, CONFIRM, etc.
+    /// Timeout for requests that show dialogs to the user, e.g. GETPIN, CONFIRM, etc.
+    /// Either a number of seconds or a duration like `5m` or `1m30s`. 0 means no timeout.
+    /// `SETTIMEOUT` from the client overrides it for the rest of the session.
+    #[default(Some(Duration::from_secs(DEFAULT_TIMEOUT_SECS)))]
     #[arg(
         short = 'o',
         long,
         env = "ELEPHANTINE_TIMEOUT",
-        value_name = "SECS",
+        value_name = "DURATION",
         value_parser = parse_duration,
         default_value = "300",
     )]
+    #[serde(
+        default,
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
     pub timeout: Option<Duration>,
 
     /// Grab keyboard only while the window is focused.
     #[arg(short = 'g', long, env = "ELEPHANTINE_NO_LOCAL_GRAB")]
     pub no_local_grab: bool,
 
-    /// Parent window ID (for partitioning).
+    /// The ID of the window dialogs belong to, until the client sets one with
+    /// `OPTION parent-wid`. It is passed to commands as `PINENTRY_PARENT_WID` and `{parent_wid}`.
     #[arg(short = 'W', long, value_name = "WINDOW_ID")]
     pub parent_wid: Option<String>,
 
@@ -50,31 +104,1080 @@ pub struct Config {
     #[arg(short = 'c', long, value_name = "STRING")]
     pub colors: Option<String>,
 
-    /// The alert mode (none, beep, or flash).
-    #[arg(short = 'a', long, value_name = "STRING")]
-    pub ttyalert: Option<String>,
+    /// How to alert the user on their terminal when a dialog shows an error, e.g. after a bad
+    /// passphrase or PINs that didn't match.
+    #[arg(
+        short = 'a',
+        long,
+        value_name = "MODE",
+        value_enum,
+        default_value = "none"
+    )]
+    pub ttyalert: TtyAlert,
 
     /// The command to run the dialog.
     /// It must print the input to stdout.
+    ///
+    /// The command should exit with 1 if the user cancelled the dialog. If the client offers to
+    /// generate a PIN, `PINENTRY_GENPIN` is set to the label of the generate button and the
+    /// command can exit with 10 to use a PIN generated by the client.
+    ///
+    /// The placeholders `{prompt}`, `{desc}`, `{title}`, `{keyinfo}`, `{error}`, and `{parent_wid}`
+    /// in any argument are replaced with the values set by the client. Use `{{` for a literal `{`.
+    ///
+    /// A string is split into arguments like a shell would, so arguments with spaces can be
+    /// quoted. In a config file the command can also be a list of arguments.
+    #[default(DEFAULT_COMMAND.parse().unwrap_or_default())]
     #[arg(
         long,
         value_name = "COMMAND",
-        value_delimiter = ' ',
-        num_args = 1..,
-        default_value = "walker --password",
+        value_parser = Command::from_str,
+        default_value = DEFAULT_COMMAND,
+    )]
+    pub command: Command,
+
+    /// Where to get PINs from. `command` runs `command`, `tty` reads the PIN from the client's
+    /// terminal with echo turned off, like `pinentry-tty`, and `systemd` asks the systemd password
+    /// agents with `systemd-ask-password`. Setting `command` to `@tty` also selects `tty`.
+    /// `env` uses the value of `pin_env_var` without asking the user, e.g. in CI.
+    /// `secret-service` looks the PIN up in the keyring, e.g. GNOME Keyring, before running
+    /// `command`, if the client allows it, and `keychain` does the same with the macOS keychain.
+    /// `pass` reads it from the first line of the `pass_entry` in the password store instead.
+    /// `zenity`, `kdialog`, `wofi`, `rofi` and `dmenu` run that tool with the prompt and
+    /// description as its options, unless `command` is set to something else. `dmenu` needs the
+    /// password patch to hide the PIN.
+    #[arg(
+        long,
+        env = "ELEPHANTINE_BACKEND",
+        value_enum,
+        default_value = "command"
+    )]
+    pub backend: Backend,
+
+    /// How `command` prints the PIN. `raw` takes all of its output as the PIN, while `json`
+    /// expects an object like `{"pin":"1234"}`, `{"cancelled":true}` or `{"generated":true}`,
+    /// the latter if the user asked for a PIN to be generated.
+    #[arg(
+        long,
+        env = "ELEPHANTINE_BACKEND_FORMAT",
+        value_name = "FORMAT",
+        value_enum,
+        default_value = "raw"
+    )]
+    pub backend_format: BackendFormat,
+
+    /// The environment variable the `env` backend reads the PIN from.
+    #[default(DEFAULT_PIN_ENV_VAR.to_string())]
+    #[arg(
+        long,
+        env = "ELEPHANTINE_PIN_ENV_VAR",
+        value_name = "NAME",
+        default_value = DEFAULT_PIN_ENV_VAR
+    )]
+    pub pin_env_var: String,
+
+    /// The entry in the password store the `pass` backend reads the PIN from, where `{keyinfo}`
+    /// is replaced with the key, e.g. `n/FINGERPRINT`.
+    #[default(DEFAULT_PASS_ENTRY.to_string())]
+    #[arg(
+        long,
+        env = "ELEPHANTINE_PASS_ENTRY",
+        value_name = "ENTRY",
+        default_value = DEFAULT_PASS_ENTRY
+    )]
+    pub pass_entry: String,
+
+    /// The exit code with which `command` reports that the user cancelled the dialog, or declined
+    /// a confirmation. It is reported to the client as cancelled, while any other non-zero exit
+    /// code is reported as a general error.
+    #[default(DEFAULT_CANCEL_EXIT_CODE)]
+    #[arg(
+        long,
+        env = "ELEPHANTINE_CANCEL_EXIT_CODE",
+        value_name = "CODE",
+        default_value = "1",
+        allow_negative_numbers = true
+    )]
+    pub cancel_exit_code: i32,
+
+    /// The command to run confirmation dialogs, if different from `command`.
+    /// It must exit with 0 if the user confirmed and `cancel_exit_code` if they declined.
+    #[arg(long, value_name = "COMMAND", value_parser = Command::from_str)]
+    pub confirm_command: Command,
+
+    /// The command to show messages with a single button, if different from `command`.
+    /// It must exit with 0 or `cancel_exit_code` once the user dismissed the message.
+    #[arg(long, value_name = "COMMAND", value_parser = Command::from_str)]
+    pub message_command: Command,
+
+    /// A pinentry to fall back to if `command` can't be run, e.g. `pinentry-curses`.
+    /// It is sent the dialog settings over the Assuan protocol.
+    #[arg(long, value_name = "COMMAND", value_parser = Command::from_str)]
+    pub fallback_command: Command,
+
+    /// The flavor reported to the client by `GETINFO flavor`.
+    #[default(Some(DEFAULT_FLAVOR.to_string()))]
+    #[arg(
+        long,
+        env = "ELEPHANTINE_FLAVOR",
+        value_name = "STRING",
+        default_value = DEFAULT_FLAVOR
     )]
-    pub command: Vec<String>,
+    pub flavor: Option<String>,
+
+    /// The text of the `OK` a connection starts with. If it is empty, a bare `OK` is sent.
+    #[default(Some(DEFAULT_GREETING.to_string()))]
+    #[arg(
+        long,
+        env = "ELEPHANTINE_GREETING",
+        value_name = "STRING",
+        default_value = DEFAULT_GREETING
+    )]
+    pub greeting: Option<String>,
+
+    /// Don't send the `OK` a connection starts with, e.g. when requests are pipelined.
+    #[arg(long, env = "ELEPHANTINE_NO_GREETING", value_name = "BOOL")]
+    pub no_greeting: bool,
+
+    /// Strip a single trailing newline (`\n` or `\r\n`) from the command's output.
+    #[default(true)]
+    #[arg(long, env = "ELEPHANTINE_TRIM_TRAILING_NEWLINE", value_name = "BOOL")]
+    pub trim_trailing_newline: bool,
+
+    /// Run commands with only the variables needed to show a dialog, i.e. `DISPLAY`,
+    /// `WAYLAND_DISPLAY`, `XAUTHORITY`, `XDG_RUNTIME_DIR`, `PATH` and the `PINENTRY_*` variables,
+    /// instead of inheriting the whole environment.
+    #[arg(long, env = "ELEPHANTINE_CLEAN_ENV", value_name = "BOOL")]
+    pub clean_env: bool,
+
+    /// Read the PIN from this file descriptor rather than from the stdout of `command`. The
+    /// command inherits the write end of a pipe as this descriptor and must write the PIN to it,
+    /// and any output to stdout is ignored. The PIN is read until every copy of the descriptor is
+    /// closed, so the command must not leave a process running in the background that holds it.
+    /// Only supported on Unix.
+    #[arg(long, env = "ELEPHANTINE_PIN_FD", value_name = "FD")]
+    pub pin_fd: Option<i32>,
+
+    /// Rate passphrases for the quality bar with a built-in estimate based on their length and
+    /// the kinds of characters in them, instead of asking the client with `INQUIRE QUALITY`.
+    #[arg(long, env = "ELEPHANTINE_LOCAL_QUALITY", value_name = "BOOL")]
+    pub local_quality: bool,
+
+    /// Reject PINs with fewer characters than this, replying with the error set by `SETERROR`
+    /// or a default message.
+    #[arg(long, env = "ELEPHANTINE_MIN_PIN_LENGTH", value_name = "NUMBER")]
+    pub min_pin_length: Option<usize>,
+
+    /// Refuse `GETPIN` without showing a dialog after this many failed attempts in a row in a
+    /// session, so that a client stuck in a loop can't keep showing dialogs. An attempt fails if
+    /// the dialog fails or is cancelled, or if the client rejects the PIN with `SETERROR`. The
+    /// count restarts after a PIN is accepted and on `RESET`. There is no limit if unset.
+    #[arg(long, env = "ELEPHANTINE_MAX_ATTEMPTS", value_name = "NUMBER")]
+    pub max_attempts: Option<u32>,
+
+    /// How many times the user may enter a PIN and its repetition after `SETREPEAT` before the
+    /// client is told they don't match. After a mismatch the dialog is shown again with the
+    /// `SETREPEATERROR` text. Must be at least 1, as the PIN is always asked for once.
+    #[default(DEFAULT_REPEAT_ATTEMPTS)]
+    #[arg(
+        long,
+        env = "ELEPHANTINE_REPEAT_ATTEMPTS",
+        value_name = "NUMBER",
+        default_value = "3"
+    )]
+    pub repeat_attempts: u32,
+
+    /// The longest request line accepted from the client, in bytes, not counting the newline.
+    /// Longer lines are discarded and answered with an error.
+    #[default(DEFAULT_MAX_LINE_LENGTH)]
+    #[arg(
+        long,
+        env = "ELEPHANTINE_MAX_LINE_LENGTH",
+        value_name = "BYTES",
+        default_value = "65536"
+    )]
+    pub max_line_length: usize,
+
+    /// Commands the client may not use, e.g. `CONFIRM`, separated by commas. They are answered
+    /// with the "not implemented" error without being handled, and the session goes on. Only
+    /// the command keyword is matched, ignoring case. `GENPIN` disables `SETGENPIN` and
+    /// `SETGENPIN_TT`, so the client can't offer to generate the PIN.
+    #[arg(
+        long,
+        env = "ELEPHANTINE_DISABLED_COMMANDS",
+        value_name = "COMMANDS",
+        value_delimiter = ','
+    )]
+    pub disabled_commands: Vec<String>,
+
+    /// Disable core dumps and lock the process's memory so secrets aren't written to disk.
+    /// Has no effect on platforms other than Unix.
+    #[arg(long, env = "ELEPHANTINE_HARDEN", value_name = "BOOL")]
+    pub harden: bool,
+
+    /// Remember PINs in memory, keyed by the key they unlock, so the user isn't asked again for
+    /// the same key. The client must also allow it with `OPTION allow-external-password-cache`.
+    ///
+    /// The cache is shared by all connections with `--socket`. Otherwise it only lasts as long
+    /// as a single connection.
+    #[arg(long, env = "ELEPHANTINE_CACHE", value_name = "BOOL")]
+    pub cache: bool,
+
+    /// Store PINs the `secret-service` or `keychain` backend didn't find once the user entered
+    /// them.
+    #[arg(long, env = "ELEPHANTINE_STORE_IN_KEYRING", value_name = "BOOL")]
+    pub store_in_keyring: bool,
+
+    /// How long a cached PIN is remembered, as a number of seconds or a duration like `10m`.
+    #[default(Some(Duration::from_secs(DEFAULT_CACHE_TTL_SECS)))]
+    #[arg(
+        long,
+        env = "ELEPHANTINE_CACHE_TTL",
+        value_name = "DURATION",
+        value_parser = parse_duration,
+        default_value = "600",
+    )]
+    #[serde(
+        default,
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub cache_ttl: Option<Duration>,
+
+    /// Close a connection if the client sends no request for this long, as a number of seconds
+    /// or a duration like `5m`. By default connections are kept open until the client closes them.
+    #[arg(
+        long,
+        env = "ELEPHANTINE_IDLE_TIMEOUT",
+        value_name = "DURATION",
+        value_parser = parse_duration
+    )]
+    #[serde(
+        default,
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub idle_timeout: Option<Duration>,
 }
 
+impl Config {
+    /// Start from the default config, which is the same as running without flags or config
+    /// files, to override only some options.
+    ///
+    /// ```
+    /// use elephantine::config::Config;
+    /// use std::time::Duration;
+    ///
+    /// let config = Config::builder()
+    ///     .command(["echo", "1234"])
+    ///     .timeout(Some(Duration::from_secs(5)))
+    ///     .build();
+    /// assert_eq!(*config.command, ["echo", "1234"]);
+    /// assert_eq!(config.flavor, Config::default().flavor);
+    /// ```
+    #[must_use]
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder(Self::default())
+    }
+
+    /// Layer the `files` that exist, in order, and then `flags` on the default config. Each
+    /// overrides only the options it sets, so e.g. a flag overrides a single option of the user's
+    /// config file, which in turn overrides the system's.
+    ///
+    /// `flags` should come from a command made by [`without_defaults`], so that options not
+    /// given on the command line don't override the files with their default values.
+    ///
+    /// # Errors
+    /// If one of the files can't be read or parsed.
+    pub fn layered(files: &[PathBuf], flags: <Self as ClapSerde>::Opt) -> Result<Self> {
+        let mut config = Self::default();
+        for path in files.iter().filter(|path| path.exists()) {
+            config.update(read_opt(path)?);
+        }
+        Ok(config.merge(flags))
+    }
+
+    /// The command that shows dialogs: `command`, or the preset of the backend if `command` is
+    /// empty or the default.
+    #[must_use]
+    pub fn backend_command(&self) -> Cow<'_, [String]> {
+        match self.backend.preset() {
+            Some(preset) if self.command.is_empty() || self.command == Self::default().command => {
+                Cow::Owned(preset.iter().map(ToString::to_string).collect())
+            }
+            _ => Cow::Borrowed(&self.command),
+        }
+    }
+
+    /// Check that the config can be used to show dialogs.
+    ///
+    /// # Errors
+    /// If the `command` backend is selected but no command is configured, `pin_fd` is one of the
+    /// standard streams, or `repeat_attempts` is 0.
+    pub fn validate(&self) -> Result<()> {
+        if self.backend == Backend::Command && self.command.is_empty() {
+            return Err(color_eyre::eyre::eyre!("No backend command configured"));
+        }
+        if let Some(fd) = self.pin_fd.filter(|fd| !(3..).contains(fd)) {
+            return Err(color_eyre::eyre::eyre!(
+                "pin_fd must be 3 or more, as {fd} is not free for the PIN"
+            ));
+        }
+        if self.repeat_attempts == 0 {
+            return Err(color_eyre::eyre::eyre!(
+                "repeat_attempts must be at least 1, as the PIN is always asked for once"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Serialize the config to TOML, with the description of each option as a comment above it.
+    /// Options without a value are left out.
+    ///
+    /// # Errors
+    /// If the config can't be represented as TOML.
+    pub fn to_commented_toml(&self) -> Result<String> {
+        let table = toml::Table::try_from(self)?;
+        let mut out = String::new();
+        for arg in <Self as ClapSerde>::Opt::command().get_arguments() {
+            let key = arg.get_id().as_str();
+            let Some(value) = table.get(key) else {
+                continue;
+            };
+            if !out.is_empty() {
+                out.push('\n');
+            }
+            if let Some(help) = arg.get_long_help().or_else(|| arg.get_help()) {
+                for (i, paragraph) in help.to_string().split("\n\n").enumerate() {
+                    if i > 0 {
+                        writeln!(out, "#")?;
+                    }
+                    for line in wrap(paragraph, COMMENT_WIDTH - "# ".len()) {
+                        writeln!(out, "# {line}")?;
+                    }
+                }
+            }
+            writeln!(out, "{key} = {value}")?;
+        }
+        Ok(out)
+    }
+}
+
+/// Builds a [`Config`], see [`Config::builder`]. Options without a setter can be set on the
+/// built config.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigBuilder(Config);
+
+impl ConfigBuilder {
+    /// The command that shows dialogs and its arguments.
+    #[must_use]
+    pub fn command<S: Into<String>>(mut self, command: impl IntoIterator<Item = S>) -> Self {
+        self.0.command = Command(command.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Timeout for requests that show dialogs. `None` or zero means no timeout.
+    #[must_use]
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.0.timeout = timeout;
+        self
+    }
+
+    /// The flavor reported by `GETINFO flavor`.
+    #[must_use]
+    pub fn flavor(mut self, flavor: impl Into<String>) -> Self {
+        self.0.flavor = Some(flavor.into());
+        self
+    }
+
+    #[must_use]
+    pub fn build(self) -> Config {
+        self.0
+    }
+}
+
+/// The width that comments in generated config files are wrapped to.
+const COMMENT_WIDTH: usize = 80;
+
+/// Wrap text at word boundaries into lines no longer than `width` where possible.
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        if !line.is_empty() && line.len() + 1 + word.len() > width {
+            lines.push(std::mem::take(&mut line));
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(word);
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+/// The backends that can get PINs.
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// Run `command`
+    #[default]
+    Command,
+    /// Read the PIN from the terminal
+    Tty,
+    /// Run `systemd-ask-password`
+    Systemd,
+    /// Read the PIN from an environment variable
+    Env,
+    /// Look the PIN up in the Secret Service, or else run `command`
+    #[serde(rename = "secret-service")]
+    SecretService,
+    /// Look the PIN up in the macOS keychain, or else run `command`
+    Keychain,
+    /// Read the PIN from `pass`, or else run `command`
+    Pass,
+    /// Run `zenity`
+    Zenity,
+    /// Run `kdialog`
+    Kdialog,
+    /// Run `wofi`
+    Wofi,
+    /// Run `rofi`
+    Rofi,
+    /// Run `dmenu`
+    Dmenu,
+}
+
+impl Backend {
+    /// The command run by a backend that is a preset for a dialog tool. Each of the tools exits
+    /// with 1, the default cancel exit code, if the user cancels.
+    #[must_use]
+    pub fn preset(self) -> Option<&'static [&'static str]> {
+        match self {
+            Self::Zenity => Some(&[
+                "zenity",
+                "--entry",
+                "--hide-text",
+                "--title={title}",
+                "--text={desc}",
+            ]),
+            Self::Kdialog => Some(&["kdialog", "--title", "{title}", "--password", "{desc}"]),
+            Self::Wofi => Some(&["wofi", "--dmenu", "--password", "--prompt", "{prompt}"]),
+            Self::Rofi => Some(&[
+                "rofi",
+                "-dmenu",
+                "-password",
+                "-p",
+                "{prompt}",
+                "-mesg",
+                "{desc}",
+            ]),
+            Self::Dmenu => Some(&["dmenu", "-P", "-p", "{prompt}"]),
+            _ => None,
+        }
+    }
+}
+
+/// How to alert the user on their terminal.
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TtyAlert {
+    /// Don't alert the user
+    #[default]
+    None,
+    /// Ring the bell
+    Beep,
+    /// Flash the screen
+    Flash,
+}
+
+/// How a backend command prints the PIN.
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendFormat {
+    /// The PIN, as it is
+    #[default]
+    Raw,
+    /// An object like `{"pin":"1234"}`
+    Json,
+}
+
+/// A command and its arguments.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+#[serde(transparent)]
+pub struct Command(Vec<String>);
+
+impl Deref for Command {
+    type Target = [String];
+
+    fn deref(&self) -> &[String] {
+        &self.0
+    }
+}
+
+impl From<Vec<String>> for Command {
+    fn from(args: Vec<String>) -> Self {
+        Self(args)
+    }
+}
+
+impl FromIterator<String> for Command {
+    fn from_iter<I: IntoIterator<Item = String>>(iter: I) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+impl FromStr for Command {
+    type Err = shell_words::ParseError;
+
+    /// Split a command line into arguments like a shell would, honouring quotes and escapes.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        shell_words::split(s).map(Self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Command {
+    /// Accept either a list of arguments, which is used as-is, or a command line string.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Line(String),
+            Args(Vec<String>),
+        }
+
+        match Repr::deserialize(deserializer)? {
+            Repr::Line(line) => line.parse().map_err(de::Error::custom),
+            Repr::Args(args) => Ok(Self(args)),
+        }
+    }
+}
+
+/// Parse a duration like `5m` or `1m30s`. A bare number is a number of seconds.
 fn parse_duration(s: &str) -> Result<Duration> {
-    Ok(Duration::from_secs(s.parse::<u64>()?))
+    match s.parse::<u64>() {
+        Ok(secs) => Ok(Duration::from_secs(secs)),
+        Err(_) => humantime::parse_duration(s).map_err(Into::into),
+    }
+}
+
+/// A config field holding a duration. In the partial config it is wrapped in another `Option`.
+trait DurationField {
+    fn from_duration(duration: Duration) -> Self;
+}
+
+impl DurationField for Option<Duration> {
+    fn from_duration(duration: Duration) -> Self {
+        Some(duration)
+    }
+}
+
+impl DurationField for Option<Option<Duration>> {
+    fn from_duration(duration: Duration) -> Self {
+        Some(Some(duration))
+    }
+}
+
+/// Deserialize a duration written as a number of seconds or as a string like `5m`.
+fn deserialize_duration<'de, D: Deserializer<'de>, T: DurationField>(
+    deserializer: D,
+) -> std::result::Result<T, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Repr {
+        Secs(u64),
+        Text(String),
+    }
+
+    let duration = match Repr::deserialize(deserializer)? {
+        Repr::Secs(secs) => Duration::from_secs(secs),
+        Repr::Text(text) => parse_duration(&text).map_err(de::Error::custom)?,
+    };
+    Ok(T::from_duration(duration))
+}
+
+/// Serialize a duration in the same form as it is written in a config file, e.g. `5m`.
+#[allow(clippy::ref_option)] // The signature is dictated by serde
+fn serialize_duration<S: Serializer>(
+    duration: &Option<Duration>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    match duration {
+        Some(duration) => serializer.collect_str(&humantime::format_duration(*duration)),
+        None => serializer.serialize_none(),
+    }
 }
 
 impl TryFrom<&PathBuf> for Config {
     type Error = color_eyre::Report;
 
+    /// Read a config file in the format given by its extension, falling back to TOML.
     fn try_from(path: &PathBuf) -> Result<Self> {
-        let data = fs::read_to_string(path)?;
-        toml::from_str(&data).map_err(Into::into)
+        read_opt(path).map(Self::from)
+    }
+}
+
+/// Read the options set in a config file. Parsing the optional form lets fields missing from the
+/// file take their default values, or those of the layers below.
+fn read_opt(path: &Path) -> Result<<Config as ClapSerde>::Opt> {
+    let data =
+        fs::read_to_string(path).wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+    let format = Format::from_path(path);
+    format
+        .parse(&data)
+        .wrap_err_with(|| format!("Failed to parse {} as {format}", path.display()))
+}
+
+/// Remove the default values of the options of [`Config`] from `command`, so that parsing leaves
+/// the options that weren't given on the command line or in the environment unset. The default
+/// values are still shown in the help of the original command.
+#[must_use]
+pub fn without_defaults(command: clap::Command) -> clap::Command {
+    let options = <Config as ClapSerde>::Opt::command();
+    command.mut_args(|arg| {
+        if options.get_arguments().any(|o| o.get_id() == arg.get_id()) {
+            arg.default_value(None)
+        } else {
+            arg
+        }
+    })
+}
+
+/// The formats a config file can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl Format {
+    fn from_path(path: &Path) -> Self {
+        let extension = path
+            .extension()
+            .map(|e| e.to_string_lossy().to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("yaml" | "yml") => Self::Yaml,
+            Some("json") => Self::Json,
+            _ => Self::Toml,
+        }
+    }
+
+    fn parse<T: DeserializeOwned>(self, data: &str) -> Result<T> {
+        match self {
+            Self::Toml => toml::from_str(data).map_err(Into::into),
+            #[cfg(feature = "yaml")]
+            Self::Yaml => serde_yaml::from_str(data).map_err(Into::into),
+            #[cfg(feature = "json")]
+            Self::Json => serde_json::from_str(data).map_err(Into::into),
+            #[allow(unreachable_patterns)]
+            _ => Err(color_eyre::eyre::eyre!(
+                "Support for {self} was not enabled when elephantine was built"
+            )),
+        }
+    }
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Toml => write!(f, "TOML"),
+            Self::Yaml => write!(f, "YAML"),
+            Self::Json => write!(f, "JSON"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Backend, Command, Config};
+    use clap_serde_derive::ClapSerde;
+    use std::io::Write;
+    use std::time::Duration;
+
+    /// Write `data` to a temporary file with the given extension.
+    fn config_file(extension: &str, data: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "elephantine-{}-{extension}.{extension}",
+            std::process::id()
+        ));
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn builder() {
+        let test_cases = vec![
+            (Config::builder(), Config::default()),
+            (
+                Config::builder().command(["echo", "1234"]),
+                Config {
+                    command: vec!["echo".to_string(), "1234".to_string()].into(),
+                    ..Config::default()
+                },
+            ),
+            (
+                Config::builder().timeout(None).flavor("test"),
+                Config {
+                    timeout: None,
+                    flavor: Some("test".to_string()),
+                    ..Config::default()
+                },
+            ),
+        ];
+
+        for (builder, expected) in test_cases {
+            assert_eq!(builder.build(), expected);
+        }
+    }
+
+    #[test]
+    fn default_matches_clap() {
+        use clap_serde_derive::clap::{CommandFactory, FromArgMatches};
+
+        type Opt = <Config as ClapSerde>::Opt;
+
+        // Ignore the environment, so only the defaults of the arguments are parsed
+        let matches = Opt::command()
+            .mut_args(|arg| arg.env(None))
+            .try_get_matches_from(["elephantine"])
+            .unwrap();
+        let parsed = Config::from(Opt::from_arg_matches(&matches).unwrap());
+        assert_eq!(parsed, Config::default());
+        assert_eq!(*parsed.command, ["walker", "--password"]);
+        assert_eq!(parsed.timeout, Some(Duration::from_mins(5)));
+    }
+
+    #[test]
+    fn try_from_format() {
+        let test_cases = vec![
+            ("toml", "command = [\"rofi\", \"-dmenu\"]\n"),
+            ("yaml", "command: [rofi, -dmenu]\n"),
+            ("yml", "command:\n  - rofi\n  - -dmenu\n"),
+            ("json", "{\"command\": [\"rofi\", \"-dmenu\"]}"),
+            ("conf", "command = [\"rofi\", \"-dmenu\"]\n"),
+        ];
+
+        for (extension, data) in test_cases {
+            let path = config_file(extension, data);
+            let config = Config::try_from(&path);
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(
+                *config.unwrap().command,
+                vec!["rofi", "-dmenu"],
+                "{extension}"
+            );
+        }
+    }
+
+    #[test]
+    fn command() {
+        let test_cases = vec![
+            ("walker --password", vec!["walker", "--password"]),
+            (
+                r#"my-tool --arg "with space" 'single quoted'"#,
+                vec!["my-tool", "--arg", "with space", "single quoted"],
+            ),
+            (r"escaped\ space", vec!["escaped space"]),
+            ("printf %s {prompt}", vec!["printf", "%s", "{prompt}"]),
+        ];
+
+        for (line, expected) in test_cases {
+            assert_eq!(*line.parse::<Command>().unwrap(), expected, "{line}");
+            let config: <Config as ClapSerde>::Opt =
+                toml::from_str(&format!("command = {line:?}")).unwrap();
+            assert_eq!(*Config::from(config).command, expected, "{line}");
+        }
+
+        assert!("unterminated 'quote".parse::<Command>().is_err());
+
+        let config: <Config as ClapSerde>::Opt =
+            toml::from_str(r#"command = ["my tool", "--arg"]"#).unwrap();
+        assert_eq!(*Config::from(config).command, vec!["my tool", "--arg"]);
+    }
+
+    #[test]
+    fn backend_command() {
+        let test_cases = vec![
+            (
+                Backend::Zenity,
+                None,
+                vec![
+                    "zenity",
+                    "--entry",
+                    "--hide-text",
+                    "--title={title}",
+                    "--text={desc}",
+                ],
+            ),
+            (
+                Backend::Kdialog,
+                None,
+                vec!["kdialog", "--title", "{title}", "--password", "{desc}"],
+            ),
+            (
+                Backend::Wofi,
+                None,
+                vec!["wofi", "--dmenu", "--password", "--prompt", "{prompt}"],
+            ),
+            (
+                Backend::Rofi,
+                None,
+                vec![
+                    "rofi",
+                    "-dmenu",
+                    "-password",
+                    "-p",
+                    "{prompt}",
+                    "-mesg",
+                    "{desc}",
+                ],
+            ),
+            (Backend::Dmenu, None, vec!["dmenu", "-P", "-p", "{prompt}"]),
+            (
+                Backend::Dmenu,
+                Some(vec![]),
+                vec!["dmenu", "-P", "-p", "{prompt}"],
+            ),
+            (Backend::Zenity, Some(vec!["my-dialog"]), vec!["my-dialog"]),
+            (Backend::Command, None, vec!["walker", "--password"]),
+            (Backend::Command, Some(vec!["my-dialog"]), vec!["my-dialog"]),
+        ];
+
+        for (backend, command, expected) in test_cases {
+            let mut config = Config {
+                backend,
+                ..Default::default()
+            };
+            if let Some(command) = command {
+                config.command = command.iter().map(ToString::to_string).collect();
+            }
+            assert_eq!(
+                config.backend_command(),
+                expected,
+                "{backend:?} {:?}",
+                config.command
+            );
+        }
+    }
+
+    #[test]
+    fn validate() {
+        let test_cases = vec![
+            (Backend::Command, vec!["pass"], None, Ok(())),
+            (
+                Backend::Command,
+                vec![],
+                None,
+                Err("No backend command configured"),
+            ),
+            (Backend::Tty, vec![], None, Ok(())),
+            (Backend::Env, vec![], None, Ok(())),
+            (Backend::Command, vec!["pass"], Some(3), Ok(())),
+            (
+                Backend::Command,
+                vec!["pass"],
+                Some(1),
+                Err("pin_fd must be 3 or more, as 1 is not free for the PIN"),
+            ),
+            (
+                Backend::Command,
+                vec!["pass"],
+                Some(-1),
+                Err("pin_fd must be 3 or more, as -1 is not free for the PIN"),
+            ),
+        ];
+
+        for (backend, command, pin_fd, expected) in test_cases {
+            let config = Config {
+                backend,
+                command: command.iter().map(ToString::to_string).collect(),
+                pin_fd,
+                ..Default::default()
+            };
+            assert_eq!(
+                config.validate().map_err(|e| e.to_string()),
+                expected.map_err(ToString::to_string),
+                "{backend:?} {command:?} {pin_fd:?}"
+            );
+        }
+
+        let config = Config {
+            repeat_attempts: 0,
+            ..Default::default()
+        };
+        assert_eq!(
+            config.validate().map_err(|e| e.to_string()),
+            Err("repeat_attempts must be at least 1, as the PIN is always asked for once".into()),
+        );
+    }
+
+    #[test]
+    fn parse_duration() {
+        let test_cases = vec![
+            ("300", Some(Duration::from_mins(5))),
+            ("5m", Some(Duration::from_mins(5))),
+            ("1m30s", Some(Duration::from_secs(90))),
+            ("0", Some(Duration::ZERO)),
+            ("", None),
+            ("5 parsecs", None),
+            ("-1", None),
+        ];
+
+        for (input, expected) in test_cases {
+            assert_eq!(super::parse_duration(input).ok(), expected, "{input}");
+        }
+    }
+
+    #[test]
+    fn deserialize_duration() {
+        let test_cases = vec![
+            ("timeout = 300", Duration::from_mins(5)),
+            ("timeout = \"5m\"", Duration::from_mins(5)),
+            ("timeout = \"1m 30s\"", Duration::from_secs(90)),
+            ("timeout = 0", Duration::ZERO),
+        ];
+
+        for (input, expected) in test_cases {
+            let config: <Config as ClapSerde>::Opt = toml::from_str(input).unwrap();
+            assert_eq!(Config::from(config).timeout, Some(expected), "{input}");
+        }
+        assert!(toml::from_str::<<Config as ClapSerde>::Opt>("timeout = \"soon\"").is_err());
+    }
+
+    #[test]
+    fn to_commented_toml() {
+        let generated = Config::default().to_commented_toml().unwrap();
+        assert!(generated.contains(
+            "# The flavor reported to the client by `GETINFO flavor`\nflavor = \"elephantine\"\n"
+        ));
+        assert!(generated.contains("command = [\"walker\", \"--password\"]\n"));
+        assert!(generated.contains("timeout = \"5m\"\n"));
+        assert!(!generated.contains("display"));
+        assert!(generated.lines().all(|l| l.len() <= 80), "{generated}");
+
+        let parsed: <Config as ClapSerde>::Opt = toml::from_str(&generated).unwrap();
+        assert_eq!(Config::from(parsed), Config::default());
+    }
+
+    #[test]
+    fn wrap() {
+        let test_cases = vec![
+            ("", 10, vec![]),
+            ("one two three", 10, vec!["one two", "three"]),
+            ("one  two\nthree", 20, vec!["one two three"]),
+            ("unbreakable-word x", 5, vec!["unbreakable-word", "x"]),
+        ];
+
+        for (text, width, expected) in test_cases {
+            assert_eq!(super::wrap(text, width), expected, "{text}");
+        }
+    }
+
+    #[test]
+    fn layered() {
+        use super::{without_defaults, Config};
+        use clap_serde_derive::{
+            clap::{CommandFactory, FromArgMatches},
+            ClapSerde,
+        };
+
+        type Opt = <Config as ClapSerde>::Opt;
+
+        let system = config_file(
+            "system.toml",
+            "flavor = \"system\"\ngreeting = \"system\"\n",
+        );
+        let user = config_file("user.toml", "flavor = \"user\"\ncache_ttl = \"5m\"\n");
+        let missing = std::env::temp_dir().join("elephantine-missing.toml");
+
+        let test_cases = vec![
+            (
+                vec![],
+                vec![],
+                ("elephantine", "Greetings from Elephantine", 600),
+            ),
+            (vec![&system], vec![], ("system", "system", 600)),
+            (
+                vec![&system, &missing, &user],
+                vec![],
+                ("user", "system", 300),
+            ),
+            (
+                vec![&system, &user],
+                vec!["--flavor", "flag"],
+                ("flag", "system", 300),
+            ),
+            (
+                vec![&system, &user],
+                vec!["--cache-ttl", "60"],
+                ("user", "system", 60),
+            ),
+            (
+                vec![],
+                vec!["--greeting", "flag"],
+                ("elephantine", "flag", 600),
+            ),
+        ];
+
+        for (files, flags, (flavor, greeting, ttl)) in test_cases {
+            let files: Vec<_> = files.into_iter().cloned().collect();
+            let matches = without_defaults(Opt::command())
+                .try_get_matches_from(std::iter::once("elephantine").chain(flags.clone()))
+                .unwrap();
+            let config = Config::layered(&files, Opt::from_arg_matches(&matches).unwrap()).unwrap();
+
+            assert_eq!(
+                config.flavor.as_deref(),
+                Some(flavor),
+                "{files:?} {flags:?}"
+            );
+            assert_eq!(
+                config.greeting.as_deref(),
+                Some(greeting),
+                "{files:?} {flags:?}"
+            );
+            assert_eq!(
+                config.cache_ttl,
+                Some(Duration::from_secs(ttl)),
+                "{files:?} {flags:?}"
+            );
+            // Options set nowhere keep their defaults
+            assert!(config.trim_trailing_newline);
+        }
+
+        std::fs::remove_file(system).unwrap();
+        std::fs::remove_file(user).unwrap();
+    }
+
+    #[test]
+    fn try_from_invalid_file() {
+        let path = config_file("json", "command = [\"rofi\"]\n");
+        let error = Config::try_from(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            error.to_string(),
+            format!("Failed to parse {} as JSON", path.display())
+        );
+    }
+
+    #[test]
+    fn try_from_partial_file() {
+        let path = std::env::temp_dir().join(format!("elephantine-{}.toml", std::process::id()));
+        std::fs::File::create(&path)
+            .unwrap()
+            .write_all(b"command = [\"rofi\", \"-dmenu\"]\nflavor = \"rofi\"\n")
+            .unwrap();
+
+        let config = Config::try_from(&path).unwrap();
+        assert_eq!(*config.command, vec!["rofi", "-dmenu"]);
+        assert_eq!(config.flavor.as_deref(), Some("rofi"));
+        assert!(config.trim_trailing_newline);
+        assert!(!config.no_local_grab);
+        assert!(!config.cache);
+        assert_eq!(config.cache_ttl, Some(Duration::from_mins(10)));
+        std::fs::remove_file(path).unwrap();
     }
 }
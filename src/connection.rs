@@ -0,0 +1,626 @@
+//! Reading requests from and writing responses to a client.
+
+use crate::{
+    response::{escape, Response},
+    secret::SecretString,
+};
+use std::{
+    borrow::Cow,
+    fmt::{self, Display, Formatter},
+    io::{self, BufRead, Write},
+    ops::ControlFlow,
+};
+#[cfg(unix)]
+use std::{
+    fs,
+    io::Read,
+    os::{
+        fd::AsRawFd,
+        unix::{
+            fs::PermissionsExt,
+            net::{UnixListener, UnixStream},
+        },
+    },
+    path::Path,
+    time::Duration,
+};
+use thiserror::Error;
+use zeroize::{Zeroize, Zeroizing};
+
+/// The initial capacity of the buffer responses are written to, which is enough for most sessions
+/// so that it rarely has to grow.
+const BUFFER_CAPACITY: usize = 4096;
+
+/// A line from the client was longer than the limit. The line was discarded.
+#[derive(Debug, Error)]
+pub(crate) struct LineTooLong(usize);
+
+impl Display for LineTooLong {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Line is longer than {} bytes", self.0)
+    }
+}
+
+impl LineTooLong {
+    /// Whether `e` was returned because a line was too long.
+    pub(crate) fn is(e: &io::Error) -> bool {
+        e.get_ref()
+            .is_some_and(<dyn std::error::Error + Send + Sync>::is::<Self>)
+    }
+}
+
+/// A line from the client was not valid UTF-8. The line was discarded.
+#[derive(Debug, Error)]
+pub(crate) struct NotUtf8;
+
+impl Display for NotUtf8 {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Line is not valid UTF-8")
+    }
+}
+
+impl NotUtf8 {
+    /// Whether `e` was returned because a line was not valid UTF-8.
+    pub(crate) fn is(e: &io::Error) -> bool {
+        e.get_ref()
+            .is_some_and(<dyn std::error::Error + Send + Sync>::is::<Self>)
+    }
+}
+
+/// A reader that fails with `TimedOut` if no input arrives within a timeout, so that a client
+/// that stays idle can be disconnected. Without a timeout it reads as usual.
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct IdleTimeout<R> {
+    inner: R,
+    timeout: Option<Duration>,
+}
+
+#[cfg(unix)]
+impl<R> IdleTimeout<R> {
+    pub fn new(inner: R, timeout: Option<Duration>) -> Self {
+        Self { inner, timeout }
+    }
+}
+
+#[cfg(unix)]
+impl<R: Read + AsRawFd> Read for IdleTimeout<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(timeout) = self.timeout {
+            let mut fd = libc::pollfd {
+                fd: self.inner.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let millis = libc::c_int::try_from(timeout.as_millis()).unwrap_or(libc::c_int::MAX);
+            // SAFETY: `poll` only accesses the one `pollfd` passed to it
+            match unsafe { libc::poll(&raw mut fd, 1, millis) } {
+                0 => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "No input before the idle timeout",
+                    ))
+                }
+                n if n < 0 => return Err(io::Error::last_os_error()),
+                _ => {}
+            }
+        }
+        self.inner.read(buf)
+    }
+}
+
+/// Listen on a Unix domain socket at `path` that only its owner can connect to, as the PINs
+/// served on it are shared between connections.
+///
+/// The mode is set right after binding, so a client of another user could only connect in
+/// between if the umask allowed it, which [`same_user`] guards against.
+///
+/// # Errors
+/// If the socket can't be bound or its mode can't be set.
+#[cfg(unix)]
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    let socket = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    Ok(socket)
+}
+
+/// Whether the peer of `stream` runs as the effective user of this process.
+///
+/// # Errors
+/// If the credentials of the peer can't be got.
+#[cfg(unix)]
+pub fn same_user(stream: &UnixStream) -> io::Result<bool> {
+    // SAFETY: `geteuid` always succeeds
+    Ok(peer_uid(stream)? == unsafe { libc::geteuid() })
+}
+
+/// The user the peer of `stream` ran as when it connected.
+#[cfg(target_os = "linux")]
+fn peer_uid(stream: &UnixStream) -> io::Result<libc::uid_t> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len =
+        libc::socklen_t::try_from(std::mem::size_of::<libc::ucred>()).map_err(io::Error::other)?;
+    // SAFETY: `getsockopt` writes at most `len` bytes to `cred`
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&raw mut cred).cast(),
+            &raw mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+/// The user the peer of `stream` ran as when it connected.
+#[cfg(all(unix, not(target_os = "linux")))]
+fn peer_uid(stream: &UnixStream) -> io::Result<libc::uid_t> {
+    let (mut uid, mut gid) = (0, 0);
+    // SAFETY: `getpeereid` only writes to the two ids passed to it
+    if unsafe { libc::getpeereid(stream.as_raw_fd(), &raw mut uid, &raw mut gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(uid)
+}
+
+/// Collects a line from the chunks of input it arrives in, discarding it if it is longer than
+/// the limit.
+pub(crate) struct LineBuffer {
+    line: Vec<u8>,
+    too_long: bool,
+    max_line_length: usize,
+}
+
+impl LineBuffer {
+    /// Read the line into the allocation of `buffer`, discarding what it held.
+    pub(crate) fn reuse(buffer: String, max_line_length: usize) -> Self {
+        let mut line = buffer.into_bytes();
+        line.clear();
+        Self {
+            line,
+            too_long: false,
+            max_line_length,
+        }
+    }
+
+    /// Whether nothing of the line has been read yet.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.line.is_empty() && !self.too_long
+    }
+
+    /// Take the line from the start of `available`. Returns the number of bytes that belong to
+    /// the line and whether it is complete.
+    pub(crate) fn push(&mut self, available: &[u8]) -> (usize, bool) {
+        let (consumed, content, done) = match available.iter().position(|&b| b == b'\n') {
+            Some(i) => (i + 1, &available[..i], true),
+            None => (available.len(), available, false),
+        };
+        // Leave room for a carriage return before the newline
+        if self.too_long || self.line.len() + content.len() > self.max_line_length.saturating_add(1)
+        {
+            // The line may be a secret sent in reply to an `INQUIRE`
+            self.line.zeroize();
+            self.too_long = true;
+        } else {
+            self.line.extend_from_slice(content);
+        }
+        (consumed, done)
+    }
+
+    /// The line without its line ending.
+    ///
+    /// # Errors
+    /// A [`LineTooLong`] error if the line was longer than the limit, or a [`NotUtf8`] error if
+    /// it is not valid UTF-8.
+    pub(crate) fn finish(mut self) -> io::Result<String> {
+        // Tolerate clients that send CRLF line endings
+        if self.line.ends_with(b"\r") {
+            self.line.pop();
+        }
+        if self.too_long || self.line.len() > self.max_line_length {
+            self.line.zeroize();
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                LineTooLong(self.max_line_length),
+            ));
+        }
+
+        String::from_utf8(self.line).map_err(|e| {
+            e.into_bytes().zeroize();
+            io::Error::new(io::ErrorKind::InvalidData, NotUtf8)
+        })
+    }
+}
+
+/// Make room for `additional` bytes in `buffer`. If it has to grow, its contents are moved to a
+/// larger buffer and the old one is wiped, as reallocating it could leave copies of a PIN behind
+/// in freed memory.
+fn reserve(buffer: &mut Zeroizing<Vec<u8>>, additional: usize) {
+    if buffer.capacity() - buffer.len() >= additional {
+        return;
+    }
+    let mut grown = Vec::with_capacity((buffer.len() + additional).max(buffer.capacity() * 2));
+    grown.extend_from_slice(buffer);
+    // The old buffer is wiped as it is dropped
+    *buffer = Zeroizing::new(grown);
+}
+
+/// Counts the bytes written to it.
+struct Length(usize);
+
+impl fmt::Write for Length {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0 += s.len();
+        Ok(())
+    }
+}
+
+/// Write `resp` as a line to `buffer`, which grows without leaving copies of a PIN behind.
+pub(crate) fn write_response(buffer: &mut Zeroizing<Vec<u8>>, resp: &Response) -> io::Result<()> {
+    let mut len = Length(0);
+    fmt::write(&mut len, format_args!("{resp}\n")).map_err(io::Error::other)?;
+    reserve(buffer, len.0);
+    writeln!(buffer, "{resp}")
+}
+
+/// Write `INQUIRE <keyword> <args>` to `buffer`, percent escaping `args`.
+pub(crate) fn write_inquire(
+    buffer: &mut Zeroizing<Vec<u8>>,
+    keyword: &str,
+    args: &str,
+) -> io::Result<()> {
+    // The arguments may be secret, e.g. the passphrase for `INQUIRE QUALITY`
+    let escaped = escape(args);
+    let written = if escaped.is_empty() {
+        reserve(buffer, "INQUIRE \n".len() + keyword.len());
+        writeln!(buffer, "INQUIRE {keyword}")
+    } else {
+        reserve(buffer, "INQUIRE  \n".len() + keyword.len() + escaped.len());
+        writeln!(buffer, "INQUIRE {keyword} {escaped}")
+    };
+    if let Cow::Owned(mut escaped) = escaped {
+        escaped.zeroize();
+    }
+    written
+}
+
+/// Collects the data a client sends back in reply to an `INQUIRE`.
+#[derive(Default)]
+pub(crate) struct InquireReply {
+    data: Zeroizing<String>,
+}
+
+impl InquireReply {
+    /// Take the next line of the reply. Breaks with the data once the client ended the reply, or
+    /// with `None` if it cancelled the inquiry.
+    ///
+    /// # Errors
+    /// If the line is not part of a reply.
+    pub(crate) fn push(
+        &mut self,
+        mut line: Zeroizing<String>,
+    ) -> io::Result<ControlFlow<Option<SecretString>>> {
+        match line.as_str() {
+            "END" => Ok(ControlFlow::Break(Some(SecretString::from(
+                self.data.as_str(),
+            )))),
+            "CAN" => Ok(ControlFlow::Break(None)),
+            _ => match line.strip_prefix("D ") {
+                Some(payload) => {
+                    let decoded = urlencoding::decode(payload)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    self.data.push_str(&decoded);
+                    if let Cow::Owned(mut decoded) = decoded {
+                        decoded.zeroize();
+                    }
+                    Ok(ControlFlow::Continue(()))
+                }
+                None => {
+                    line.zeroize();
+                    Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Unexpected reply to INQUIRE",
+                    ))
+                }
+            },
+        }
+    }
+}
+
+/// Asks the client for more information while a request is being handled.
+pub trait Inquire {
+    /// Send `INQUIRE <keyword> <args>` to the client and wait for the data it sends back.
+    ///
+    /// `args` are percent escaped before they are sent. Returns `None` if the client cancelled
+    /// the inquiry.
+    ///
+    /// # Errors
+    /// If there was an error talking to the client.
+    fn inquire(&mut self, keyword: &str, args: &str) -> io::Result<Option<SecretString>>;
+}
+
+/// A client that cancels every inquiry, for handling requests without a connection.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NoInquire;
+
+impl Inquire for NoInquire {
+    fn inquire(&mut self, _keyword: &str, _args: &str) -> io::Result<Option<SecretString>> {
+        Ok(None)
+    }
+}
+
+/// A connection to a client speaking the Assuan protocol.
+///
+/// Responses are buffered until they are flushed, which happens before every read so that the
+/// client is never left waiting for a reply we haven't sent.
+pub(crate) struct Connection<R, W> {
+    input: R,
+    output: W,
+    /// Responses that have not been written to `output` yet. They may hold a PIN, so they are
+    /// kept in a buffer that is wiped when it is flushed, unlike that of a `BufWriter`.
+    buffer: Zeroizing<Vec<u8>>,
+    max_line_length: usize,
+}
+
+impl<R: BufRead, W: Write> Connection<R, W> {
+    pub(crate) fn new(input: R, output: W) -> Self {
+        Self {
+            input,
+            output,
+            buffer: Zeroizing::new(Vec::with_capacity(BUFFER_CAPACITY)),
+            max_line_length: usize::MAX,
+        }
+    }
+
+    /// Discard lines longer than `max` bytes, not counting the newline, instead of reading
+    /// them into memory.
+    pub(crate) fn max_line_length(mut self, max: usize) -> Self {
+        self.max_line_length = max;
+        self
+    }
+
+    /// Read the next line from the client, without its line ending. Returns `None` at the end of
+    /// the input.
+    ///
+    /// A line longer than the limit is skipped and reported with a [`LineTooLong`] error, and a
+    /// line that isn't valid UTF-8 with a [`NotUtf8`] error, after which the next line can be
+    /// read.
+    pub(crate) fn read_line(&mut self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        Ok(self.read_line_into(&mut line)?.then_some(line))
+    }
+
+    /// Read the next line like [`read_line`](Self::read_line), but into `line`, replacing what it
+    /// held and reusing its allocation. Returns `false` at the end of the input.
+    pub(crate) fn read_line_into(&mut self, line: &mut String) -> io::Result<bool> {
+        self.flush()?;
+        let mut buffer = LineBuffer::reuse(std::mem::take(line), self.max_line_length);
+        loop {
+            let available = match self.input.fill_buf() {
+                Ok(available) => available,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if available.is_empty() {
+                if buffer.is_empty() {
+                    return Ok(false);
+                }
+                break;
+            }
+            let (consumed, done) = buffer.push(available);
+            self.input.consume(consumed);
+            if done {
+                break;
+            }
+        }
+        *line = buffer.finish()?;
+        Ok(true)
+    }
+
+    pub(crate) fn write(&mut self, resp: &Response) -> io::Result<()> {
+        write_response(&mut self.buffer, resp)
+    }
+
+    /// Write the buffered responses to the client.
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        let written = self.output.write_all(&self.buffer);
+        self.buffer.zeroize();
+        written?;
+        self.output.flush()
+    }
+}
+
+impl<R: BufRead, W: Write> Inquire for Connection<R, W> {
+    fn inquire(&mut self, keyword: &str, args: &str) -> io::Result<Option<SecretString>> {
+        write_inquire(&mut self.buffer, keyword, args)?;
+
+        let mut reply = InquireReply::default();
+        loop {
+            let Some(line) = self.read_line()?.map(Zeroizing::new) else {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            };
+            if let ControlFlow::Break(data) = reply.push(line)? {
+                return Ok(data);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Connection, Inquire};
+    use crate::{response::Response, secret::SecretString};
+    use indoc::indoc;
+    use zeroize::Zeroizing;
+
+    #[test]
+    fn inquire() {
+        let test_cases = vec![
+            ("D 42\nEND\n", Some("42")),
+            ("D 4\nD 2%0A\nEND\n", Some("42\n")),
+            ("END\n", Some("")),
+            ("CAN\n", None),
+        ];
+
+        for (input, expected) in test_cases {
+            let mut output = vec![];
+            let mut connection = Connection::new(input.as_bytes(), &mut output);
+            let reply = connection.inquire("QUALITY", "pass%word").unwrap();
+            assert_eq!(
+                reply.as_ref().map(SecretString::expose),
+                expected,
+                "{input}"
+            );
+            assert_eq!(output, b"INQUIRE QUALITY pass%25word\n");
+        }
+    }
+
+    #[test]
+    fn write_response() {
+        let test_cases = vec![
+            Response::ok(),
+            Response::SecretD(SecretString::from("1234")),
+            Response::SecretD(SecretString::from("%\n".repeat(1000))),
+            Response::D("é".repeat(1000)),
+        ];
+
+        for resp in test_cases {
+            let mut buffer = Zeroizing::new(vec![]);
+            super::write_response(&mut buffer, &resp).unwrap();
+            assert_eq!(*buffer, format!("{resp}\n").into_bytes());
+            // The buffer grew once before the line was written, rather than while writing it
+            assert_eq!(buffer.capacity(), buffer.len(), "{resp:?}");
+        }
+    }
+
+    #[test]
+    fn write_inquire() {
+        let test_cases = vec![("GENPIN", ""), ("QUALITY", "pass word%")];
+
+        for (keyword, args) in test_cases {
+            let mut buffer = Zeroizing::new(b"OK\n".to_vec());
+            super::write_inquire(&mut buffer, keyword, args).unwrap();
+            assert_eq!(buffer.capacity(), buffer.len(), "{keyword}");
+        }
+    }
+
+    #[test]
+    fn inquire_without_args() {
+        let mut output = vec![];
+        let mut connection = Connection::new("END\n".as_bytes(), &mut output);
+        connection.inquire("GENPIN", "").unwrap();
+        assert_eq!(output, b"INQUIRE GENPIN\n");
+    }
+
+    #[test]
+    fn inquire_errors() {
+        for input in ["", "D 42\n", "BYE\n"] {
+            let mut output = vec![];
+            let mut connection = Connection::new(input.as_bytes(), &mut output);
+            assert!(connection.inquire("QUALITY", "x").is_err(), "{input}");
+        }
+    }
+
+    #[test]
+    fn read_line() {
+        let mut output = vec![];
+        let mut connection = Connection::new(
+            indoc! {"
+                GETPIN
+                BYE\r
+                END"}
+            .as_bytes(),
+            &mut output,
+        );
+
+        assert_eq!(connection.read_line().unwrap().as_deref(), Some("GETPIN"));
+        assert_eq!(connection.read_line().unwrap().as_deref(), Some("BYE"));
+        assert_eq!(connection.read_line().unwrap().as_deref(), Some("END"));
+        assert_eq!(connection.read_line().unwrap(), None);
+    }
+
+    #[test]
+    fn read_line_into() {
+        let mut connection = Connection::new("GETPIN\nNOP\n".as_bytes(), vec![]);
+        let mut line = String::with_capacity(64);
+        let allocation = line.as_ptr();
+
+        for expected in ["GETPIN", "NOP"] {
+            assert!(connection.read_line_into(&mut line).unwrap());
+            assert_eq!(line, expected);
+            assert_eq!(line.as_ptr(), allocation);
+        }
+        assert!(!connection.read_line_into(&mut line).unwrap());
+    }
+
+    #[test]
+    fn read_line_too_long() {
+        use super::LineTooLong;
+        use std::io::BufReader;
+
+        let test_cases = vec![
+            ("1234\n", vec![Some("1234")]),
+            ("1234\r\n", vec![Some("1234")]),
+            ("12345\nNOP\n", vec![None, Some("NOP")]),
+            ("1234567890\nNOP", vec![None, Some("NOP")]),
+            ("12345", vec![None]),
+        ];
+
+        for (input, expected) in test_cases {
+            // A small buffer, so that lines are read in several chunks
+            let reader = BufReader::with_capacity(3, input.as_bytes());
+            let mut connection = Connection::new(reader, vec![]).max_line_length(4);
+            for line in expected {
+                match connection.read_line() {
+                    Ok(read) => assert_eq!(read.as_deref(), line, "{input:?}"),
+                    Err(e) => assert!(line.is_none() && LineTooLong::is(&e), "{input:?}"),
+                }
+            }
+            assert_eq!(connection.read_line().unwrap(), None, "{input:?}");
+        }
+    }
+
+    #[test]
+    fn write_is_flushed_before_reading() {
+        use crate::response::Response;
+
+        let mut output = vec![];
+        let mut connection = Connection::new("NOP\n".as_bytes(), &mut output);
+        connection.write(&Response::Ok(None)).unwrap();
+        connection.write(&Response::Ok(None)).unwrap();
+        assert!(connection.output.is_empty());
+
+        connection.read_line().unwrap();
+        assert_eq!(connection.output.as_slice(), b"OK\nOK\n");
+        assert!(connection.buffer.is_empty());
+
+        connection.write(&Response::Ok(None)).unwrap();
+        connection.flush().unwrap();
+        assert_eq!(output, b"OK\nOK\nOK\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn bind() {
+        use std::os::unix::{fs::PermissionsExt, net::UnixStream};
+
+        let path = std::env::temp_dir().join(format!("elephantine-socket-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = super::bind(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let _client = UnixStream::connect(&path).unwrap();
+        let (stream, _) = socket.accept().unwrap();
+        assert!(super::same_user(&stream).unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,116 @@
+//! Asking for PINs over D-Bus, so that applications other than gpg-agent can use the configured
+//! backend too.
+
+use crate::{
+    config::Config,
+    connection::NoInquire,
+    request::{Request, Set},
+    GetPinError, Listener,
+};
+
+/// The well-known name the service is registered under on the session bus.
+pub const SERVICE_NAME: &str = "io.github.triarius.Elephantine";
+
+/// The path of the object implementing the interface.
+pub const OBJECT_PATH: &str = "/io/github/triarius/Elephantine";
+
+/// The errors a method call can fail with, named after [`SERVICE_NAME`] so that clients can tell
+/// a cancelled dialog from a failure.
+#[derive(Debug, zbus::DBusError)]
+#[zbus(prefix = "io.github.triarius.Elephantine.Error")]
+pub enum Error {
+    #[zbus(error)]
+    ZBus(zbus::Error),
+    /// The user cancelled the dialog.
+    Canceled(String),
+    /// The dialog could not be shown, or the PIN was rejected.
+    Failed(String),
+}
+
+/// The `io.github.triarius.Elephantine` interface. Each call gets a listener of its own, so calls
+/// don't share any state, and is answered before the next one is handled.
+#[derive(Debug)]
+pub struct Service {
+    config: Config,
+}
+
+impl Service {
+    #[must_use]
+    pub fn new(config: Config) -> Self {
+        Self { config }
+    }
+}
+
+#[zbus::interface(name = "io.github.triarius.Elephantine")]
+impl Service {
+    /// Ask for a PIN with the configured backend, like `GETPIN` after `SETPROMPT` and `SETDESC`.
+    /// An empty `prompt` or `desc` leaves the default.
+    fn get_pin(&self, prompt: &str, desc: &str) -> Result<String, Error> {
+        let mut listener = Listener::from(self.config.clone());
+        let settings = [
+            (!prompt.is_empty()).then(|| Set::Prompt(prompt.into())),
+            (!desc.is_empty()).then(|| Set::Desc(desc.into())),
+        ];
+        for set in settings.into_iter().flatten() {
+            listener.handle_req(Request::Set(set), &mut NoInquire);
+        }
+
+        match listener.get_pin_once() {
+            Ok(pin) => Ok(pin.expose().to_string()),
+            Err(e @ GetPinError::Canceled) => Err(Error::Canceled(e.to_string())),
+            Err(e) => {
+                log::warn!("{e}");
+                Err(Error::Failed(e.to_string()))
+            }
+        }
+    }
+}
+
+/// Register [`SERVICE_NAME`] on the session bus and answer calls until the process is killed.
+///
+/// # Errors
+/// If the session bus can't be reached or the name is already taken.
+pub fn serve(config: Config) -> zbus::Result<()> {
+    let _connection = zbus::blocking::connection::Builder::session()?
+        .name(SERVICE_NAME)?
+        .serve_at(OBJECT_PATH, Service::new(config))?
+        .build()?;
+    log::info!("Serving {SERVICE_NAME} on the session bus");
+    loop {
+        std::thread::park();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Error, Service};
+    use crate::config::Config;
+
+    #[test]
+    fn get_pin() {
+        let test_cases = vec![
+            ("echo 1234", "", "", Ok("1234")),
+            (
+                r#"echo "$PINENTRY_PROMPT $PINENTRY_DESC""#,
+                "PIN:",
+                "Unlock the key",
+                Ok("PIN: Unlock the key"),
+            ),
+            ("exit 1", "", "", Err("canceled")),
+            ("echo oops >&2; exit 2", "", "", Err("failed")),
+        ];
+
+        for (script, prompt, desc, expected) in test_cases {
+            let service = Service::new(Config {
+                command: vec!["sh".to_string(), "-c".to_string(), script.to_string()].into(),
+                ..Config::default()
+            });
+            let pin = service.get_pin(prompt, desc);
+            match expected {
+                Ok(expected) => assert_eq!(pin.unwrap(), expected, "{script}"),
+                Err("canceled") => assert!(matches!(pin, Err(Error::Canceled(_))), "{script}"),
+                Err(_) => assert!(matches!(pin, Err(Error::Failed(_))), "{script}"),
+            }
+        }
+    }
+}
@@ -0,0 +1,470 @@
+//! Falling back to another pinentry by speaking Assuan to it.
+
+use crate::{
+    assuan,
+    connection::{Inquire, NoInquire},
+    provider::PinProvider,
+    request::{Keyinfo, OptionReq, Request, Set},
+    response::{escape, Response},
+    secret::SecretString,
+    signal::{self, TrackedChild},
+    CommandError, GetPinError, OptionValue, State,
+};
+use std::{
+    borrow::Cow,
+    io::{self, BufRead, BufReader, Write},
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::Duration,
+};
+use zeroize::{Zeroize, Zeroizing};
+
+/// Gets PINs from another pinentry, e.g. `pinentry-curses`, by forwarding the dialog settings to
+/// it and relaying its responses.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Pinentry {
+    command: Vec<String>,
+    timeout: Option<Duration>,
+}
+
+impl Pinentry {
+    #[must_use]
+    pub fn new(command: Vec<String>) -> Self {
+        Self {
+            command,
+            timeout: None,
+        }
+    }
+
+    /// Kill the pinentry if the dialog is not over after `timeout`, unless the client set a
+    /// timeout with `SETTIMEOUT`.
+    #[must_use]
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Get a PIN from the pinentry, relaying any `INQUIRE` it sends to `client`.
+    ///
+    /// The pinentry asks for a repeated PIN itself if `SETREPEAT` was set.
+    ///
+    /// # Errors
+    /// `GetPinError::Setup` if the pinentry could not be run or talked to
+    /// `GetPinError::Canceled` if the user cancelled the dialog
+    /// `GetPinError::Timeout` if the pinentry timed out, or was killed as it didn't answer in time
+    /// `GetPinError::Command` if the pinentry responded with any other error
+    /// `GetPinError::Inquire` if relaying an `INQUIRE` to the client failed, in which case the
+    /// pinentry is killed
+    pub fn get_pin(
+        &self,
+        state: &State,
+        client: &mut dyn Inquire,
+    ) -> Result<SecretString, GetPinError> {
+        let setup_err = |e| GetPinError::Setup(e, self.command.clone());
+        let mut session = Session::spawn(&self.command).map_err(setup_err)?;
+
+        let timeout = state.timeout(self.timeout);
+        let pid = i32::try_from(session.child.id()).unwrap_or_default();
+        let (result, timed_out) = thread::scope(|scope| {
+            let (done, waiting) = mpsc::channel::<()>();
+            let watchdog = timeout.map(|timeout| {
+                scope.spawn(move || {
+                    // Nothing is sent, so this returns early only once the dialog is over
+                    let expired = waiting.recv_timeout(timeout) == Err(RecvTimeoutError::Timeout);
+                    if expired {
+                        signal::kill(pid);
+                    }
+                    expired
+                })
+            });
+            let result = session
+                .expect_ok()
+                .map_err(SessionError::from)
+                .and_then(|()| session.get_pin(state, client));
+            drop(done);
+            (
+                result,
+                watchdog.is_some_and(|w| w.join().unwrap_or_default()),
+            )
+        });
+        let result = match result {
+            Err(_) if timed_out => Err(SessionError::GetPin(GetPinError::Timeout(
+                timeout.unwrap_or_default(),
+            ))),
+            result => result,
+        };
+
+        if let Err(SessionError::Client(e)) = &result {
+            // Nobody is waiting for the PIN, so don't wait for the user to enter it
+            log::debug!("Stopping the fallback pinentry, as the client failed: {e}");
+            session.kill();
+        } else {
+            // The pinentry exits when its input is closed, so a failure to say goodbye is harmless
+            let _ = session.finish();
+        }
+        result.map_err(|e| match e {
+            SessionError::Io(e) => setup_err(e),
+            SessionError::Client(e) => GetPinError::Inquire(e),
+            SessionError::GetPin(e) => e,
+        })
+    }
+}
+
+impl PinProvider for Pinentry {
+    /// Get a PIN from the pinentry, cancelling any `INQUIRE` it sends.
+    fn get_pin(&self, state: &State) -> Result<SecretString, GetPinError> {
+        Pinentry::get_pin(self, state, &mut NoInquire)
+    }
+}
+
+enum SessionError {
+    /// Talking to the pinentry failed.
+    Io(io::Error),
+    /// Talking to the client failed while relaying an `INQUIRE`.
+    Client(io::Error),
+    GetPin(GetPinError),
+}
+
+impl From<io::Error> for SessionError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// A running pinentry.
+struct Session {
+    child: Child,
+    input: ChildStdin,
+    output: BufReader<ChildStdout>,
+    _tracked: TrackedChild,
+}
+
+impl Session {
+    /// Run the pinentry, which greets us with an `OK` once it is ready.
+    fn spawn(command: &[String]) -> io::Result<Self> {
+        let (program, args) = command
+            .split_first()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Empty command"))?;
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let (Some(input), Some(output)) = (child.stdin.take(), child.stdout.take()) else {
+            return Err(io::Error::other("Failed to open pipes to the pinentry"));
+        };
+
+        Ok(Self {
+            _tracked: TrackedChild::new(&child),
+            child,
+            input,
+            output: BufReader::new(output),
+        })
+    }
+
+    fn get_pin(
+        &mut self,
+        state: &State,
+        client: &mut dyn Inquire,
+    ) -> Result<SecretString, SessionError> {
+        for req in requests(state) {
+            self.send(&req)?;
+            if let Err(e) = self.expect_ok() {
+                // An older pinentry may not support every setting, which is not fatal
+                log::debug!("Fallback pinentry rejected {req}: {e}");
+            }
+        }
+
+        self.send(&Request::GetPin)?;
+        let mut pin = Zeroizing::new(String::new());
+        loop {
+            match self.read_response()? {
+                Response::Ok(_) => return Ok(SecretString::from(pin.as_str())),
+                Response::D(mut data) => {
+                    pin.push_str(&data);
+                    data.zeroize();
+                }
+                Response::Inquire(keyword, mut args) => {
+                    let relayed = self.relay_inquire(&keyword, &args, client);
+                    args.zeroize();
+                    relayed?;
+                }
+                Response::Err(code, description) => {
+                    return Err(SessionError::GetPin(error(code, description, state)));
+                }
+                Response::S(..) | Response::Comment(_) | Response::SecretD(_) => {}
+            }
+        }
+    }
+
+    /// Ask the client to answer an `INQUIRE` from the pinentry and send its reply back.
+    fn relay_inquire(
+        &mut self,
+        keyword: &str,
+        args: &str,
+        client: &mut dyn Inquire,
+    ) -> Result<(), SessionError> {
+        match client
+            .inquire(keyword, args)
+            .map_err(SessionError::Client)?
+        {
+            Some(data) => {
+                let mut escaped = escape(data.expose());
+                let written = writeln!(self.input, "D {escaped}\nEND");
+                if let Cow::Owned(escaped) = &mut escaped {
+                    escaped.zeroize();
+                }
+                written?;
+            }
+            None => writeln!(self.input, "CAN")?,
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, req: &Request) -> io::Result<()> {
+        writeln!(self.input, "{req}")
+    }
+
+    fn read_response(&mut self) -> io::Result<Response> {
+        // The line may hold a secret
+        let mut line = Zeroizing::new(String::new());
+        if self.output.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if line.ends_with('\n') {
+            line.pop();
+        }
+        line.parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Wait for the `OK` ending the response to a request.
+    fn expect_ok(&mut self) -> io::Result<()> {
+        loop {
+            match self.read_response()? {
+                Response::Ok(_) => return Ok(()),
+                Response::Err(code, description) => {
+                    return Err(io::Error::other(format!("ERR {code} {description}")));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Stop the pinentry without waiting for the user to finish the dialog.
+    fn kill(mut self) {
+        if let Err(e) = self.child.kill() {
+            log::warn!("Failed to kill the fallback pinentry: {e}");
+        }
+        let _ = self.child.wait();
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.send(&Request::Bye)?;
+        drop(self.input);
+        self.child.wait().map(|_| ())
+    }
+}
+
+/// The requests that recreate `state` in another pinentry.
+fn requests(state: &State) -> Vec<Request<'_>> {
+    fn text(value: Option<&str>) -> Option<Cow<'_, str>> {
+        value.map(Cow::from)
+    }
+
+    let settings = [
+        text(state.desc.as_deref()).map(Set::Desc),
+        text(state.prompt.as_deref()).map(Set::Prompt),
+        text(state.title.as_deref()).map(Set::Title),
+        text(state.ok.as_deref()).map(Set::Ok),
+        text(state.cancel.as_deref()).map(Set::Cancel),
+        text(state.notok.as_deref()).map(Set::Notok),
+        text(state.error.as_deref()).map(Set::Error),
+        state
+            .keyinfo
+            .as_ref()
+            .map(|k| Set::Keyinfo(Keyinfo::from(k.raw()))),
+        state
+            .repeat
+            .as_deref()
+            .map(|label| Set::Repeat((!label.is_empty()).then(|| Cow::from(label)))),
+        text(state.repeaterror.as_deref()).map(Set::Repeaterror),
+        text(state.repeatok.as_deref()).map(Set::Repeatok),
+        state
+            .qualitybar
+            .as_deref()
+            .map(|label| Set::Qualitybar((!label.is_empty()).then(|| Cow::from(label)))),
+        text(state.qualitybar_tt.as_deref()).map(Set::QualitybarTt),
+        text(state.genpin.as_deref()).map(Set::Genpin),
+        text(state.genpin_tt.as_deref()).map(Set::GenpinTt),
+        state.timeout.map(Set::Timeout),
+    ];
+
+    let options = state.options.iter().map(|(k, v)| {
+        Request::Option(match v {
+            OptionValue::Enabled => OptionReq::Bool(Cow::from(k.as_str())),
+            OptionValue::Disabled => OptionReq::Bool(Cow::from(format!("no-{k}"))),
+            OptionValue::Value(v) => OptionReq::KV(Cow::from(k.as_str()), Cow::from(v.as_str())),
+        })
+    });
+    options
+        .chain(settings.into_iter().flatten().map(Request::Set))
+        .collect()
+}
+
+/// The error for an `ERR <code> <description>` response.
+fn error(code: i32, description: String, state: &State) -> GetPinError {
+    if code == assuan::pinentry_error(assuan::CANCELED) {
+        GetPinError::Canceled
+    } else if code == assuan::pinentry_error(assuan::TIMEOUT) {
+        GetPinError::Timeout(Duration::from_secs(state.timeout.unwrap_or_default()))
+    } else {
+        GetPinError::Command(CommandError {
+            code,
+            stderr: description,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Pinentry;
+    use crate::{connection::Connection, test_support::state, GetPinError};
+
+    /// A fake pinentry that accepts every request and answers `GETPIN` by running `script`.
+    fn pinentry(script: &str) -> Pinentry {
+        Pinentry::new(vec![
+            "sh".to_string(),
+            "-c".to_string(),
+            format!(
+                r#"echo "OK ready"
+                while read -r line; do
+                    case "$line" in
+                        GETPIN) {script} ;;
+                        BYE) echo OK; exit ;;
+                        *) echo OK ;;
+                    esac
+                done"#
+            ),
+        ])
+    }
+
+    #[test]
+    fn get_pin() {
+        let test_cases = vec![
+            (r"printf 'D 12%%2534\nOK\n'", Some("12%34")),
+            (r"printf 'S PIN_REPEATED\nD 12\nD 34\nOK\n'", Some("1234")),
+            (r"printf '# comment\nOK\n'", Some("")),
+            ("echo ERR 83886179 canceled", None),
+        ];
+
+        for (script, expected) in test_cases {
+            let pin = super::PinProvider::get_pin(&pinentry(script), &state(&[]));
+            match expected {
+                Some(expected) => assert_eq!(pin.unwrap().expose(), expected, "{script}"),
+                None => assert!(matches!(pin, Err(GetPinError::Canceled)), "{script}"),
+            }
+        }
+    }
+
+    #[test]
+    fn get_pin_errors() {
+        let pin = super::PinProvider::get_pin(&pinentry("echo ERR 42 oops"), &state(&[]));
+        assert!(
+            matches!(pin, Err(GetPinError::Command(ref e)) if e.code == 42 && e.stderr == "oops")
+        );
+
+        let pin = super::PinProvider::get_pin(&pinentry("exit"), &state(&[]));
+        assert!(matches!(pin, Err(GetPinError::Setup(..))));
+
+        let pin = super::PinProvider::get_pin(
+            &Pinentry::new(vec!["/nonexistent/pinentry".to_string()]),
+            &state(&[]),
+        );
+        assert!(matches!(pin, Err(GetPinError::Setup(..))));
+    }
+
+    #[test]
+    fn get_pin_forwards_state() {
+        // Answer GETPIN with the requests received so far
+        let pinentry = Pinentry::new(vec![
+            "sh".to_string(),
+            "-c".to_string(),
+            r#"echo OK; received=""
+            while read -r line; do
+                case "$line" in
+                    GETPIN) echo "D $received"; echo OK ;;
+                    BYE) echo OK; exit ;;
+                    *) received="$received$line;"; echo OK ;;
+                esac
+            done"#
+                .to_string(),
+        ]);
+        let state = state(&[
+            "OPTION ttyname=/dev/pts/1",
+            "OPTION no-grab",
+            "SETDESC a%0Ab",
+            "SETQUALITYBAR",
+        ]);
+        assert_eq!(
+            super::PinProvider::get_pin(&pinentry, &state)
+                .unwrap()
+                .expose(),
+            // The received requests are decoded as the data of the PIN
+            "OPTION ttyname=/dev/pts/1;OPTION no-grab;SETDESC a\nb;SETQUALITYBAR;"
+        );
+    }
+
+    #[test]
+    fn get_pin_relays_inquire() {
+        let pinentry = pinentry(
+            r#"echo "INQUIRE QUALITY 12%2534"; read -r d; read -r end; echo "$d"; echo OK"#,
+        );
+        let mut output = vec![];
+        let mut client = Connection::new("D 42\nEND\n".as_bytes(), &mut output);
+        let pin = pinentry.get_pin(&state(&[]), &mut client).unwrap();
+        assert_eq!(pin.expose(), "42");
+        assert_eq!(output, b"INQUIRE QUALITY 12%2534\n");
+    }
+
+    #[test]
+    fn get_pin_kills_pinentry_when_client_fails() {
+        use std::time::{Duration, Instant};
+
+        // The dialog would stay open until the pinentry is killed
+        let pinentry = pinentry(r#"echo "INQUIRE QUALITY 1234"; sleep 10; echo OK"#);
+        let mut output = vec![];
+        // The client hangs up instead of answering the INQUIRE
+        let mut client = Connection::new("".as_bytes(), &mut output);
+        let start = Instant::now();
+        let pin = pinentry.get_pin(&state(&[]), &mut client);
+        assert!(matches!(pin, Err(GetPinError::Inquire(_))));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn get_pin_kills_pinentry_after_timeout() {
+        use std::time::{Duration, Instant};
+
+        let test_cases = vec![
+            // The pinentry never answers GETPIN
+            (pinentry("exec sleep 10"), &[][..]),
+            // The pinentry never greets us
+            (
+                Pinentry::new(vec!["sleep".to_string(), "10".to_string()]),
+                &[][..],
+            ),
+            // SETTIMEOUT overrides the configured timeout
+            (pinentry("exec sleep 10"), &["SETTIMEOUT 1"][..]),
+        ];
+
+        for (pinentry, requests) in test_cases {
+            let pinentry = pinentry.timeout(Some(Duration::from_millis(100)));
+            let start = Instant::now();
+            let pin = super::PinProvider::get_pin(&pinentry, &state(requests));
+            assert!(matches!(pin, Err(GetPinError::Timeout(_))), "{requests:?}");
+            assert!(start.elapsed() < Duration::from_secs(5), "{requests:?}");
+        }
+    }
+}
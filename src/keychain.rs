@@ -0,0 +1,106 @@
+//! Getting PINs from the macOS login keychain, like the "Save in Keychain" checkbox of
+//! `pinentry-mac`.
+
+use crate::{
+    config::Config,
+    keyring::{self, Keyring},
+    provider::{self, ConfiguredProvider, Origin, PinProvider, Rate},
+    secret::SecretString,
+    GetPinError, State,
+};
+use security_framework::{base::Error, passwords};
+
+/// The service of the keychain items, the same as `pinentry-mac`'s, which stores them with the
+/// key as the account.
+const SERVICE: &str = "GnuPG";
+
+/// `errSecItemNotFound`
+const ITEM_NOT_FOUND: i32 = -25300;
+
+/// A provider that looks the PIN up in the login keychain by the key it unlocks, and asks
+/// `prompt` for it if it isn't stored there.
+///
+/// The keychain is used like every keyring, as `keyring::get_pin` describes.
+#[derive(Debug, PartialEq, Eq)]
+pub struct KeychainProvider<P> {
+    prompt: P,
+    store: bool,
+}
+
+impl<P: PinProvider> KeychainProvider<P> {
+    #[must_use]
+    pub fn new(prompt: P) -> Self {
+        Self {
+            prompt,
+            store: false,
+        }
+    }
+
+    /// Store the PIN the user entered if it wasn't found.
+    #[must_use]
+    pub fn store(mut self, store: bool) -> Self {
+        self.store = store;
+        self
+    }
+}
+
+impl From<&Config> for KeychainProvider<Box<ConfiguredProvider>> {
+    fn from(config: &Config) -> Self {
+        Self::new(Box::new(keyring::prompt(config))).store(config.store_in_keyring)
+    }
+}
+
+impl<P: PinProvider> PinProvider for KeychainProvider<P> {
+    /// Get the PIN from the keychain, or else from `prompt`
+    ///
+    /// # Errors
+    /// Any error of `prompt`
+    fn get_pin(&self, state: &State) -> Result<SecretString, GetPinError> {
+        self.get_pin_with_origin(state, None).map(|(pin, _)| pin)
+    }
+
+    fn get_pin_with_quality(
+        &self,
+        state: &State,
+        quality: &mut dyn FnMut(&SecretString) -> Result<Option<i32>, GetPinError>,
+    ) -> Result<SecretString, GetPinError> {
+        self.get_pin_with_origin(state, Some(quality))
+            .map(|(pin, _)| pin)
+    }
+
+    fn get_pin_with_origin(
+        &self,
+        state: &State,
+        quality: Option<&mut Rate<'_>>,
+    ) -> Result<(SecretString, Origin), GetPinError> {
+        keyring::get_pin(&Keychain, state, self.store, || {
+            provider::ask(&self.prompt, state, quality)
+        })
+    }
+}
+
+struct Keychain;
+
+impl Keyring for Keychain {
+    const NAME: &'static str = "keychain";
+
+    type Error = Error;
+
+    fn lookup(&self, key: &str) -> Result<Option<SecretString>, Self::Error> {
+        match passwords::get_generic_password(SERVICE, key) {
+            Ok(secret) => match SecretString::try_from(secret) {
+                Ok(pin) => Ok(Some(pin)),
+                Err(e) => {
+                    log::warn!("Ignoring the PIN in the keychain, as it is not valid UTF-8: {e}");
+                    Ok(None)
+                }
+            },
+            Err(e) if e.code() == ITEM_NOT_FOUND => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn store(&self, key: &str, pin: &SecretString) -> Result<(), Self::Error> {
+        passwords::set_generic_password(SERVICE, key, pin.expose().as_bytes())
+    }
+}
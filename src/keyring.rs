@@ -0,0 +1,146 @@
+//! Looking PINs up in a keyring outside of gpg-agent before asking the user for them.
+
+use crate::{
+    config::{Backend, Config},
+    provider::{ConfiguredProvider, Origin},
+    secret::SecretString,
+    GetPinError, State,
+};
+use std::fmt::Display;
+
+/// A keyring that stores PINs by the key they unlock.
+pub(crate) trait Keyring {
+    /// The name of the keyring in log messages.
+    const NAME: &'static str;
+
+    type Error: Display;
+
+    fn lookup(&self, key: &str) -> Result<Option<SecretString>, Self::Error>;
+
+    /// Keyrings that PINs aren't stored in don't need to implement this.
+    fn store(&self, key: &str, pin: &SecretString) -> Result<(), Self::Error> {
+        let _ = (key, pin);
+        Ok(())
+    }
+}
+
+/// The provider that asks the user for a PIN the keyring doesn't have: the one `command` selects.
+pub(crate) fn prompt(config: &Config) -> ConfiguredProvider {
+    ConfiguredProvider::from(&Config {
+        backend: Backend::Command,
+        ..config.clone()
+    })
+}
+
+/// Get the PIN from `keyring`, or else from `prompt`, storing what `prompt` returns if `store`,
+/// and whether it was found in the keyring.
+///
+/// The keyring is only used if the client set a cacheable key with `SETKEYINFO` and allowed it
+/// with `OPTION allow-external-password-cache`. It isn't looked in after a failed attempt, as the
+/// stored PIN may be the one that was rejected. Failures of the keyring are only logged.
+pub(crate) fn get_pin<K: Keyring>(
+    keyring: &K,
+    state: &State,
+    store: bool,
+    prompt: impl FnOnce() -> Result<SecretString, GetPinError>,
+) -> Result<(SecretString, Origin), GetPinError> {
+    let Some(key) = state.external_cache_key() else {
+        return prompt().map(|pin| (pin, Origin::User));
+    };
+
+    if state.error.is_none() {
+        match keyring.lookup(key) {
+            Ok(Some(pin)) => return Ok((pin, Origin::Stored)),
+            Ok(None) => {}
+            Err(e) => log::warn!("Failed to look the PIN up in the {}: {e}", K::NAME),
+        }
+    }
+
+    let pin = prompt()?;
+    if store {
+        if let Err(e) = keyring.store(key, &pin) {
+            log::warn!("Failed to store the PIN in the {}: {e}", K::NAME);
+        }
+    }
+    Ok((pin, Origin::User))
+}
+
+#[cfg(test)]
+mod test {
+    use super::Keyring;
+    use crate::{provider::Origin, secret::SecretString, test_support::state, GetPinError};
+    use std::{cell::RefCell, collections::HashMap};
+
+    thread_local! {
+        static STORED: RefCell<HashMap<String, String>> = RefCell::default();
+    }
+
+    /// A keyring that fails for the key `n/BROKEN`.
+    struct Fake;
+
+    impl Keyring for Fake {
+        const NAME: &'static str = "fake keyring";
+
+        type Error = &'static str;
+
+        fn lookup(&self, key: &str) -> Result<Option<SecretString>, Self::Error> {
+            if key == "n/BROKEN" {
+                return Err("broken");
+            }
+            Ok(STORED.with_borrow(|s| s.get(key).map(|pin| SecretString::from(pin.as_str()))))
+        }
+
+        fn store(&self, key: &str, pin: &SecretString) -> Result<(), Self::Error> {
+            STORED.with_borrow_mut(|s| s.insert(key.to_string(), pin.expose().to_string()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn get_pin() {
+        const ALLOW: &str = "OPTION allow-external-password-cache";
+
+        let test_cases = vec![
+            // Not allowed, or no key to store it by
+            (vec!["SETKEYINFO n/STORED"], "entered", false),
+            (vec![ALLOW], "entered", false),
+            (vec![ALLOW, "SETKEYINFO --clear"], "entered", false),
+            (vec![ALLOW, "SETKEYINFO n/STORED"], "stored", false),
+            (vec![ALLOW, "SETKEYINFO n/MISSING"], "entered", true),
+            // The stored PIN was wrong
+            (
+                vec![ALLOW, "SETKEYINFO n/STORED", "SETERROR Bad"],
+                "entered",
+                true,
+            ),
+            (vec![ALLOW, "SETKEYINFO n/BROKEN"], "entered", true),
+        ];
+
+        for (requests, expected, stored) in test_cases {
+            for store in [false, true] {
+                STORED.with_borrow_mut(|s| {
+                    *s = HashMap::from([("n/STORED".to_string(), "stored".to_string())]);
+                });
+                let state = state(&requests);
+                let (pin, origin) =
+                    super::get_pin(&Fake, &state, store, || Ok(SecretString::from("entered")))
+                        .unwrap();
+                assert_eq!(pin.expose(), expected, "{requests:?}");
+                let from_keyring = expected == "stored";
+                assert_eq!(origin == Origin::Stored, from_keyring, "{requests:?}");
+
+                let after = state
+                    .external_cache_key()
+                    .and_then(|key| STORED.with_borrow(|s| s.get(key).cloned()));
+                assert_eq!(
+                    after.as_deref() == Some("entered"),
+                    store && stored,
+                    "{requests:?} {store}"
+                );
+            }
+        }
+
+        let pin = super::get_pin(&Fake, &state(&[]), true, || Err(GetPinError::Canceled));
+        assert!(matches!(pin, Err(GetPinError::Canceled)));
+    }
+}
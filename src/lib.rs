@@ -1,29 +1,114 @@
+#[cfg(feature = "systemd")]
+pub mod ask_password;
+pub mod assuan;
+#[cfg(feature = "async")]
+pub mod asynchronous;
+pub mod build;
+pub mod cache;
 pub mod config;
+pub mod connection;
+#[cfg(feature = "dbus")]
+pub mod dbus;
+pub mod fallback;
+#[cfg(all(feature = "keychain", target_os = "macos"))]
+pub mod keychain;
+mod keyring;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod observer;
+pub mod pass;
+pub mod provider;
 pub mod request;
 pub mod response;
+pub mod secret;
+#[cfg(feature = "secret-service")]
+pub mod secret_service;
+pub mod signal;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+#[cfg(unix)]
+pub mod tty;
 
+#[allow(clippy::pedantic)]
 pub(crate) mod build_info {
     include!(concat!(env!("OUT_DIR"), "/built.rs"));
 }
 
 use crate::{
+    cache::{Cache, SharedCache},
     config::Config,
-    request::{parse, OptionReq, Request, Set},
+    connection::{Connection, Inquire, LineTooLong, NotUtf8},
+    fallback::Pinentry,
+    observer::{NoObserver, SessionObserver},
+    provider::{ConfiguredProvider, Origin, PinProvider, Rate},
+    request::{parse, Keyinfo, OptionReq, Request, Set},
     response::Response,
+    secret::SecretString,
 };
-use color_eyre::Result;
+use indexmap::IndexMap;
 use std::{
-    collections::HashMap,
+    borrow::Cow,
+    cell::Cell,
     fmt::{self, Display, Formatter},
     io::{BufRead, Write},
+    ops::ControlFlow,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
 };
 use thiserror::Error;
+use zeroize::{Zeroize, Zeroizing};
+
+/// An error that ends an Assuan session.
+#[derive(Debug, Error)]
+pub enum Error {
+    /// Reading from or writing to the client failed.
+    Io(#[from] std::io::Error),
+    /// A request could not be parsed.
+    Parse(#[from] request::Error),
+    /// The PIN could not be got from the backend.
+    Backend(#[from] GetPinError),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {e}"),
+            Error::Parse(e) => write!(f, "{e}"),
+            Error::Backend(e) => write!(f, "Backend error: {e}"),
+        }
+    }
+}
+
+/// The error shown when the repeated PIN doesn't match, if the client didn't set one with
+/// `SETREPEATERROR`.
+const REPEAT_MISMATCH: &str = "Repeated PIN does not match";
+
+/// The label of the repeat field if the client sent `SETREPEAT` without one.
+const DEFAULT_REPEAT_LABEL: &str = "Repeat:";
+
+/// The character masking the PIN as it is typed, if the client didn't set one with
+/// `OPTION invisible-char`.
+const DEFAULT_INVISIBLE_CHAR: &str = "•";
 
 #[derive(Debug, Error)]
 pub enum GetPinError {
-    Command(CommandError),
-    Setup(std::io::Error, Vec<String>),
-    Output(std::string::FromUtf8Error),
+    Command(#[source] CommandError),
+    Setup(#[source] std::io::Error, Vec<String>),
+    Output(#[source] std::str::Utf8Error),
+    Timeout(Duration),
+    Canceled,
+    RepeatMismatch,
+    Inquire(#[source] std::io::Error),
+    /// The user asked for the client to generate a PIN.
+    Generate,
+    /// The environment variable holding the PIN is not set.
+    MissingEnv(String),
+    /// The PIN has fewer characters than the minimum length.
+    TooShort(usize),
+    /// The output of the command is not in the configured format.
+    Malformed(String),
+    /// The dialog wasn't shown, as this many attempts in a row already failed.
+    TooManyAttempts(u32),
 }
 
 impl Display for GetPinError {
@@ -33,6 +118,15 @@ impl Display for GetPinError {
             Command(e) => write!(f, "{e}"),
             Setup(e, cmd) => write!(f, "Setup error: {e}, cmd = {cmd:?}"),
             Output(e) => write!(f, "Output error: {e}"),
+            Timeout(t) => write!(f, "Timed out after {}", humantime::format_duration(*t)),
+            Canceled => write!(f, "Operation cancelled"),
+            RepeatMismatch => write!(f, "{REPEAT_MISMATCH}"),
+            Inquire(e) => write!(f, "Inquire error: {e}"),
+            Generate => write!(f, "Asked to generate a PIN"),
+            MissingEnv(var) => write!(f, "The environment variable {var} is not set"),
+            TooShort(min) => write!(f, "PIN must have at least {min} characters"),
+            Malformed(e) => write!(f, "Malformed output: {e}"),
+            TooManyAttempts(n) => write!(f, "Too many failed attempts ({n})"),
         }
     }
 }
@@ -43,6 +137,17 @@ pub struct CommandError {
     stderr: String,
 }
 
+impl CommandError {
+    /// The first line of the command's stderr, or its exit code if it printed nothing, to tell
+    /// the client on a single line.
+    fn summary(&self) -> String {
+        match self.stderr.trim().lines().next() {
+            Some(line) => line.to_string(),
+            None => format!("Command failed with code {}", self.code),
+        }
+    }
+}
+
 impl Display for CommandError {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         write!(
@@ -53,110 +158,691 @@ impl Display for CommandError {
     }
 }
 
+/// What to do after handling a request.
 #[derive(Debug, PartialEq, Eq)]
-enum Action<T> {
+pub enum Action<T> {
+    /// Send the responses and wait for the next request.
     Next(T),
+    /// Send the responses and close the connection.
     Stop(T),
 }
 
-#[derive(Debug, Default, PartialEq, Eq)]
-struct State {
-    timeout: u64,
+impl<T> Action<T> {
+    /// The responses, whether or not the connection should be closed.
+    pub fn into_inner(self) -> T {
+        match self {
+            Action::Next(t) | Action::Stop(t) => t,
+        }
+    }
+}
+
+/// The reply to a request as the listener sends it. A PIN isn't put in the list of responses, but
+/// kept in its own [`SecretString`] until it is written straight to the client.
+enum Reply {
+    Send(Action<Vec<Response>>),
+    /// Send the status lines, then the PIN as a `D` line, then `OK`.
+    Pin(Vec<Response>, SecretString),
+}
+
+impl Reply {
+    /// The responses the reply stands for, with the PIN as a [`Response::SecretD`].
+    fn into_action(self) -> Action<Vec<Response>> {
+        match self {
+            Reply::Send(action) => action,
+            Reply::Pin(mut resps, pin) => {
+                resps.extend([Response::SecretD(pin), Response::ok()]);
+                Action::Next(resps)
+            }
+        }
+    }
+
+    /// Write the reply with `respond`, the PIN last before the `OK` that ends it. Returns whether
+    /// the connection should be closed.
+    fn send(
+        self,
+        mut respond: impl FnMut(&Response) -> std::io::Result<()>,
+    ) -> std::io::Result<bool> {
+        let (stop, resps, pin) = match self {
+            Reply::Send(Action::Next(resps)) => (false, resps, None),
+            Reply::Send(Action::Stop(resps)) => (true, resps, None),
+            Reply::Pin(resps, pin) => (false, resps, Some(pin)),
+        };
+        for resp in &resps {
+            respond(resp)?;
+        }
+        if let Some(pin) = pin {
+            // The PIN is wiped as the response is dropped, once it has been written
+            respond(&Response::SecretD(pin))?;
+            respond(&Response::ok())?;
+        }
+        Ok(stop)
+    }
+}
+
+impl From<Action<Vec<Response>>> for Reply {
+    fn from(action: Action<Vec<Response>>) -> Self {
+        Reply::Send(action)
+    }
+}
+
+/// How a request is handled: whether it is already answered or needs a dialog shown.
+enum Dispatch {
+    Done(Action<Vec<Response>>),
+    GetPin,
+    Confirm { one_button: bool },
+    Message,
+}
+
+/// The value of an option set by the client with `OPTION`.
+///
+/// gpg-agent disables a boolean option by prefixing it with `no-`, so `OPTION no-grab` is stored
+/// as `grab` being `Disabled` rather than as an option of its own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OptionValue {
+    /// Set with `OPTION <key>`
+    Enabled,
+    /// Set with `OPTION no-<key>`
+    Disabled,
+    /// Set with `OPTION <key>=<value>`
+    Value(String),
+}
+
+/// The labels shown in a dialog.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Labels<'a> {
+    pub prompt: Option<&'a str>,
+    pub ok: Option<&'a str>,
+    pub cancel: Option<&'a str>,
+}
+
+/// The dialog settings sent by the client.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct State {
+    timeout: Option<u64>,
     desc: Option<String>,
-    keyinfo: Option<String>,
+    keyinfo: Option<Keyinfo<'static>>,
     prompt: Option<String>,
     title: Option<String>,
     ok: Option<String>,
     cancel: Option<String>,
     notok: Option<String>,
     error: Option<String>,
+    /// The label of the repeat field, which is shown if this is `Some`, even if it is empty.
     repeat: Option<String>,
     repeatok: Option<String>,
     repeaterror: Option<String>,
+    /// The label of the quality bar, which is shown if this is `Some`, even if it is empty.
     qualitybar: Option<String>,
     qualitybar_tt: Option<String>,
     genpin: Option<String>,
     genpin_tt: Option<String>,
-    options: HashMap<String, Option<String>>,
+    /// In the order the client first set them.
+    options: IndexMap<String, OptionValue>,
+    /// Whether the dialog is asking the user to repeat the PIN.
+    repeating: bool,
+    /// Whether the PINs the user entered last time didn't match.
+    mismatch: bool,
+    /// How many `GETPIN`s in a row failed, including those whose PIN the client rejected.
+    failed_attempts: u32,
 }
 
-#[derive(Debug, PartialEq, Eq)]
-pub struct Listener {
+impl State {
+    /// The description set with `SETDESC`.
+    #[must_use]
+    pub fn desc(&self) -> Option<&str> {
+        self.desc.as_deref()
+    }
+
+    /// The prompt set with `SETPROMPT`. See [`State::effective_labels`] for the prompt to show.
+    #[must_use]
+    pub fn prompt(&self) -> Option<&str> {
+        self.prompt.as_deref()
+    }
+
+    /// The title set with `SETTITLE`.
+    #[must_use]
+    pub fn title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+
+    /// The error from the last attempt, set with `SETERROR`. It is only shown by the next dialog
+    /// asking for a PIN.
+    #[must_use]
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    /// The key set with `SETKEYINFO`.
+    #[must_use]
+    pub fn keyinfo(&self) -> Option<&Keyinfo<'static>> {
+        self.keyinfo.as_ref()
+    }
+
+    /// The options set with `OPTION`, in the order the client first set them.
+    pub fn options(&self) -> impl Iterator<Item = (&str, &OptionValue)> {
+        self.options.iter().map(|(k, v)| (k.as_str(), v))
+    }
+
+    /// The value of the option `key`, if it was set with `OPTION <key>=<value>`.
+    #[must_use]
+    pub fn option(&self, key: &str) -> Option<&str> {
+        match self.options.get(key)? {
+            OptionValue::Value(v) => Some(v),
+            OptionValue::Enabled | OptionValue::Disabled => None,
+        }
+    }
+
+    /// Whether the boolean option `key` is enabled, or `None` if the client didn't set it.
+    ///
+    /// `OPTION <key>` enables it, while `OPTION no-<key>` and `OPTION <key>=0` disable it. Any
+    /// other value enables it.
+    #[must_use]
+    pub fn flag(&self, key: &str) -> Option<bool> {
+        match self.options.get(key)? {
+            OptionValue::Enabled => Some(true),
+            OptionValue::Disabled => Some(false),
+            OptionValue::Value(v) => Some(v != "0"),
+        }
+    }
+
+    /// Whether the client allowed PINs to be taken from and stored in a cache or keyring outside
+    /// of gpg-agent with `OPTION allow-external-password-cache`. gpg-agent only sends it for keys
+    /// whose PIN may be cached, and external storage must be bypassed without it.
+    #[must_use]
+    pub fn external_cache_allowed(&self) -> bool {
+        self.flag("allow-external-password-cache") == Some(true)
+    }
+
+    /// The key the PIN may be remembered with outside of gpg-agent, which is the `SETKEYINFO`
+    /// of the key, if the client allowed it and the key is cacheable.
+    pub(crate) fn external_cache_key(&self) -> Option<&str> {
+        let keyinfo = self.keyinfo.as_ref()?;
+        (self.external_cache_allowed() && keyinfo.cacheable()).then(|| keyinfo.raw())
+    }
+
+    /// Whether the client asked with `OPTION formatted-passphrase` for the passphrase to be shown
+    /// in groups of characters, e.g. for a generated passphrase.
+    #[must_use]
+    pub fn formatted_passphrase(&self) -> bool {
+        self.flag("formatted-passphrase").unwrap_or(false)
+    }
+
+    /// Undo the formatting of a formatted passphrase. The backend may return it with the spaces
+    /// that separate its groups, which aren't part of it, so every space is removed.
+    fn unformat(&self, mut pin: SecretString) -> SecretString {
+        if self.formatted_passphrase() {
+            pin.remove_spaces();
+        }
+        pin
+    }
+
+    /// The labels to show in the dialog. Labels the client didn't set with `SETPROMPT`, `SETOK`
+    /// or `SETCANCEL` fall back to the `default-prompt`, `default-ok` and `default-cancel` options,
+    /// which gpg-agent sets to labels in the user's language.
+    #[must_use]
+    pub fn effective_labels(&self) -> Labels<'_> {
+        Labels {
+            prompt: self
+                .prompt
+                .as_deref()
+                .or_else(|| self.option("default-prompt")),
+            ok: self.ok.as_deref().or_else(|| self.option("default-ok")),
+            cancel: self
+                .cancel
+                .as_deref()
+                .or_else(|| self.option("default-cancel")),
+        }
+    }
+
+    /// The error to show because the PINs the user entered last time didn't match: the text set
+    /// with `SETREPEATERROR` or a default message. `None` unless the dialog is being shown again.
+    #[must_use]
+    pub fn repeat_error(&self) -> Option<&str> {
+        self.mismatch
+            .then(|| self.repeaterror.as_deref().unwrap_or(REPEAT_MISMATCH))
+    }
+
+    /// The label of the repeat field, or a default one if the client sent a bare `SETREPEAT`.
+    /// `None` unless the client asked for a repeat.
+    fn repeat_label(&self) -> Option<&str> {
+        self.repeat.as_deref().map(|label| {
+            if label.is_empty() {
+                DEFAULT_REPEAT_LABEL
+            } else {
+                label
+            }
+        })
+    }
+
+    /// The state to ask for the PIN again with, to check that the user can repeat it, using the
+    /// label for the repeat field as the prompt. `None` unless the client asked for a repeat.
+    fn for_repeat(&self) -> Option<State> {
+        self.repeat_label().map(|label| {
+            let mut state = self.clone();
+            state.prompt = Some(label.to_string());
+            state.repeating = true;
+            state
+        })
+    }
+
+    /// Wipe the text the client sent from memory, as it may describe the key or the user. This
+    /// happens whenever a state is dropped, e.g. on `RESET` or when the client disconnects.
+    fn wipe(&mut self) {
+        for text in [
+            &mut self.desc,
+            &mut self.prompt,
+            &mut self.title,
+            &mut self.ok,
+            &mut self.cancel,
+            &mut self.notok,
+            &mut self.error,
+            &mut self.repeat,
+            &mut self.repeatok,
+            &mut self.repeaterror,
+            &mut self.qualitybar,
+            &mut self.qualitybar_tt,
+            &mut self.genpin,
+            &mut self.genpin_tt,
+        ] {
+            text.zeroize();
+        }
+        self.keyinfo.zeroize();
+        for (mut key, value) in self.options.drain(..) {
+            key.zeroize();
+            if let OptionValue::Value(mut v) = value {
+                v.zeroize();
+            }
+        }
+    }
+
+    /// The state of a new session, in which the display, terminal and parent window are those in
+    /// `config` until the client sets them with `OPTION`.
+    fn new(config: &Config) -> Self {
+        let options = [
+            ("display", &config.display),
+            ("ttyname", &config.ttyname),
+            ("ttytype", &config.ttytype),
+            ("parent-wid", &config.parent_wid),
+        ]
+        .into_iter()
+        .filter_map(|(k, v)| v.clone().map(|v| (k.to_string(), OptionValue::Value(v))))
+        .collect();
+        let mut state = Self::default();
+        state.options = options;
+        state
+    }
+
+    /// The environment variables describing the dialog for the command. Values have already been
+    /// percent-decoded by the parser, so e.g. `%0A` in a `SETDESC` is exported as a newline.
+    fn env(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        let labels = self.effective_labels();
+        [
+            ("PINENTRY_DESC", self.desc.as_deref()),
+            ("PINENTRY_PROMPT", labels.prompt),
+            ("PINENTRY_OK", labels.ok),
+            ("PINENTRY_CANCEL", labels.cancel),
+            ("PINENTRY_NOTOK", self.notok.as_deref()),
+            ("PINENTRY_TITLE", self.title.as_deref()),
+            ("PINENTRY_KEYINFO", self.keyinfo.as_ref().map(Keyinfo::raw)),
+            ("PINENTRY_ERROR", self.error.as_deref()),
+            ("PINENTRY_GENPIN", self.genpin.as_deref()),
+            ("PINENTRY_GENPIN_TT", self.genpin_tt.as_deref()),
+            (
+                "PINENTRY_FORMATTED_PASSPHRASE",
+                self.formatted_passphrase().then_some("1"),
+            ),
+            (
+                "PINENTRY_FORMATTED_PASSPHRASE_HINT",
+                self.option("formatted-passphrase-hint"),
+            ),
+            (
+                "PINENTRY_INVISIBLE_CHAR",
+                Some(
+                    self.option("invisible-char")
+                        .unwrap_or(DEFAULT_INVISIBLE_CHAR),
+                ),
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(k, v)| v.map(|v| (k, v)))
+        .chain(
+            self.repeat_label()
+                .filter(|_| self.repeating)
+                .map(|label| ("PINENTRY_REPEAT", label)),
+        )
+        .chain(self.repeat_error().map(|e| ("PINENTRY_REPEAT_ERROR", e)))
+        .chain(self.session_env())
+    }
+
+    /// The environment variables pointing the command at the client's display, terminal and
+    /// window.
+    fn session_env(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        let option = |k| self.option(k);
+        // X displays have the form `host:display`, while Wayland ones are socket names
+        let display = option("display").map(|d| {
+            if d.contains(':') {
+                ("DISPLAY", d)
+            } else {
+                ("WAYLAND_DISPLAY", d)
+            }
+        });
+        display
+            .into_iter()
+            .chain(option("ttyname").map(|t| ("GPG_TTY", t)))
+            .chain(option("ttytype").map(|t| ("TERM", t)))
+            .chain(option("parent-wid").map(|w| ("PINENTRY_PARENT_WID", w)))
+    }
+
+    /// The timeout for the next dialog. `SETTIMEOUT` overrides `default`, and either being zero
+    /// means no timeout.
+    fn timeout(&self, default: Option<Duration>) -> Option<Duration> {
+        self.timeout
+            .map(Duration::from_secs)
+            .or(default)
+            .filter(|t| !t.is_zero())
+    }
+
+    /// The value for a `{name}` placeholder in the command, if `name` is a known placeholder.
+    fn placeholder(&self, name: &str) -> Option<&str> {
+        let value = match name {
+            "prompt" => self.effective_labels().prompt,
+            "desc" => self.desc.as_deref(),
+            "title" => self.title.as_deref(),
+            "keyinfo" => self.keyinfo.as_ref().map(Keyinfo::raw),
+            "error" => self.error.as_deref(),
+            "parent_wid" => self.option("parent-wid"),
+            _ => return None,
+        };
+        Some(value.unwrap_or_default())
+    }
+
+    /// Substitute placeholders like `{prompt}` in a command argument.
+    ///
+    /// Missing fields expand to the empty string, `{{` and `}}` expand to literal braces, and
+    /// unknown placeholders are left as they are.
+    fn substitute(&self, arg: &str) -> String {
+        let mut out = String::with_capacity(arg.len());
+        let mut rest = arg;
+
+        while let Some(i) = rest.find(['{', '}']) {
+            out.push_str(&rest[..i]);
+            rest = &rest[i..];
+
+            if rest.starts_with("{{") || rest.starts_with("}}") {
+                out.push_str(&rest[..1]);
+                rest = &rest[2..];
+                continue;
+            }
+
+            let value = rest
+                .strip_prefix('{')
+                .and_then(|r| r.find('}').map(|end| (&r[..end], end)))
+                .and_then(|(name, end)| self.placeholder(name).map(|v| (v, end + 2)));
+            match value {
+                Some((value, len)) => {
+                    out.push_str(value);
+                    rest = &rest[len..];
+                }
+                None => {
+                    out.push_str(&rest[..1]);
+                    rest = &rest[1..];
+                }
+            }
+        }
+        out.push_str(rest);
+
+        out
+    }
+}
+
+impl Drop for State {
+    fn drop(&mut self) {
+        self.wipe();
+    }
+}
+
+#[derive(Debug)]
+pub struct Listener<P = ConfiguredProvider> {
     config: Config,
+    provider: P,
     state: State,
+    cache: Option<SharedCache>,
+    fallback: Option<Pinentry>,
+    observer: Box<dyn SessionObserver>,
+    /// Whether to echo each option as it was recorded in a comment, for debugging.
+    comment_options: bool,
+    /// Whether to tell the client in a comment that a PIN came from the fallback, for debugging.
+    comment_fallback: bool,
+    /// Whether the fallback pinentry was used for the current `GETPIN`.
+    fell_back: Cell<bool>,
+    /// Whether the provider took the PIN for the current `GETPIN` from storage, like a keyring.
+    from_cache: Cell<bool>,
+}
+
+/// Serve a single Assuan session on `input` and `output`, getting PINs from `provider`.
+///
+/// ```
+/// use elephantine::{config::Config, provider::PinProvider, secret::SecretString};
+/// use elephantine::{GetPinError, State};
+///
+/// struct Fixed;
+///
+/// impl PinProvider for Fixed {
+///     fn get_pin(&self, _state: &State) -> Result<SecretString, GetPinError> {
+///         Ok(SecretString::from("1234"))
+///     }
+/// }
+///
+/// let mut output = vec![];
+/// elephantine::serve(Config::default(), Fixed, "GETPIN\n".as_bytes(), &mut output).unwrap();
+/// assert_eq!(output, b"OK Greetings from Elephantine\nD 1234\nOK\n");
+/// ```
+///
+/// # Errors
+/// If there was an error reading from `input` or writing to `output`.
+pub fn serve(
+    config: Config,
+    provider: impl PinProvider,
+    input: impl BufRead,
+    output: &mut impl Write,
+) -> Result<(), Error> {
+    Listener::new(config, provider).listen(input, output)
+}
+
+impl From<Config> for Listener {
+    /// Create a listener that gets the PIN from the backend selected by `config`.
+    fn from(config: Config) -> Self {
+        let provider = ConfiguredProvider::from(&config);
+        Self::new(config, provider)
+    }
 }
 
-impl Listener {
+impl<P> Listener<P> {
     #[must_use]
-    pub fn new(config: Config) -> Self {
+    pub fn new(config: Config, provider: P) -> Self {
+        let cache = config
+            .cache
+            .then(|| Arc::new(Mutex::new(Cache::new(config.cache_ttl))));
+        let fallback = (!config.fallback_command.is_empty())
+            .then(|| Pinentry::new(config.fallback_command.to_vec()).timeout(config.timeout));
         Self {
+            state: State::new(&config),
             config,
-            state: State::default(),
+            provider,
+            cache,
+            fallback,
+            observer: Box::new(NoObserver),
+            comment_options: false,
+            comment_fallback: false,
+            fell_back: Cell::new(false),
+            from_cache: Cell::new(false),
         }
     }
 
-    /// Listen for Assuan requests and respond to them
+    /// Use `cache` to remember PINs instead of a cache of this listener's own, so that it can be
+    /// shared with other listeners. It is only used if caching is enabled in the config.
+    #[must_use]
+    pub fn cache(mut self, cache: SharedCache) -> Self {
+        if self.config.cache {
+            self.cache = Some(cache);
+        }
+        self
+    }
+
+    /// Pass every request read and response written by [`listen`](Self::listen) to `observer`.
+    #[must_use]
+    pub fn observer(mut self, observer: impl SessionObserver + 'static) -> Self {
+        self.observer = Box::new(observer);
+        self
+    }
+
+    /// Reply to each `OPTION` with a comment showing the key and value it was recorded as, before
+    /// the `OK`. This is meant for debugging, so it is off by default.
+    #[must_use]
+    pub fn comment_options(mut self, enabled: bool) -> Self {
+        self.comment_options = enabled;
+        self
+    }
+
+    /// Reply to a `GETPIN` the fallback pinentry answered with a comment saying so, before any
+    /// other response, so that it shows up in the client's debug log. Clients ignore comments, so
+    /// the responses they parse are the same. This is meant for debugging, so it is off by
+    /// default.
+    #[must_use]
+    pub fn comment_fallback(mut self, enabled: bool) -> Self {
+        self.comment_fallback = enabled;
+        self
+    }
+
+    /// What the client has set in the current session, e.g. to show a dialog from a provider
+    /// embedded in the same process.
+    #[must_use]
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    /// The `OK` a connection starts with, unless it is disabled.
+    fn greeting(&self) -> Option<Response> {
+        (!self.config.no_greeting)
+            .then(|| Response::Ok(self.config.greeting.clone().filter(|g| !g.is_empty())))
+    }
+
+    /// What to tell the client after reading a request failed.
     ///
     /// # Errors
-    ///
-    pub fn listen(&mut self, input: impl BufRead, output: &mut impl Write) -> Result<()> {
-        writeln!(
-            output,
-            "{}",
-            Response::Ok(Some("Greetings from Elephantine".to_string())),
-        )?;
-        log::debug!("Started Assuan server...");
+    /// If the error can't be answered, e.g. because the connection failed.
+    fn read_error(e: std::io::Error) -> std::io::Result<Action<Vec<Response>>> {
+        if signal::terminating() {
+            log::debug!("{e}");
+            return Ok(Action::Stop(vec![Response::ok_msg("closing connection")]));
+        }
+        match e.kind() {
+            // The input is read with an idle timeout
+            std::io::ErrorKind::TimedOut => {
+                log::debug!("Closing the idle connection: {e}");
+                Ok(Action::Stop(vec![Response::err(
+                    assuan::pinentry_error(assuan::TIMEOUT),
+                    "Timeout",
+                )]))
+            }
+            _ if LineTooLong::is(&e) => {
+                log::debug!("{e}");
+                Ok(Action::Next(vec![Response::err(
+                    assuan::protocol_error(assuan::LINE_TOO_LONG),
+                    "Line too long",
+                )]))
+            }
+            // Requests are percent escaped ASCII, so a stray byte is the client's mistake
+            _ if NotUtf8::is(&e) => {
+                log::debug!("{e}");
+                Ok(Action::Next(vec![Response::err(
+                    assuan::protocol_error(assuan::SYNTAX),
+                    "Invalid UTF-8",
+                )]))
+            }
+            _ => Err(e),
+        }
+    }
+
+    /// Parse a request line and show it to the observer, or return the error to reply with.
+    fn parse_request<'l>(&self, line: &'l str) -> std::result::Result<Request<'l>, Response> {
+        log::debug!("Request: {}", redact(line));
+        let keyword = line.split_once(' ').map_or(line, |(keyword, _)| keyword);
+        if self.disabled(keyword) {
+            log::debug!("{keyword} is disabled");
+            return Err(Response::err(
+                assuan::protocol_error(assuan::NOT_IMPLEMENTED),
+                "Not implemented",
+            ));
+        }
+        match parse(line) {
+            Ok(req) => {
+                self.observer.on_request(&req);
+                Ok(req)
+            }
+            Err(e) => {
+                log::debug!("{e}");
+                Err(Response::err(
+                    assuan::protocol_error(assuan::NOT_IMPLEMENTED),
+                    "Unknown command",
+                ))
+            }
+        }
+    }
 
-        for line in input.lines() {
-            let line = line?;
-            log::debug!("Request: {}", line);
+    /// Whether the client may not send a request starting with `keyword`. Disabling `GENPIN`
+    /// disables the requests that set the label of the generate button, without which the user
+    /// isn't offered to generate a PIN.
+    fn disabled(&self, keyword: &str) -> bool {
+        let generate = ["SETGENPIN", "SETGENPIN_TT"]
+            .iter()
+            .any(|k| k.eq_ignore_ascii_case(keyword));
+        self.config.disabled_commands.iter().any(|disabled| {
+            disabled.eq_ignore_ascii_case(keyword)
+                || (generate && disabled.eq_ignore_ascii_case("GENPIN"))
+        })
+    }
 
-            let req = parse(&line)?;
-            match self.handle_req(req) {
-                Action::Next(resps) => {
-                    for resp in resps {
-                        writeln!(output, "{resp}")?;
-                    }
-                }
-                Action::Stop(resps) => {
-                    for resp in resps {
-                        writeln!(output, "{resp}")?;
-                    }
-                    return Ok(());
-                }
+    /// Forget the session, however it ended, so that the next starts afresh. Dialogs are shown
+    /// while handling a request, so none can still be open.
+    fn end_session(&mut self, result: std::io::Result<()>) -> Result<(), Error> {
+        // The old state is wiped as it is dropped
+        self.state = State::new(&self.config);
+        match result {
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {
+                log::debug!("The client disconnected: {e}");
+                Ok(())
             }
+            result => result.map_err(Error::from),
         }
-        Ok(())
     }
 
-    fn handle_req(&mut self, req: Request) -> Action<Vec<Response>> {
+    /// Handle a request that doesn't show a dialog. Dialogs are left to the caller, as they are
+    /// shown differently by the blocking and async listeners.
+    fn dispatch(&mut self, req: Request) -> Dispatch {
         use crate::request::Request::*;
         use Action::*;
-        match req {
+        Dispatch::Done(match req {
             Set(s) => Next(self.handle_set_req(s)),
             Option(o) => Next(self.handle_option_req(o)),
-            Message => {
-                // Show a message with the value of the last SETDESC
-                Next(vec![Response::Ok(None)])
-            }
-            Confirm => {
-                // Show a confirmation dialog with the value of the last SETDESC
-                Next(vec![Response::Ok(None)])
-            }
-            ConfirmOneButton => {
-                // Show a confirmation dialog with the value of the last SETDESC, but with only one
-                // button
-                Next(vec![Response::Ok(None)])
-            }
+            Message => return Dispatch::Message,
+            Confirm => return Dispatch::Confirm { one_button: false },
+            ConfirmOneButton => return Dispatch::Confirm { one_button: true },
             GetInfoPid => Next(vec![
                 Response::D(format!("{}", std::process::id())),
-                Response::Ok(None),
+                Response::ok(),
             ]),
-            GetInfoVersion => Next(vec![
-                Response::D(crate::build_info::PKG_VERSION.to_string()),
-                Response::Ok(None),
+            GetInfoVersion => Next(vec![Response::D(version_string()), Response::ok()]),
+            GetInfoFlavor => Next(vec![
+                Response::D(
+                    self.config
+                        .flavor
+                        .as_deref()
+                        .unwrap_or(config::DEFAULT_FLAVOR)
+                        .to_string(),
+                ),
+                Response::ok(),
             ]),
-            GetInfoFlavor => Next(vec![Response::D("walker".to_string()), Response::Ok(None)]),
             GetInfoTtyinfo => {
                 // TODO: find out what this is supposed to do by reading more from
                 // https://github.com/gpg/pinentry/blob/f4be34f83fd2079fa452525738ef19783c712438/pinentry/pinentry.c#L1896
@@ -166,98 +852,2599 @@ impl Listener {
                         users::get_current_uid(),
                         users::get_current_gid(),
                     )),
-                    Response::Ok(None),
+                    Response::ok(),
                 ])
             }
-            GetPin => self.get_pin().map_or_else(
-                |e| match e {
-                    GetPinError::Command(e) => Next(vec![Response::Err(e.code, e.stderr)]),
-                    e => Stop(vec![Response::Err(1, e.to_string())]),
-                },
-                |pin| Next(vec![Response::D(pin), Response::Ok(None)]),
-            ),
+            GetPin => return Dispatch::GetPin,
+            ClearPassphrase(key) => {
+                if let Some(cache) = &self.cache {
+                    cache
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .remove(&key);
+                }
+                Next(vec![Response::ok()])
+            }
             Reset => {
-                self.state = State::default();
-                Next(vec![Response::Ok(None)])
+                self.state = State::new(&self.config);
+                Next(vec![Response::ok()])
             }
             Help => {
                 // TODO Print all available commands
-                Next(vec![Response::Ok(None)])
+                Next(vec![Response::ok()])
+            }
+            Nop => Next(vec![Response::ok()]),
+            // Like libassuan, which reserves the command but doesn't implement it
+            Auth => Next(vec![Response::err(
+                assuan::protocol_error(assuan::NOT_IMPLEMENTED),
+                "Not implemented",
+            )]),
+            // There is no operation in progress to cancel, as dialogs block reading requests
+            Cancel => self.dialog_error(GetPinError::Canceled),
+            Bye | End | Quit => Stop(vec![Response::ok_msg("closing connection")]),
+        })
+    }
+
+    /// The responses for a dialog that failed.
+    fn dialog_error(&self, e: GetPinError) -> Action<Vec<Response>> {
+        use Action::*;
+        match e {
+            GetPinError::Command(e) => {
+                log::warn!("{e}");
+                Next(vec![Response::err(
+                    assuan::pinentry_error(assuan::GENERAL),
+                    e.summary(),
+                )])
+            }
+            GetPinError::Malformed(_) | GetPinError::MissingEnv(_) => {
+                log::warn!("{e}");
+                Next(vec![Response::err(
+                    assuan::pinentry_error(assuan::GENERAL),
+                    e.to_string(),
+                )])
             }
-            Nop => Next(vec![Response::Ok(None)]),
-            Bye | End | Quit | Cancel | Auth => {
-                Stop(vec![Response::Ok(Some("closing connection".to_string()))])
+            GetPinError::Timeout(_) => Next(vec![Response::err(
+                assuan::pinentry_error(assuan::TIMEOUT),
+                "Timeout",
+            )]),
+            GetPinError::Canceled | GetPinError::TooManyAttempts(_) => Next(vec![Response::err(
+                assuan::pinentry_error(assuan::CANCELED),
+                e.to_string(),
+            )]),
+            GetPinError::TooShort(_) => Next(vec![Response::err(
+                assuan::pinentry_error(assuan::BAD_PASSPHRASE),
+                self.state.error.clone().unwrap_or_else(|| e.to_string()),
+            )]),
+            GetPinError::RepeatMismatch => Next(vec![Response::err(
+                assuan::pinentry_error(assuan::BAD_PASSPHRASE),
+                self.state
+                    .repeaterror
+                    .clone()
+                    .unwrap_or_else(|| e.to_string()),
+            )]),
+            // The backend can't be run or the client can't be asked, which won't get better
+            e => {
+                log::warn!("{e}");
+                Stop(vec![Response::err(
+                    assuan::pinentry_error(assuan::GENERAL),
+                    e.to_string(),
+                )])
+            }
+        }
+    }
+
+    /// The responses to `GETPIN` if no dialog is to be shown, either because too many attempts in
+    /// a row failed or because the PIN is in the cache.
+    ///
+    /// A `GETPIN` after `SETERROR` counts as a failed attempt, as the client rejected the PIN it
+    /// got last time. The error is only shown for that `GETPIN`, so each `SETERROR` counts once.
+    fn responses_without_dialog(&mut self) -> Option<Reply> {
+        if self.state.error.is_some() {
+            self.state.failed_attempts += 1;
+        }
+        if let Some(max) = self
+            .config
+            .max_attempts
+            .filter(|&max| self.state.failed_attempts >= max)
+        {
+            log::warn!("Not showing the dialog, as {max} attempts in a row failed");
+            let action = self.dialog_error(GetPinError::TooManyAttempts(max));
+            self.state.error.zeroize();
+            return Some(action.into());
+        }
+        self.cached_responses()
+    }
+
+    /// The responses to `GETPIN` if the PIN is in the cache.
+    fn cached_responses(&self) -> Option<Reply> {
+        self.cached_pin()
+            .map(|pin| Reply::Pin(vec![Response::password_from_cache()], pin))
+    }
+
+    /// The responses to `GETPIN` once the user entered `pin` or the dialog failed. A PIN the
+    /// provider took from storage is marked as coming from a cache, like one from our own.
+    ///
+    /// Like pinentry, the error set with `SETERROR` is forgotten once the dialog has shown it. The
+    /// count of failed attempts is only reset by a PIN entered without one, as the client may
+    /// still reject a PIN entered after an error.
+    fn pin_responses(&mut self, pin: std::result::Result<SecretString, GetPinError>) -> Reply {
+        let from_cache = self.from_cache.take();
+        let reply = match pin
+            .map(|pin| self.state.unformat(pin))
+            .and_then(|pin| self.check_length(pin))
+        {
+            Ok(pin) => {
+                if self.state.error.is_none() {
+                    self.state.failed_attempts = 0;
+                }
+                self.cache_pin(&pin);
+                self.touch_file();
+                let status = if from_cache {
+                    Some(Response::password_from_cache())
+                } else {
+                    self.state.repeat.as_ref().map(|_| Response::pin_repeated())
+                };
+                Reply::Pin(status.into_iter().collect(), pin)
+            }
+            Err(e) => {
+                self.state.failed_attempts += 1;
+                self.dialog_error(e).into()
             }
+        };
+        self.state.error.zeroize();
+        reply
+    }
+
+    /// The responses to `CONFIRM` once the user answered the dialog or it failed.
+    fn confirmation(
+        &self,
+        one_button: bool,
+        confirmed: std::result::Result<bool, GetPinError>,
+    ) -> Action<Vec<Response>> {
+        match confirmed {
+            Ok(true) => Action::Next(vec![Response::ok()]),
+            Ok(false) if one_button => Action::Next(vec![Response::ok()]),
+            Ok(false) => Action::Next(vec![Response::err(
+                assuan::pinentry_error(assuan::NOT_CONFIRMED),
+                "Not confirmed",
+            )]),
+            Err(e) => self.dialog_error(e),
         }
     }
 
     fn handle_set_req(&mut self, req: Set) -> Vec<Response> {
         use Set::*;
         match req {
-            Timeout(t) => self.state.timeout = t,
+            Timeout(t) => self.state.timeout = Some(t),
             Desc(m) => self.state.desc = Some(m.to_string()),
-            Keyinfo(m) => self.state.keyinfo = Some(m.to_string()),
+            Keyinfo(k) => self.state.keyinfo = Some(k.into_owned()),
             Prompt(m) => self.state.prompt = Some(m.to_string()),
             Title(m) => self.state.title = Some(m.to_string()),
             Ok(m) => self.state.ok = Some(m.to_string()),
             Cancel(m) => self.state.cancel = Some(m.to_string()),
             Notok(m) => self.state.notok = Some(m.to_string()),
             Error(m) => self.state.error = Some(m.to_string()),
-            Repeat(m) => self.state.repeat = Some(m.to_string()),
+            Repeat(m) => {
+                self.state.repeat = Some(m.map(|s| s.to_string()).unwrap_or_default());
+            }
             Repeaterror(m) => self.state.repeaterror = Some(m.to_string()),
             Repeatok(m) => self.state.repeatok = Some(m.to_string()),
-            Qualitybar(m) => self.state.qualitybar = m.map(|s| s.to_string()),
+            Qualitybar(m) => {
+                self.state.qualitybar = Some(m.map(|s| s.to_string()).unwrap_or_default());
+            }
             QualitybarTt(m) => self.state.qualitybar_tt = Some(m.to_string()),
             Genpin(m) => self.state.genpin = Some(m.to_string()),
             GenpinTt(m) => self.state.genpin_tt = Some(m.to_string()),
-        };
-        vec![Response::Ok(None)]
+        }
+        vec![Response::ok()]
     }
 
     fn handle_option_req(&mut self, o: OptionReq) -> Vec<Response> {
         use OptionReq::*;
-        match o {
-            Bool(k) => {
-                self.state.options.insert(k.to_string(), None);
-            }
-            KV(k, v) => {
-                self.state
-                    .options
-                    .insert(k.to_string(), Some(v.to_string()));
-            }
+        let (key, value) = match o {
+            Bool(k) => match k.strip_prefix("no-") {
+                Some(base) if !base.is_empty() => (base.to_string(), OptionValue::Disabled),
+                _ => (k.to_string(), OptionValue::Enabled),
+            },
+            KV(k, v) => (k.to_string(), OptionValue::Value(v.to_string())),
+        };
+        let comment = self.comment_options.then(|| {
+            Response::Comment(match &value {
+                OptionValue::Enabled => format!("Option {key} enabled"),
+                OptionValue::Disabled => format!("Option {key} disabled"),
+                OptionValue::Value(v) => format!("Option {key}={v}"),
+            })
+        });
+        if let Some(old) = self.state.options.insert(key.clone(), value) {
+            log::debug!("Replaced option {key}, which was {old:?}");
+        }
+        comment.into_iter().chain([Response::ok()]).collect()
+    }
+
+    /// The cache and key to remember the current PIN with, if caching is enabled and allowed by
+    /// the client.
+    fn cache_key(&self) -> Option<(&SharedCache, &str)> {
+        Some((self.cache.as_ref()?, self.state.external_cache_key()?))
+    }
+
+    /// Get the cached PIN for the current key.
+    fn cached_pin(&self) -> Option<SecretString> {
+        let (cache, key) = self.cache_key()?;
+        let mut cache = cache.lock().unwrap_or_else(PoisonError::into_inner);
+        if self.state.error.is_some() {
+            // The client is asking again because the cached PIN was wrong
+            cache.remove(key);
+            return None;
+        }
+        cache.get(key)
+    }
+
+    fn cache_pin(&self, pin: &SecretString) {
+        if let Some((cache, key)) = self.cache_key() {
+            cache
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(key, pin.clone());
+        }
+    }
+
+    /// Update the access and modification times of the file given by the `touch-file` option.
+    /// gpg-agent uses this to notice that its socket is still in use. Failures are only logged.
+    fn touch_file(&self) {
+        let Some(path) = self.state.option("touch-file") else {
+            return;
+        };
+        let now = filetime::FileTime::now();
+        if let Err(e) = filetime::set_file_times(path, now, now) {
+            log::warn!("Failed to touch {path}: {e}");
+        }
+    }
+
+    /// Reject `pin` if it is shorter than the configured minimum length, counted in characters.
+    /// A rejected PIN is wiped when it is dropped.
+    fn check_length(&self, pin: SecretString) -> std::result::Result<SecretString, GetPinError> {
+        match self.config.min_pin_length {
+            Some(min) if pin.expose().chars().count() < min => Err(GetPinError::TooShort(min)),
+            _ => Ok(pin),
         }
-        vec![Response::Ok(None)]
     }
 
-    /// Get the PIN using the an external process
+    /// The command showing confirmation dialogs and the variables to run it with.
+    fn confirm_command(
+        &self,
+        one_button: bool,
+    ) -> (Cow<'_, [String]>, &'static [(&'static str, &'static str)]) {
+        let command = if self.config.confirm_command.is_empty() {
+            self.config.backend_command()
+        } else {
+            Cow::Borrowed(&*self.config.confirm_command)
+        };
+        let env: &[_] = if one_button {
+            &[("PINENTRY_ONE_BUTTON", "1")]
+        } else {
+            &[]
+        };
+        (command, env)
+    }
+
+    /// The command showing messages and the variables to run it with.
+    fn message_command(&self) -> (Cow<'_, [String]>, &'static [(&'static str, &'static str)]) {
+        let command = if self.config.message_command.is_empty() {
+            self.config.backend_command()
+        } else {
+            Cow::Borrowed(&*self.config.message_command)
+        };
+        (
+            command,
+            &[("PINENTRY_ONE_BUTTON", "1"), ("PINENTRY_MESSAGE", "1")],
+        )
+    }
+
+    /// Whether the user confirmed a dialog, from the exit code of the command showing it, which
+    /// exits with 0 if the user confirmed and with the cancel exit code if they declined.
     ///
     /// # Errors
-    /// `GetPinError::Setup` if there was a failure to setup the process
-    /// `GenPinError::Output` if there was an error reading the output of the process
     /// `GenPinError::Command` if the command failed
-    fn get_pin(&self) -> std::result::Result<String, GetPinError> {
-        std::process::Command::new(&self.config.command[0])
-            .args(&self.config.command[1..])
-            .output()
-            .map_err(|e| GetPinError::Setup(e, self.config.command.clone()))
-            .and_then(|output| {
-                if output.status.success() {
-                    String::from_utf8(output.stdout).map_err(GetPinError::Output)
-                } else {
-                    Err(GetPinError::Command(CommandError {
-                        code: output.status.code().unwrap_or(1),
-                        stderr: String::from_utf8(output.stderr).unwrap_or_default(),
-                    }))
-                }
-            })
+    fn confirmed(&self, output: &std::process::Output) -> std::result::Result<bool, GetPinError> {
+        match output.status.code() {
+            Some(0) => Ok(true),
+            Some(code) if code == self.config.cancel_exit_code => Ok(false),
+            _ => Err(provider::command_error(output)),
+        }
+    }
+
+    /// The responses to `MESSAGE` once the user dismissed it or the dialog failed.
+    fn acknowledgement(
+        &self,
+        acknowledged: std::result::Result<(), GetPinError>,
+    ) -> Action<Vec<Response>> {
+        acknowledged.map_or_else(
+            |e| self.dialog_error(e),
+            |()| Action::Next(vec![Response::ok()]),
+        )
     }
 }
 
-#[cfg(test)]
-mod test {
-    use super::Listener;
-    use crate::config::Config;
-    use indoc::indoc;
+impl<P: PinProvider> Listener<P> {
+    /// Listen for Assuan requests and respond to them
+    ///
+    /// The client closing `output` while we are writing to it is treated like the end of the
+    /// input, as gpg-agent may hang up without waiting for a reply.
+    ///
+    /// # Errors
+    /// If there was an error reading from `input` or writing to `output`.
+    pub fn listen(&mut self, input: impl BufRead, output: &mut impl Write) -> Result<(), Error> {
+        let result = self.serve_connection(input, output);
+        self.end_session(result)
+    }
+
+    fn serve_connection(
+        &mut self,
+        input: impl BufRead,
+        output: &mut impl Write,
+    ) -> std::io::Result<()> {
+        let mut connection =
+            Connection::new(input, output).max_line_length(self.config.max_line_length);
+        self.observer.on_session();
+        if let Some(greeting) = self.greeting() {
+            self.respond(&mut connection, &greeting)?;
+        }
+        log::debug!("Started Assuan server...");
+
+        // Every request is read into the same buffer, which is wiped once the session ends
+        let mut line = Zeroizing::new(String::new());
+        loop {
+            let reply = match connection.read_line_into(&mut line) {
+                Ok(true) if skipped(&line) => continue,
+                Ok(true) => match self.parse_request(&line) {
+                    Ok(req) => self.reply(req, &mut connection),
+                    Err(resp) => Action::Next(vec![resp]).into(),
+                },
+                Ok(false) => {
+                    log::debug!("Client disconnected without saying goodbye");
+                    break;
+                }
+                Err(e) => Self::read_error(e)?.into(),
+            };
+            let stop = reply.send(|resp| self.respond(&mut connection, resp))?;
+            connection.flush()?;
+            // After a signal, any dialog was cancelled, so the client has already been told
+            let stop = stop || signal::terminating();
+            if stop {
+                return Ok(());
+            }
+        }
+        connection.flush()?;
+        Ok(())
+    }
+
+    /// Write a response to the client, after showing it to the observer.
+    fn respond(
+        &self,
+        connection: &mut Connection<impl BufRead, impl Write>,
+        resp: &Response,
+    ) -> std::io::Result<()> {
+        observer::observe_response(&*self.observer, resp);
+        connection.write(resp)
+    }
+
+    /// Handle a request, using `client` to ask the client for more information if needed.
+    ///
+    /// This does no I/O with the client other than through `client`, so it can be used to drive
+    /// a listener without an Assuan connection, e.g. with [`NoInquire`](connection::NoInquire).
+    /// `GETPIN` only gets the PIN from the provider, so with a stub provider the responses to
+    /// every request but `CONFIRM` and `MESSAGE`, which run the configured command, are known.
+    ///
+    /// ```
+    /// use elephantine::{config::Config, connection::NoInquire, request::parse, Listener};
+    /// use elephantine::response::Response;
+    ///
+    /// let mut listener = Listener::from(Config::default());
+    /// let responses = listener
+    ///     .handle_req(parse("GETINFO flavor").unwrap(), &mut NoInquire)
+    ///     .into_inner();
+    /// assert_eq!(
+    ///     responses,
+    ///     vec![Response::D("elephantine".to_string()), Response::ok()]
+    /// );
+    /// ```
+    pub fn handle_req(&mut self, req: Request, client: &mut dyn Inquire) -> Action<Vec<Response>> {
+        self.reply(req, client).into_action()
+    }
+
+    /// Handle a request like [`handle_req`](Self::handle_req), keeping a PIN apart from the
+    /// other responses so it can be written straight to the client.
+    fn reply(&mut self, req: Request, client: &mut dyn Inquire) -> Reply {
+        match self.dispatch(req) {
+            Dispatch::Done(action) => action.into(),
+            Dispatch::GetPin => self.handle_get_pin(client),
+            Dispatch::Confirm { one_button } => {
+                let confirmed = self.confirm(one_button);
+                self.confirmation(one_button, confirmed).into()
+            }
+            Dispatch::Message => {
+                let acknowledged = self.message();
+                self.acknowledgement(acknowledged).into()
+            }
+        }
+    }
+
+    /// Ask for a PIN once, like `GETPIN`, but without a client, e.g. for a script. The PIN is
+    /// neither cached nor taken from the cache, and it can't be generated or rated by a client.
+    ///
+    /// # Errors
+    /// Any error from the provider
+    /// `GenPinError::TooShort` if the PIN has fewer characters than the minimum length
+    pub fn get_pin_once(&self) -> std::result::Result<SecretString, GetPinError> {
+        self.get_pin(&mut connection::NoInquire)
+            .map(|pin| self.state.unformat(pin))
+            .and_then(|pin| self.check_length(pin))
+    }
+
+    fn handle_get_pin(&mut self, client: &mut dyn Inquire) -> Reply {
+        if let Some(reply) = self.responses_without_dialog() {
+            return reply;
+        }
+        self.fell_back.set(false);
+        self.from_cache.set(false);
+        let pin = self.get_pin(client);
+        let reply = self.pin_responses(pin);
+        if !(self.comment_fallback && self.fell_back.get()) {
+            return reply;
+        }
+        let comment = Response::Comment("PIN from the fallback pinentry".to_string());
+        match reply {
+            Reply::Send(Action::Next(resps)) => {
+                Action::Next([comment].into_iter().chain(resps).collect()).into()
+            }
+            Reply::Send(Action::Stop(resps)) => {
+                Action::Stop([comment].into_iter().chain(resps).collect()).into()
+            }
+            Reply::Pin(resps, pin) => Reply::Pin([comment].into_iter().chain(resps).collect(), pin),
+        }
+    }
+
+    /// Get the PIN from the provider, asking for it a second time if `SETREPEAT` was set. If the
+    /// two don't match, the user is asked again with the repeat error shown, up to the configured
+    /// number of attempts.
+    ///
+    /// If `SETQUALITYBAR` was set, the provider can rate passphrases by sending
+    /// `INQUIRE QUALITY` to the client. If `SETGENPIN` was set and the user asks for a PIN to be
+    /// generated, it is fetched from the client with `INQUIRE GENPIN`.
+    ///
+    /// If the provider can't be run and a fallback pinentry is configured, the PIN is fetched
+    /// from the fallback instead.
+    ///
+    /// # Errors
+    /// Any error from the provider
+    /// `GenPinError::RepeatMismatch` if the repeated PIN did not match the first on every attempt
+    fn get_pin(&self, client: &mut dyn Inquire) -> std::result::Result<SecretString, GetPinError> {
+        let mut state = Cow::Borrowed(&self.state);
+        // `validate` rejects 0, but a config that was never validated still asks once
+        for attempt in 1..=self.config.repeat_attempts.max(1) {
+            let pin = match self.settle(self.ask_pin(&state, client), &state, client)? {
+                ControlFlow::Break(pin) => return Ok(pin),
+                ControlFlow::Continue(pin) => pin,
+            };
+
+            // A stored PIN was already entered when it was stored
+            if self.from_cache.get() {
+                return Ok(pin);
+            }
+            let Some(repeat_state) = state.for_repeat() else {
+                return Ok(pin);
+            };
+            let repeated =
+                match self.settle(self.provider.get_pin(&repeat_state), &state, client)? {
+                    ControlFlow::Break(pin) => return Ok(pin),
+                    ControlFlow::Continue(repeated) => repeated,
+                };
+            // Compared in constant time
+            if pin == repeated {
+                return Ok(pin);
+            }
+            // Both PINs are wiped as they are dropped here
+            log::debug!("The repeated PIN did not match on attempt {attempt}");
+            state.to_mut().mismatch = true;
+        }
+
+        Err(GetPinError::RepeatMismatch)
+    }
+
+    /// Handle the errors from either prompt of the dialog described by `state` that don't end it.
+    ///
+    /// If the user asked for a PIN to be generated, it is fetched from the client, and if the
+    /// provider can't be run, the PIN is fetched from the fallback pinentry. Neither needs to be
+    /// repeated, so they are returned as `Break`, while a PIN the user entered is `Continue`.
+    ///
+    /// # Errors
+    /// Any other error from the provider, the client or the fallback
+    fn settle(
+        &self,
+        pin: std::result::Result<SecretString, GetPinError>,
+        state: &State,
+        client: &mut dyn Inquire,
+    ) -> std::result::Result<ControlFlow<SecretString, SecretString>, GetPinError> {
+        match pin {
+            // A generated PIN doesn't need to be repeated
+            Err(GetPinError::Generate) if state.genpin.is_some() => client
+                .inquire("GENPIN", "")
+                .map_err(GetPinError::Inquire)?
+                .ok_or(GetPinError::Canceled)
+                .map(ControlFlow::Break),
+            // The fallback handles repeating the PIN itself
+            Err(GetPinError::Setup(e, command)) => match &self.fallback {
+                Some(fallback) => {
+                    log::warn!("Falling back to another pinentry, as {command:?} failed: {e}");
+                    self.fell_back.set(true);
+                    fallback.get_pin(state, client).map(ControlFlow::Break)
+                }
+                None => Err(GetPinError::Setup(e, command)),
+            },
+            pin => pin.map(ControlFlow::Continue),
+        }
+    }
+
+    /// Get a PIN from the provider, rating passphrases if `SETQUALITYBAR` was set, and remember
+    /// whether it was taken from storage instead of entered by the user.
+    fn ask_pin(
+        &self,
+        state: &State,
+        client: &mut dyn Inquire,
+    ) -> std::result::Result<SecretString, GetPinError> {
+        if state.repeat_error().or(state.error.as_deref()).is_some() {
+            self.alert(state);
+        }
+        let local = self.config.local_quality;
+        let mut rate = |pin: &SecretString| {
+            if local {
+                Ok(Some(quality(pin.expose())))
+            } else {
+                inquire_quality(client, pin)
+            }
+        };
+        let rate: Option<&mut Rate> = state.qualitybar.is_some().then_some(&mut rate);
+        let (pin, origin) = self.provider.get_pin_with_origin(state, rate)?;
+        self.from_cache.set(origin == Origin::Stored);
+        Ok(pin)
+    }
+
+    /// Alert the user on their terminal that the dialog shows an error, if configured to.
+    #[cfg(unix)]
+    fn alert(&self, state: &State) {
+        let gpg_tty = std::env::var(tty::GPG_TTY).ok();
+        let path = tty::path(state, gpg_tty.as_deref());
+        if let Err(e) = tty::alert(self.config.ttyalert, path) {
+            log::warn!("Failed to alert the user on {path}: {e}");
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn alert(&self, _state: &State) {}
+
+    /// Ask the user to confirm the last `SETDESC` using an external process. The command exits
+    /// with 0 if the user confirmed and with the cancel exit code if they declined.
+    ///
+    /// Returns whether the user confirmed. If `one_button` is set, the dialog only needs to be
+    /// acknowledged, so declining is not possible.
+    ///
+    /// # Errors
+    /// `GetPinError::Setup` if there was a failure to setup the process
+    /// `GenPinError::Command` if the command failed
+    /// `GenPinError::Timeout` if the command did not exit before the timeout
+    fn confirm(&self, one_button: bool) -> std::result::Result<bool, GetPinError> {
+        let (command, env) = self.confirm_command(one_button);
+        let output = provider::run(
+            &command,
+            &self.state,
+            self.config.timeout,
+            self.config.clean_env,
+            env,
+        )?;
+
+        self.confirmed(&output)
+    }
+
+    /// Show the last `SETDESC` as a message with a single button using an external process,
+    /// which is run with `PINENTRY_ONE_BUTTON` and `PINENTRY_MESSAGE` set. Returns once the user
+    /// dismissed the message.
+    ///
+    /// # Errors
+    /// `GetPinError::Setup` if there was a failure to setup the process
+    /// `GenPinError::Command` if the command failed
+    /// `GenPinError::Timeout` if the command did not exit before the timeout
+    fn message(&self) -> std::result::Result<(), GetPinError> {
+        let (command, env) = self.message_command();
+        let output = provider::run(
+            &command,
+            &self.state,
+            self.config.timeout,
+            self.config.clean_env,
+            env,
+        )?;
+
+        self.confirmed(&output).map(|_| ())
+    }
+}
+
+/// The version of elephantine, followed by the commit it was built from if it was built from a
+/// git repository, e.g. `0.1.0 (abc1234)`, or `0.1.0 (abc1234-dirty)` if there were uncommitted
+/// changes.
+#[must_use]
+pub fn version_string() -> String {
+    format_version(build::version(), build::git_hash(), build::git_dirty())
+}
+
+fn format_version(version: &str, hash: Option<&str>, dirty: Option<bool>) -> String {
+    match (hash, dirty) {
+        (Some(hash), Some(true)) => format!("{version} ({hash}-dirty)"),
+        (Some(hash), _) => format!("{version} ({hash})"),
+        (None, _) => version.to_string(),
+    }
+}
+
+/// The class of a character, and the number of characters in it. The classes are lowercase and
+/// uppercase letters, digits, and everything else.
+fn character_class(c: char) -> (usize, u32) {
+    match c {
+        'a'..='z' => (0, 26),
+        'A'..='Z' => (1, 26),
+        '0'..='9' => (2, 10),
+        _ => (3, 33),
+    }
+}
+
+/// Estimate the quality of a passphrase from -100 to 100, without asking the client.
+///
+/// The estimate is the entropy of a random passphrase of the same length drawn from the classes
+/// of characters it uses, so it only rewards length and variety. 50 bits scores 0, and 100 bits
+/// or more scores 100.
+///
+/// ```
+/// assert_eq!(elephantine::quality(""), -100);
+/// assert!(elephantine::quality("password") < elephantine::quality("Tr0ub4dor&3"));
+/// ```
+#[must_use]
+pub fn quality(pw: &str) -> i32 {
+    let mut classes = [0; 4];
+    for c in pw.chars() {
+        let (class, size) = character_class(c);
+        classes[class] = size;
+    }
+    let pool: u32 = classes.iter().sum();
+    if pool == 0 {
+        return -100;
+    }
+    let len = u32::try_from(pw.chars().count()).unwrap_or(u32::MAX);
+    let bits = f64::from(len) * f64::from(pool).log2();
+    // Clamped, so it can't be truncated
+    #[allow(clippy::cast_possible_truncation)]
+    let score = (bits * 2.0 - 100.0).clamp(-100.0, 100.0).round() as i32;
+    score
+}
+
+/// Ask the client to rate the quality of a passphrase from -100 to 100.
+///
+/// Returns `None` if the client cancelled the inquiry or sent back something other than a number.
+fn inquire_quality(
+    client: &mut dyn Inquire,
+    pin: &SecretString,
+) -> std::result::Result<Option<i32>, GetPinError> {
+    let reply = client
+        .inquire("QUALITY", pin.expose())
+        .map_err(GetPinError::Inquire)?;
+    Ok(reply
+        .and_then(|r| r.expose().trim().parse::<i32>().ok())
+        .map(|q| q.clamp(-100, 100)))
+}
+
+/// Requests whose payload may hold a secret, e.g. a `D` line answering an `INQUIRE`.
+const SENSITIVE_REQUESTS: &[&str] = &["D"];
+
+/// Whether a request line is blank or a `#` comment, which is skipped without a response, as
+/// lenient Assuan servers do, e.g. when a client is driven by hand with `socat`.
+fn skipped(line: &str) -> bool {
+    if line.starts_with('#') {
+        log::debug!("Comment: {line}");
+        return true;
+    }
+    line.trim().is_empty()
+}
+
+/// Replace the payload of a request line with `***` if it may hold a secret, so it can be logged.
+fn redact(line: &str) -> Cow<'_, str> {
+    let (keyword, payload) = line.split_once(' ').unwrap_or((line, ""));
+    if SENSITIVE_REQUESTS.contains(&keyword) && !payload.is_empty() {
+        Cow::from(format!("{keyword} ***"))
+    } else {
+        Cow::from(line)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Listener;
+    use crate::{
+        config::Config, connection::NoInquire, provider::ConfiguredProvider,
+        test_support::run_session,
+    };
+    use indoc::indoc;
+    use std::time::Duration;
+
+    fn listener(command: &[&str], timeout: Option<Duration>) -> Listener {
+        Listener::from(Config {
+            timeout,
+            command: command.iter().map(ToString::to_string).collect(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn substitute() {
+        let mut listener = listener(&["echo"], None);
+        listener.handle_req(
+            crate::request::parse("SETPROMPT PIN:").unwrap(),
+            &mut NoInquire,
+        );
+        listener.handle_req(
+            crate::request::parse("SETDESC a%0Ab").unwrap(),
+            &mut NoInquire,
+        );
+
+        let test_cases = vec![
+            ("", ""),
+            ("-p", "-p"),
+            ("{prompt}", "PIN:"),
+            ("--mesg={desc}", "--mesg=a\nb"),
+            ("{title}", ""),
+            ("{prompt} {error}!", "PIN: !"),
+            ("--parent={parent_wid}", "--parent="),
+            ("{{prompt}}", "{prompt}"),
+            ("{{{prompt}}}", "{PIN:}"),
+            ("{unknown}", "{unknown}"),
+            ("{prompt", "{prompt"),
+            ("}", "}"),
+        ];
+
+        for (input, expected) in test_cases {
+            assert_eq!(listener.state.substitute(input), expected, "{input}");
+        }
+    }
+
+    #[test]
+    fn effective_labels() {
+        use super::Labels;
+
+        let test_cases = vec![
+            (vec![], Labels::default()),
+            (
+                vec![
+                    "OPTION default-prompt=PIN%3A",
+                    "OPTION default-ok=_OK",
+                    "OPTION default-cancel=_Abbrechen",
+                ],
+                Labels {
+                    prompt: Some("PIN:"),
+                    ok: Some("_OK"),
+                    cancel: Some("_Abbrechen"),
+                },
+            ),
+            (
+                vec![
+                    "OPTION default-prompt=PIN%3A",
+                    "OPTION default-ok=_OK",
+                    "SETPROMPT Passphrase:",
+                    "SETCANCEL No",
+                ],
+                Labels {
+                    prompt: Some("Passphrase:"),
+                    ok: Some("_OK"),
+                    cancel: Some("No"),
+                },
+            ),
+        ];
+
+        for (requests, expected) in test_cases {
+            let mut listener = listener(&["true"], None);
+            for req in &requests {
+                listener.handle_req(crate::request::parse(req).unwrap(), &mut NoInquire);
+            }
+            assert_eq!(listener.state.effective_labels(), expected, "{requests:?}");
+        }
+
+        let mut listener = listener(&["true"], None);
+        listener.handle_req(
+            crate::request::parse("OPTION default-prompt=Passwort:").unwrap(),
+            &mut NoInquire,
+        );
+        assert!(listener
+            .state
+            .env()
+            .any(|kv| kv == ("PINENTRY_PROMPT", "Passwort:")));
+        assert_eq!(listener.state.substitute("{prompt}"), "Passwort:");
+    }
+
+    #[test]
+    fn get_pin_min_length() {
+        use super::{Action::Next, Response};
+        use crate::assuan::{pinentry_error, BAD_PASSPHRASE};
+        use crate::request::Request;
+
+        let too_short = |message: &str| {
+            Next(vec![Response::Err(
+                pinentry_error(BAD_PASSPHRASE),
+                message.to_string(),
+            )])
+        };
+        let accepted = |pin: &str| {
+            Next(vec![
+                Response::SecretD(crate::secret::SecretString::from(pin)),
+                Response::Ok(None),
+            ])
+        };
+        let test_cases = vec![
+            ("1234", None, accepted("1234")),
+            (
+                "123",
+                None,
+                too_short("PIN must have at least 4 characters"),
+            ),
+            ("1234", Some("Too short"), accepted("1234")),
+            ("123", Some("Too short"), too_short("Too short")),
+            ("12345", None, accepted("12345")),
+            // Counted in characters rather than bytes
+            (
+                "ééé",
+                None,
+                too_short("PIN must have at least 4 characters"),
+            ),
+            ("éééé", None, accepted("éééé")),
+        ];
+
+        for (pin, error, expected) in test_cases {
+            let mut listener = Listener::from(Config {
+                command: ["printf", pin].into_iter().map(String::from).collect(),
+                min_pin_length: Some(4),
+                ..Default::default()
+            });
+            if let Some(error) = error {
+                listener.handle_req(
+                    crate::request::parse(&format!("SETERROR {error}")).unwrap(),
+                    &mut NoInquire,
+                );
+            }
+            assert_eq!(
+                listener.handle_req(Request::GetPin, &mut NoInquire),
+                expected,
+                "{pin} {error:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn quality() {
+        let test_cases = vec![
+            ("", -100),
+            ("abc", -72),
+            ("12345678", -47),
+            ("password", -25),
+            ("Tr0ub4dor&3", 45),
+            ("correct horse battery staple", 100),
+        ];
+
+        for (pw, expected) in test_cases {
+            assert_eq!(super::quality(pw), expected, "{pw}");
+        }
+    }
+
+    #[test]
+    fn get_pin_error_display() {
+        use super::GetPinError;
+        use std::time::Duration;
+
+        let test_cases = vec![
+            (Duration::from_mins(5), "Timed out after 5m"),
+            (Duration::from_millis(1500), "Timed out after 1s 500ms"),
+            (Duration::from_millis(100), "Timed out after 100ms"),
+        ];
+
+        for (timeout, expected) in test_cases {
+            assert_eq!(GetPinError::Timeout(timeout).to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn get_pin_error_source() {
+        use super::GetPinError;
+        use std::{error::Error, io};
+
+        let test_cases = vec![
+            (
+                GetPinError::Setup(io::ErrorKind::NotFound.into(), vec!["x".to_string()]),
+                Some("entity not found"),
+            ),
+            (
+                GetPinError::Inquire(io::ErrorKind::UnexpectedEof.into()),
+                Some("unexpected end of file"),
+            ),
+            (GetPinError::Canceled, None),
+        ];
+
+        for (e, expected) in test_cases {
+            assert_eq!(
+                e.source().map(ToString::to_string).as_deref(),
+                expected,
+                "{e}"
+            );
+        }
+
+        let e = GetPinError::Setup(io::ErrorKind::NotFound.into(), vec![]);
+        let source = e.source().and_then(|s| s.downcast_ref::<io::Error>());
+        assert_eq!(source.map(io::Error::kind), Some(io::ErrorKind::NotFound));
+    }
+
+    #[test]
+    fn option_flags() {
+        let test_cases = vec![
+            (vec![], None),
+            (vec!["OPTION grab"], Some(true)),
+            (vec!["OPTION no-grab"], Some(false)),
+            (vec!["OPTION grab", "OPTION no-grab"], Some(false)),
+            (vec!["OPTION no-grab", "OPTION grab"], Some(true)),
+            (vec!["OPTION grab=0"], Some(false)),
+            (vec!["OPTION grab=1"], Some(true)),
+            (vec!["OPTION no-grab", "OPTION grab=1"], Some(true)),
+        ];
+
+        for (requests, expected) in test_cases {
+            let mut listener = listener(&["true"], None);
+            for req in &requests {
+                listener.handle_req(crate::request::parse(req).unwrap(), &mut NoInquire);
+            }
+            assert_eq!(listener.state.flag("grab"), expected, "{requests:?}");
+            assert_eq!(listener.state.flag("no-grab"), None, "{requests:?}");
+        }
+
+        let mut listener = listener(&["true"], None);
+        for req in [
+            "OPTION no-",
+            "OPTION no-grab=1",
+            "OPTION ttyname=/dev/pts/1",
+        ] {
+            listener.handle_req(crate::request::parse(req).unwrap(), &mut NoInquire);
+        }
+        assert_eq!(listener.state.flag("no-"), Some(true));
+        assert_eq!(listener.state.option("no-grab"), Some("1"));
+        assert_eq!(listener.state.flag("grab"), None);
+        assert_eq!(listener.state.option("ttyname"), Some("/dev/pts/1"));
+    }
+
+    #[test]
+    fn comment_options() {
+        use super::{Action::Next, Response};
+
+        let test_cases = vec![
+            ("OPTION grab", false, vec![Response::ok()]),
+            (
+                "OPTION grab",
+                true,
+                vec![
+                    Response::Comment("Option grab enabled".to_string()),
+                    Response::ok(),
+                ],
+            ),
+            (
+                "OPTION no-grab",
+                true,
+                vec![
+                    Response::Comment("Option grab disabled".to_string()),
+                    Response::ok(),
+                ],
+            ),
+            (
+                "OPTION ttyname=/dev/pts/1",
+                true,
+                vec![
+                    Response::Comment("Option ttyname=/dev/pts/1".to_string()),
+                    Response::ok(),
+                ],
+            ),
+        ];
+
+        for (req, enabled, expected) in test_cases {
+            let mut listener = listener(&["true"], None).comment_options(enabled);
+            assert_eq!(
+                listener.handle_req(crate::request::parse(req).unwrap(), &mut NoInquire),
+                Next(expected),
+                "{req} {enabled}"
+            );
+        }
+    }
+
+    #[test]
+    fn options_keep_order() {
+        use super::OptionValue::*;
+
+        let mut listener = listener(&["true"], None);
+        for req in [
+            "OPTION ttyname=/dev/pts/1",
+            "OPTION grab",
+            "OPTION display=:0",
+            "OPTION no-grab",
+            "OPTION ttyname=/dev/pts/2",
+        ] {
+            listener.handle_req(crate::request::parse(req).unwrap(), &mut NoInquire);
+        }
+        assert_eq!(
+            listener
+                .state
+                .options
+                .clone()
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec![
+                ("ttyname".to_string(), Value("/dev/pts/2".to_string())),
+                ("grab".to_string(), Disabled),
+                ("display".to_string(), Value(":0".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn session_env() {
+        let config = Config {
+            display: Some(":0".to_string()),
+            ttyname: Some("/dev/pts/0".to_string()),
+            ..Default::default()
+        };
+        let test_cases = vec![
+            (
+                vec![],
+                vec![
+                    ("PINENTRY_INVISIBLE_CHAR", "•"),
+                    ("DISPLAY", ":0"),
+                    ("GPG_TTY", "/dev/pts/0"),
+                ],
+            ),
+            (
+                vec!["OPTION ttyname=/dev/pts/1", "OPTION ttytype=xterm"],
+                vec![
+                    ("PINENTRY_INVISIBLE_CHAR", "•"),
+                    ("DISPLAY", ":0"),
+                    ("GPG_TTY", "/dev/pts/1"),
+                    ("TERM", "xterm"),
+                ],
+            ),
+            (
+                vec!["OPTION display=wayland-1"],
+                vec![
+                    ("PINENTRY_INVISIBLE_CHAR", "•"),
+                    ("WAYLAND_DISPLAY", "wayland-1"),
+                    ("GPG_TTY", "/dev/pts/0"),
+                ],
+            ),
+            (
+                vec!["OPTION display=wayland-1", "RESET"],
+                vec![
+                    ("PINENTRY_INVISIBLE_CHAR", "•"),
+                    ("DISPLAY", ":0"),
+                    ("GPG_TTY", "/dev/pts/0"),
+                ],
+            ),
+            (
+                vec!["OPTION parent-wid=0x2a00003"],
+                vec![
+                    ("PINENTRY_INVISIBLE_CHAR", "•"),
+                    ("DISPLAY", ":0"),
+                    ("GPG_TTY", "/dev/pts/0"),
+                    ("PINENTRY_PARENT_WID", "0x2a00003"),
+                ],
+            ),
+        ];
+
+        for (requests, expected) in test_cases {
+            let mut listener = Listener::from(config.clone());
+            for req in &requests {
+                listener.handle_req(crate::request::parse(req).unwrap(), &mut NoInquire);
+            }
+            assert_eq!(
+                listener.state.env().collect::<Vec<_>>(),
+                expected,
+                "{requests:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn formatted_passphrase() {
+        use super::{Action::Next, Response};
+        use crate::{request::Request, secret::SecretString};
+
+        let test_cases = vec![
+            (vec![], "abcd efgh\tij", "abcd efgh\tij"),
+            (
+                vec!["OPTION formatted-passphrase"],
+                "abcd efgh\tij",
+                "abcdefgh\tij",
+            ),
+            (
+                vec![
+                    "OPTION formatted-passphrase",
+                    "OPTION no-formatted-passphrase",
+                ],
+                "abcd efgh",
+                "abcd efgh",
+            ),
+            (vec!["OPTION formatted-passphrase"], " ab  cd ", "abcd"),
+        ];
+
+        for (requests, output, expected) in test_cases {
+            let mut listener = listener(&["printf", output], None);
+            for req in &requests {
+                listener.handle_req(crate::request::parse(req).unwrap(), &mut NoInquire);
+            }
+            assert_eq!(
+                listener.handle_req(Request::GetPin, &mut NoInquire),
+                Next(vec![
+                    Response::SecretD(SecretString::from(expected)),
+                    Response::ok()
+                ]),
+                "{requests:?}"
+            );
+        }
+
+        let mut listener = listener(&["true"], None);
+        for req in [
+            "OPTION formatted-passphrase",
+            "OPTION formatted-passphrase-hint=Groups of five",
+        ] {
+            listener.handle_req(crate::request::parse(req).unwrap(), &mut NoInquire);
+        }
+        let env = listener.state.env().collect::<Vec<_>>();
+        assert!(env.contains(&("PINENTRY_FORMATTED_PASSPHRASE", "1")));
+        assert!(env.contains(&("PINENTRY_FORMATTED_PASSPHRASE_HINT", "Groups of five")));
+    }
+
+    #[test]
+    fn format_version() {
+        use super::format_version;
+
+        let test_cases = vec![
+            (None, None, "0.1.0"),
+            (Some("abc1234"), None, "0.1.0 (abc1234)"),
+            (Some("abc1234"), Some(false), "0.1.0 (abc1234)"),
+            (Some("abc1234"), Some(true), "0.1.0 (abc1234-dirty)"),
+        ];
+
+        for (hash, dirty, expected) in test_cases {
+            assert_eq!(format_version("0.1.0", hash, dirty), expected);
+        }
+    }
+
+    #[test]
+    fn state_timeout() {
+        let secs = |s| Some(Duration::from_secs(s));
+        let test_cases = vec![
+            (vec![], secs(300), secs(300)),
+            (vec![], None, None),
+            (vec![], secs(0), None),
+            (vec!["SETTIMEOUT 5"], secs(300), secs(5)),
+            (vec!["SETTIMEOUT 5"], None, secs(5)),
+            (vec!["SETTIMEOUT 0"], secs(300), None),
+            (vec!["SETTIMEOUT 5", "SETTIMEOUT 0"], secs(300), None),
+            (vec!["SETTIMEOUT 5", "RESET"], secs(300), secs(300)),
+        ];
+
+        for (requests, default, expected) in test_cases {
+            let mut listener = Listener::from(Config::default());
+            for req in &requests {
+                listener.handle_req(crate::request::parse(req).unwrap(), &mut NoInquire);
+            }
+            assert_eq!(
+                listener.state.timeout(default),
+                expected,
+                "{requests:?} {default:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn confirm_settimeout() {
+        use super::{Action::Next, Response};
+        use crate::assuan::{pinentry_error, TIMEOUT};
+        use crate::request::Request;
+
+        let timed_out = || Response::Err(pinentry_error(TIMEOUT), "Timeout".to_string());
+        let test_cases = vec![
+            (vec![], timed_out()),
+            (vec!["SETTIMEOUT 5"], Response::Ok(None)),
+            (vec!["SETTIMEOUT 0"], Response::Ok(None)),
+            (vec!["SETTIMEOUT 5", "RESET"], timed_out()),
+        ];
+
+        for (requests, expected) in test_cases {
+            let mut listener = listener(&["false"], Some(Duration::from_millis(100)));
+            listener.config.confirm_command = vec!["sleep".to_string(), "0.3".to_string()].into();
+            for req in &requests {
+                listener.handle_req(crate::request::parse(req).unwrap(), &mut NoInquire);
+            }
+            assert_eq!(
+                listener.handle_req(Request::Confirm, &mut NoInquire),
+                Next(vec![expected]),
+                "{requests:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn state_accessors() {
+        use super::OptionValue;
+
+        let mut listener = listener(&["true"], None);
+        for req in [
+            "OPTION ttyname=/dev/pts/1",
+            "SETDESC Enter the PIN%0Afor the key",
+            "SETPROMPT PIN:",
+            "SETTITLE Unlock",
+            "SETERROR Bad PIN",
+            "SETKEYINFO n/ABCD",
+            "OPTION no-grab",
+            "OPTION allow-external-password-cache",
+        ] {
+            listener.handle_req(crate::request::parse(req).unwrap(), &mut NoInquire);
+        }
+
+        let state = listener.state();
+        assert_eq!(state.desc(), Some("Enter the PIN\nfor the key"));
+        assert_eq!(state.prompt(), Some("PIN:"));
+        assert_eq!(state.title(), Some("Unlock"));
+        assert_eq!(state.error(), Some("Bad PIN"));
+        assert_eq!(
+            state.keyinfo().map(crate::request::Keyinfo::raw),
+            Some("n/ABCD")
+        );
+        assert_eq!(
+            state.options().collect::<Vec<_>>(),
+            vec![
+                ("ttyname", &OptionValue::Value("/dev/pts/1".to_string())),
+                ("grab", &OptionValue::Disabled),
+                ("allow-external-password-cache", &OptionValue::Enabled),
+            ]
+        );
+
+        listener.handle_req(crate::request::parse("RESET").unwrap(), &mut NoInquire);
+        let state = listener.state();
+        assert_eq!(
+            (state.desc(), state.prompt(), state.title(), state.error()),
+            (None, None, None, None)
+        );
+        assert!(state.keyinfo().is_none());
+        assert_eq!(state.options().count(), 0);
+    }
+
+    #[test]
+    fn external_cache_allowed() {
+        let test_cases = vec![
+            (vec![], false),
+            (vec!["OPTION allow-external-password-cache"], true),
+            (vec!["OPTION allow-external-password-cache=1"], true),
+            (vec!["OPTION allow-external-password-cache=0"], false),
+            (
+                vec![
+                    "OPTION allow-external-password-cache",
+                    "OPTION no-allow-external-password-cache",
+                ],
+                false,
+            ),
+            (vec!["OPTION allow-external-password-cache", "RESET"], false),
+        ];
+
+        for (requests, expected) in test_cases {
+            let mut listener = listener(&["true"], None);
+            for req in &requests {
+                listener.handle_req(crate::request::parse(req).unwrap(), &mut NoInquire);
+            }
+            assert_eq!(
+                listener.state.external_cache_allowed(),
+                expected,
+                "{requests:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn invisible_char() {
+        let test_cases = vec![
+            (vec![], "•"),
+            (vec!["OPTION invisible-char=*"], "*"),
+            (vec!["OPTION invisible-char=*", "RESET"], "•"),
+        ];
+
+        for (requests, expected) in test_cases {
+            let mut listener = listener(&["true"], None);
+            for req in &requests {
+                listener.handle_req(crate::request::parse(req).unwrap(), &mut NoInquire);
+            }
+            assert_eq!(
+                listener
+                    .state
+                    .env()
+                    .find(|(k, _)| *k == "PINENTRY_INVISIBLE_CHAR"),
+                Some(("PINENTRY_INVISIBLE_CHAR", expected)),
+                "{requests:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn confirm() {
+        use super::{Action::Next, Response};
+        use crate::assuan::{pinentry_error, GENERAL, NOT_CONFIRMED};
+        use crate::request::Request;
+
+        let test_cases = vec![
+            ("exit 0", Request::Confirm, Response::Ok(None)),
+            (
+                "exit 1",
+                Request::Confirm,
+                Response::Err(pinentry_error(NOT_CONFIRMED), "Not confirmed".to_string()),
+            ),
+            (
+                "echo oops >&2; exit 2",
+                Request::Confirm,
+                Response::Err(pinentry_error(GENERAL), "oops".to_string()),
+            ),
+            ("exit 0", Request::ConfirmOneButton, Response::Ok(None)),
+            ("exit 1", Request::ConfirmOneButton, Response::Ok(None)),
+            (
+                "test \"$PINENTRY_ONE_BUTTON\" = 1",
+                Request::ConfirmOneButton,
+                Response::Ok(None),
+            ),
+            (
+                "test \"$PINENTRY_DESC\" = 'Really?'",
+                Request::Confirm,
+                Response::Ok(None),
+            ),
+        ];
+
+        for (script, req, expected) in test_cases {
+            let mut listener = listener(&["false"], None);
+            listener.config.confirm_command = vec!["sh", "-c", script]
+                .into_iter()
+                .map(ToString::to_string)
+                .collect();
+            listener.handle_req(
+                crate::request::parse("SETDESC Really?").unwrap(),
+                &mut NoInquire,
+            );
+            assert_eq!(
+                listener.handle_req(req, &mut NoInquire),
+                Next(vec![expected]),
+                "{script}"
+            );
+        }
+    }
+
+    #[test]
+    fn message() {
+        use super::{Action::Next, Response};
+        use crate::assuan::{pinentry_error, GENERAL};
+        use crate::request::Request;
+
+        let test_cases = vec![
+            ("exit 0", Response::Ok(None)),
+            ("exit 1", Response::Ok(None)),
+            (
+                "echo oops >&2; exit 2",
+                Response::Err(pinentry_error(GENERAL), "oops".to_string()),
+            ),
+            (
+                "test \"$PINENTRY_DESC\" = 'Card reset' && test \"$PINENTRY_ONE_BUTTON\" = 1",
+                Response::Ok(None),
+            ),
+            ("test \"$PINENTRY_MESSAGE\" = 1", Response::Ok(None)),
+        ];
+
+        for (script, expected) in test_cases {
+            let mut listener = listener(&["false"], None);
+            listener.config.message_command = vec!["sh", "-c", script]
+                .into_iter()
+                .map(ToString::to_string)
+                .collect();
+            listener.handle_req(
+                crate::request::parse("SETDESC Card reset").unwrap(),
+                &mut NoInquire,
+            );
+            assert_eq!(
+                listener.handle_req(Request::Message, &mut NoInquire),
+                Next(vec![expected]),
+                "{script}"
+            );
+        }
+    }
+
+    #[test]
+    fn get_pin_once() {
+        use crate::GetPinError;
+
+        let test_cases = vec![
+            ("echo 1234", Ok("1234")),
+            ("echo 12", Err("too short")),
+            ("exit 1", Err("canceled")),
+        ];
+
+        for (script, expected) in test_cases {
+            let mut listener = listener(&["sh", "-c", script], None);
+            listener.config.min_pin_length = Some(4);
+            let pin = listener.get_pin_once();
+            match expected {
+                Ok(expected) => assert_eq!(pin.unwrap().expose(), expected, "{script}"),
+                Err("canceled") => assert!(matches!(pin, Err(GetPinError::Canceled)), "{script}"),
+                Err(_) => assert!(matches!(pin, Err(GetPinError::TooShort(4))), "{script}"),
+            }
+        }
+    }
+
+    #[test]
+    fn message_falls_back_to_command() {
+        let listener = listener(&["true"], None);
+        assert!(listener.message().is_ok());
+    }
+
+    #[test]
+    fn confirm_falls_back_to_command() {
+        let listener = listener(&["true"], None);
+        assert!(listener.confirm(false).unwrap());
+    }
+
+    #[test]
+    fn get_pin_errors() {
+        use super::{Action::Next, Response};
+        use crate::assuan::{pinentry_error, CANCELED, GENERAL, TIMEOUT};
+        use crate::request::Request;
+
+        let test_cases = vec![
+            (
+                "exit 1",
+                Response::Err(pinentry_error(CANCELED), "Operation cancelled".to_string()),
+            ),
+            (
+                "sleep 5",
+                Response::Err(pinentry_error(TIMEOUT), "Timeout".to_string()),
+            ),
+            (
+                "echo oops >&2; exit 3",
+                Response::Err(pinentry_error(GENERAL), "oops".to_string()),
+            ),
+            (
+                "printf 'oops\\ntwo\\n\\n' >&2; exit 3",
+                Response::Err(pinentry_error(GENERAL), "oops".to_string()),
+            ),
+            (
+                "exit 3",
+                Response::Err(
+                    pinentry_error(GENERAL),
+                    "Command failed with code 3".to_string(),
+                ),
+            ),
+        ];
+
+        for (script, expected) in test_cases {
+            let mut listener = listener(&["sh", "-c", script], Some(Duration::from_millis(100)));
+            assert_eq!(
+                listener.handle_req(Request::GetPin, &mut NoInquire),
+                Next(vec![expected]),
+                "{script}"
+            );
+        }
+    }
+
+    #[test]
+    fn get_pin_repeat() {
+        use super::{Action::Next, Response};
+        use crate::assuan::{pinentry_error, BAD_PASSPHRASE};
+        use crate::request::{parse, Request};
+
+        // Answer the repeat prompt differently if the script is told to
+        let script = r#"if [ -n "$PINENTRY_REPEAT" ]; then echo "$REPEAT_PIN"; else echo 1234; fi"#;
+        let test_cases = vec![
+            (
+                "1234",
+                vec![
+                    Response::S("PIN_REPEATED".to_string(), "1".to_string()),
+                    Response::SecretD("1234".into()),
+                    Response::Ok(None),
+                ],
+            ),
+            (
+                "12345",
+                vec![Response::Err(
+                    pinentry_error(BAD_PASSPHRASE),
+                    "does not match - try again".to_string(),
+                )],
+            ),
+            (
+                "123",
+                vec![Response::Err(
+                    pinentry_error(BAD_PASSPHRASE),
+                    "does not match - try again".to_string(),
+                )],
+            ),
+        ];
+
+        for (repeat_pin, expected) in test_cases {
+            let mut listener = listener(
+                &["sh", "-c", &format!("REPEAT_PIN={repeat_pin}; {script}")],
+                None,
+            );
+            listener.handle_req(parse("SETREPEAT Repeat:").unwrap(), &mut NoInquire);
+            listener.handle_req(
+                parse("SETREPEATERROR does not match - try again").unwrap(),
+                &mut NoInquire,
+            );
+            assert_eq!(
+                listener.handle_req(Request::GetPin, &mut NoInquire),
+                Next(expected),
+                "{repeat_pin}"
+            );
+        }
+    }
+
+    #[test]
+    fn get_pin_repeat_label() {
+        use super::{Action::Next, Response};
+        use crate::request::{parse, Request};
+
+        // Answer the repeat prompt with the labels it was shown with, which must be the PIN
+        let script = r#"if [ -n "$PINENTRY_REPEAT" ]
+            then echo "$PINENTRY_PROMPT|$PINENTRY_REPEAT"; else echo "$EXPECTED"; fi"#;
+        let test_cases = vec![
+            ("SETREPEAT", "Repeat:|Repeat:"),
+            ("SETREPEAT Again:", "Again:|Again:"),
+        ];
+
+        for (request, expected) in test_cases {
+            let mut listener = listener(
+                &["sh", "-c", &format!("EXPECTED='{expected}'; {script}")],
+                None,
+            );
+            listener.handle_req(parse(request).unwrap(), &mut NoInquire);
+            assert_eq!(
+                listener.handle_req(Request::GetPin, &mut NoInquire),
+                Next(vec![
+                    Response::S("PIN_REPEATED".to_string(), "1".to_string()),
+                    Response::SecretD(expected.into()),
+                    Response::Ok(None),
+                ]),
+                "{request}"
+            );
+        }
+    }
+
+    #[test]
+    fn get_pin_repeat_attempts() {
+        use super::{Action::Next, Response};
+        use crate::assuan::{pinentry_error, BAD_PASSPHRASE};
+        use crate::request::{parse, Request};
+
+        // Only repeat the PIN correctly once told that it didn't match
+        let script = r#"if [ -z "$PINENTRY_REPEAT" ] || [ "$PINENTRY_REPEAT_ERROR" = "$EXPECTED" ]
+            then echo 1234; else echo 4321; fi"#;
+        let test_cases = vec![
+            (3, Some("does not match"), true),
+            (2, None, true),
+            (1, Some("does not match"), false),
+            (0, None, false),
+        ];
+
+        for (repeat_attempts, repeaterror, matches) in test_cases {
+            let expected_error = repeaterror.unwrap_or("Repeated PIN does not match");
+            let mut listener = Listener::from(Config {
+                command: vec![
+                    "sh".to_string(),
+                    "-c".to_string(),
+                    format!("EXPECTED='{expected_error}'; {script}"),
+                ]
+                .into(),
+                repeat_attempts,
+                ..Default::default()
+            });
+            listener.handle_req(parse("SETREPEAT Repeat:").unwrap(), &mut NoInquire);
+            if let Some(e) = repeaterror {
+                let req = format!("SETREPEATERROR {e}");
+                listener.handle_req(parse(&req).unwrap(), &mut NoInquire);
+            }
+            let expected = if matches {
+                vec![
+                    Response::pin_repeated(),
+                    Response::SecretD("1234".into()),
+                    Response::ok(),
+                ]
+            } else {
+                vec![Response::err(
+                    pinentry_error(BAD_PASSPHRASE),
+                    expected_error,
+                )]
+            };
+            assert_eq!(
+                listener.handle_req(Request::GetPin, &mut NoInquire),
+                Next(expected),
+                "{repeat_attempts} {repeaterror:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn listen_continues_after_parse_error() {
+        let input = std::io::Cursor::new(indoc! {"
+            BOGUS
+            GETINFO pid
+        "});
+        let mut output = vec![];
+        listener(&["true"], None)
+            .listen(input, &mut output)
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!(
+                indoc! {"
+                    OK Greetings from Elephantine
+                    ERR 536870981 Unknown command
+                    D {}
+                    OK
+                "},
+                std::process::id(),
+            ),
+        );
+    }
+
+    #[test]
+    fn reset_wipes_state() {
+        let test_cases = vec![
+            vec!["SETDESC Enter the PIN", "SETPROMPT PIN:", "SETTITLE Unlock"],
+            vec!["SETKEYINFO n/0123456789ABCDEF", "SETREPEAT Again"],
+            vec!["OPTION ttyname=/dev/pts/1", "OPTION grab"],
+        ];
+
+        for requests in test_cases {
+            let config = Config {
+                ttyname: Some("/dev/tty".to_string()),
+                ..Default::default()
+            };
+            let mut listener = Listener::from(config.clone());
+            for req in &requests {
+                listener.handle_req(crate::request::parse(req).unwrap(), &mut NoInquire);
+            }
+            let mut state = listener.state.clone();
+            assert_ne!(state, super::State::new(&config), "{requests:?}");
+
+            listener.handle_req(crate::request::Request::Reset, &mut NoInquire);
+            assert_eq!(listener.state, super::State::new(&config), "{requests:?}");
+
+            // What the old state was wiped with as it was dropped
+            state.wipe();
+            assert_eq!(state, super::State::default(), "{requests:?}");
+        }
+    }
+
+    #[test]
+    fn listen_forgets_session_at_eof() {
+        let test_cases = vec![
+            "SETDESC Enter the PIN\n",
+            "OPTION ttyname=/dev/pts/1\nBYE\n",
+        ];
+
+        for input in test_cases {
+            let config = Config {
+                ttyname: Some("/dev/tty".to_string()),
+                ..Default::default()
+            };
+            let mut listener = Listener::from(config.clone());
+            let mut output = vec![];
+            listener.listen(input.as_bytes(), &mut output).unwrap();
+
+            assert!(
+                String::from_utf8(output)
+                    .unwrap()
+                    .starts_with("OK Greetings from Elephantine\nOK\n"),
+                "{input}"
+            );
+            assert_eq!(listener.state, super::State::new(&config), "{input}");
+            assert_eq!(
+                listener.state.option("ttyname"),
+                Some("/dev/tty"),
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    fn listen_idle_timeout() {
+        use crate::connection::IdleTimeout;
+        use std::{io::Write, os::unix::net::UnixStream};
+
+        let timeout = Some(Duration::from_millis(100));
+        let test_cases = vec![
+            (
+                "",
+                timeout,
+                true,
+                "OK Greetings from Elephantine\nERR 83886142 Timeout\n",
+            ),
+            (
+                "NOP\n",
+                timeout,
+                true,
+                "OK Greetings from Elephantine\nOK\nERR 83886142 Timeout\n",
+            ),
+            (
+                "NOP\n",
+                timeout,
+                false,
+                "OK Greetings from Elephantine\nOK\n",
+            ),
+            ("NOP\n", None, false, "OK Greetings from Elephantine\nOK\n"),
+        ];
+
+        for (input, timeout, withhold, expected) in test_cases {
+            let (mut client, server) = UnixStream::pair().unwrap();
+            client.write_all(input.as_bytes()).unwrap();
+            // Keep the connection open without sending anything else
+            let _client = withhold.then_some(client);
+            let mut output = vec![];
+            listener(&["true"], None)
+                .listen(
+                    std::io::BufReader::new(IdleTimeout::new(server, timeout)),
+                    &mut output,
+                )
+                .unwrap();
+            assert_eq!(
+                String::from_utf8(output).unwrap(),
+                expected,
+                "{input:?} {timeout:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn listen_rejects_long_lines() {
+        let test_cases = vec![
+            (format!("SETDESC {}\nNOP\n", "a".repeat(92)), "OK\nOK\n"),
+            (
+                format!("SETDESC {}\nNOP\n", "a".repeat(93)),
+                "ERR 536871175 Line too long\nOK\n",
+            ),
+            (
+                format!("SETDESC {}", "a".repeat(100_000)),
+                "ERR 536871175 Line too long\n",
+            ),
+        ];
+
+        for (input, expected) in test_cases {
+            let mut output = vec![];
+            Listener::from(Config {
+                max_line_length: 100,
+                no_greeting: true,
+                ..Default::default()
+            })
+            .listen(input.as_bytes(), &mut output)
+            .unwrap();
+            assert_eq!(String::from_utf8(output).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn listen_rejects_invalid_utf8() {
+        let test_cases: Vec<(&[u8], &str)> = vec![
+            (b"NOP\n\xff\nNOP\n", "OK\nERR 536871188 Invalid UTF-8\nOK\n"),
+            (
+                b"SETDESC caf\xc3\nGETINFO flavor\n",
+                "ERR 536871188 Invalid UTF-8\nD elephantine\nOK\n",
+            ),
+            (b"SETDESC caf\xc3\xa9\n", "OK\n"),
+            (b"\x80", "ERR 536871188 Invalid UTF-8\n"),
+        ];
+
+        for (input, expected) in test_cases {
+            let mut output = vec![];
+            Listener::from(Config {
+                no_greeting: true,
+                ..Default::default()
+            })
+            .listen(input, &mut output)
+            .unwrap();
+            assert_eq!(String::from_utf8(output).unwrap(), expected, "{input:?}");
+        }
+    }
+
+    #[test]
+    fn listen_strips_crlf() {
+        let input = std::io::Cursor::new("SETDESC desc\r\nGETINFO pid\r\nBYE\r\n");
+        let mut output = vec![];
+        listener(&["true"], None)
+            .listen(input, &mut output)
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!(
+                indoc! {"
+                    OK Greetings from Elephantine
+                    OK
+                    D {}
+                    OK
+                    OK closing connection
+                "},
+                std::process::id(),
+            ),
+        );
+    }
+
+    #[test]
+    fn listen_skips_blank_and_comment_lines() {
+        let test_cases = vec![
+            ("\nNOP\n\n", "OK\n"),
+            ("   \t\nNOP\r\n\r\nNOP\n", "OK\nOK\n"),
+            ("# hello\nNOP\n#\n# GETPIN\n", "OK\n"),
+            // Only a `#` at the start of the line is a comment
+            (" # hello\nNOP\n", "ERR 536870981 Unknown command\nOK\n"),
+            ("NOP\n# bye\nBYE\nNOP\n", "OK\nOK closing connection\n"),
+        ];
+
+        for (input, expected) in test_cases {
+            let mut output = vec![];
+            listener(&["echo", "1234"], None)
+                .listen(input.as_bytes(), &mut output)
+                .unwrap();
+            assert_eq!(
+                String::from_utf8(output).unwrap(),
+                format!("OK Greetings from Elephantine\n{expected}"),
+                "{input:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn listen_max_attempts() {
+        const CANCELED: &str = "ERR 83886179 Operation cancelled\n";
+        const PIN: &str = "D 1234\nOK\n";
+
+        // Cancels every other time, starting with the first
+        let flag =
+            std::env::temp_dir().join(format!("elephantine-attempts-{}", std::process::id()));
+        let alternating = format!(
+            "if [ -e {0} ]; then rm {0}; echo 1234; else touch {0}; exit 1; fi",
+            flag.display()
+        );
+        let test_cases = vec![
+            (
+                "exit 1",
+                Some(2),
+                "GETPIN\nGETPIN\nGETPIN\n",
+                format!("{CANCELED}{CANCELED}ERR 83886179 Too many failed attempts (2)\n"),
+            ),
+            (
+                "exit 1",
+                None,
+                "GETPIN\nGETPIN\nGETPIN\n",
+                format!("{CANCELED}{CANCELED}{CANCELED}"),
+            ),
+            (
+                "exit 1",
+                Some(1),
+                "GETPIN\nGETPIN\nRESET\nGETPIN\n",
+                format!("{CANCELED}ERR 83886179 Too many failed attempts (1)\nOK\n{CANCELED}"),
+            ),
+            (
+                "echo 1234",
+                Some(2),
+                "GETPIN\nSETERROR Bad\nGETPIN\nGETPIN\n",
+                format!("{PIN}OK\n{PIN}{PIN}"),
+            ),
+            (
+                "echo 1234",
+                Some(2),
+                "GETPIN\nSETERROR Bad\nGETPIN\nGETPIN\nSETERROR Bad\nGETPIN\nGETPIN\n",
+                format!("{PIN}OK\n{PIN}{PIN}OK\n{PIN}{PIN}"),
+            ),
+            (
+                "echo 1234",
+                Some(2),
+                "GETPIN\nSETERROR Bad\nGETPIN\nSETERROR Bad\nGETPIN\n",
+                format!("{PIN}OK\n{PIN}OK\nERR 83886179 Too many failed attempts (2)\n"),
+            ),
+            (
+                &alternating,
+                Some(2),
+                "GETPIN\nGETPIN\nGETPIN\nGETPIN\n",
+                format!("{CANCELED}{PIN}{CANCELED}{PIN}"),
+            ),
+        ];
+
+        for (script, max_attempts, input, expected) in test_cases {
+            let config = Config {
+                command: vec!["sh".to_string(), "-c".to_string(), script.to_string()].into(),
+                max_attempts,
+                ..Default::default()
+            };
+            let provider = ConfiguredProvider::from(&config);
+            assert_eq!(
+                run_session(config, provider, input),
+                format!("OK Greetings from Elephantine\n{expected}"),
+                "{script} {input:?}"
+            );
+        }
+        let _ = std::fs::remove_file(flag);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn listen_after_malformed_output() {
+        let test_cases = vec![
+            (
+                r#"echo '{"pin":'"#,
+                "ERR 83886081 Malformed output: Invalid JSON at line 2 column 0\nOK\n",
+            ),
+            ("echo '{}'", "ERR 83886081 Malformed output: No pin\nOK\n"),
+            (r#"echo '{"pin":"1234"}'"#, "D 1234\nOK\nOK\n"),
+        ];
+
+        for (script, expected) in test_cases {
+            let config = Config {
+                command: vec!["sh".to_string(), "-c".to_string(), script.to_string()].into(),
+                backend_format: crate::config::BackendFormat::Json,
+                ..Default::default()
+            };
+            let provider = ConfiguredProvider::from(&config);
+            assert_eq!(
+                run_session(config, provider, "GETPIN\nNOP\n"),
+                format!("OK Greetings from Elephantine\n{expected}"),
+                "{script}"
+            );
+        }
+    }
+
+    #[test]
+    fn listen_after_missing_env() {
+        let var = format!("ELEPHANTINE_TEST_MISSING_{}", std::process::id());
+        let config = Config {
+            backend: crate::config::Backend::Env,
+            pin_env_var: var.clone(),
+            ..Default::default()
+        };
+        let provider = ConfiguredProvider::from(&config);
+        assert_eq!(
+            run_session(config, provider, "GETPIN\nNOP\n"),
+            format!(
+                "OK Greetings from Elephantine\n\
+                 ERR 83886081 The environment variable {var} is not set\nOK\n"
+            ),
+        );
+    }
+
+    #[test]
+    fn listen_after_auth() {
+        let test_cases = vec![
+            ("AUTH\nNOP\n", "ERR 536870981 Not implemented\nOK\n"),
+            (
+                "AUTH\nGETPIN\n",
+                "ERR 536870981 Not implemented\nD 1234\nOK\n",
+            ),
+            (
+                "AUTH\nBYE\nNOP\n",
+                "ERR 536870981 Not implemented\nOK closing connection\n",
+            ),
+        ];
+
+        for (input, expected) in test_cases {
+            let config = Config {
+                command: vec!["echo".to_string(), "1234".to_string()].into(),
+                ..Default::default()
+            };
+            let provider = ConfiguredProvider::from(&config);
+            assert_eq!(
+                run_session(config, provider, input),
+                format!("OK Greetings from Elephantine\n{expected}"),
+                "{input:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn listen_disabled_commands() {
+        let test_cases = vec![
+            (
+                vec!["GENPIN"],
+                "SETGENPIN Generate\nSETGENPIN_TT Tip\nGETPIN\n",
+                "ERR 536870981 Not implemented\nERR 536870981 Not implemented\nD 1234\nOK\n",
+            ),
+            (
+                vec!["genpin"],
+                "setgenpin Generate\nGETPIN\n",
+                "ERR 536870981 Not implemented\nD 1234\nOK\n",
+            ),
+            (
+                vec![],
+                "SETGENPIN Generate\nGETPIN\nD 42\nEND\n",
+                "OK\nINQUIRE GENPIN\nD 42\nOK\n",
+            ),
+            (
+                vec!["SETGENPIN", "CONFIRM"],
+                "setgenpin Generate\nCONFIRM\nGETPIN\n",
+                "ERR 536870981 Not implemented\nERR 536870981 Not implemented\nD 1234\nOK\n",
+            ),
+            (
+                vec!["GETPIN"],
+                "GETPIN\nGETINFO flavor\n",
+                "ERR 536870981 Not implemented\nD elephantine\nOK\n",
+            ),
+            (vec![], "GENPIN\n", "ERR 536870981 Unknown command\n"),
+            (vec!["GET"], "GETPIN\n", "D 1234\nOK\n"),
+        ];
+
+        for (disabled, input, expected) in test_cases {
+            let config = Config {
+                // Asks for the PIN to be generated whenever the generate button is offered
+                command: vec![
+                    "sh".to_string(),
+                    "-c".to_string(),
+                    r#"[ -n "$PINENTRY_GENPIN" ] && exit 10; printf 1234"#.to_string(),
+                ]
+                .into(),
+                disabled_commands: disabled.into_iter().map(ToString::to_string).collect(),
+                ..Default::default()
+            };
+            let provider = ConfiguredProvider::from(&config);
+            assert_eq!(
+                run_session(config, provider, input),
+                format!("OK Greetings from Elephantine\n{expected}"),
+                "{input:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn listen_after_cancel() {
+        let test_cases = vec![
+            ("CANCEL\nGETINFO flavor\n", "D elephantine\nOK\n"),
+            ("CANCEL\nGETPIN\n", "D 1234\nOK\n"),
+            ("CANCEL\nBYE\nNOP\n", "OK closing connection\n"),
+        ];
+
+        for (input, expected) in test_cases {
+            let config = Config {
+                command: vec!["echo".to_string(), "1234".to_string()].into(),
+                ..Default::default()
+            };
+            let provider = ConfiguredProvider::from(&config);
+            assert_eq!(
+                run_session(config, provider, input),
+                format!(
+                    "OK Greetings from Elephantine\nERR 83886179 Operation cancelled\n{expected}"
+                ),
+                "{input:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn handle_req() {
+        use super::{Action::*, Response};
+        use crate::{
+            assuan::{pinentry_error, protocol_error, CANCELED, NOT_IMPLEMENTED},
+            test_support::{handle_requests, FixedPin},
+        };
+
+        let ok = || Next(vec![Response::ok()]);
+        let pin = || Next(vec![Response::SecretD("1234".into()), Response::ok()]);
+        let closing = || Stop(vec![Response::ok_msg("closing connection")]);
+        let test_cases = vec![
+            ("SETDESC Enter your PIN", ok()),
+            ("SETPROMPT PIN:", ok()),
+            ("SETTITLE Unlock", ok()),
+            ("SETOK Unlock", ok()),
+            ("SETCANCEL Abort", ok()),
+            ("SETNOTOK Forget", ok()),
+            ("SETERROR Bad PIN", ok()),
+            ("SETKEYINFO n/ABCD", ok()),
+            ("SETTIMEOUT 5", ok()),
+            ("OPTION ttyname=/dev/pts/1", ok()),
+            (
+                "GETINFO flavor",
+                Next(vec![Response::D("elephantine".to_string()), Response::ok()]),
+            ),
+            ("GETPIN", pin()),
+            ("CLEARPASSPHRASE n/ABCD", ok()),
+            ("RESET", ok()),
+            ("HELP", ok()),
+            ("NOP", ok()),
+            (
+                "AUTH",
+                Next(vec![Response::err(
+                    protocol_error(NOT_IMPLEMENTED),
+                    "Not implemented",
+                )]),
+            ),
+            (
+                "CANCEL",
+                Next(vec![Response::err(
+                    pinentry_error(CANCELED),
+                    "Operation cancelled",
+                )]),
+            ),
+            ("BYE", closing()),
+            ("END", closing()),
+            ("QUIT", closing()),
+        ];
+
+        for (req, expected) in test_cases {
+            assert_eq!(
+                handle_requests(Config::default(), FixedPin("1234"), &[req]),
+                vec![expected],
+                "{req}"
+            );
+        }
+    }
+
+    #[test]
+    fn listen_inquires_quality() {
+        use crate::{provider::PinProvider, secret::SecretString, GetPinError, State};
+
+        /// Returns the quality of "1234" as the PIN.
+        struct QualityProvider;
+
+        impl PinProvider for QualityProvider {
+            fn get_pin(&self, _state: &State) -> Result<SecretString, GetPinError> {
+                Ok(SecretString::from("1234"))
+            }
+
+            fn get_pin_with_quality(
+                &self,
+                _state: &State,
+                quality: &mut dyn FnMut(&SecretString) -> Result<Option<i32>, GetPinError>,
+            ) -> Result<SecretString, GetPinError> {
+                let quality = quality(&SecretString::from("1234"))?;
+                Ok(SecretString::from(format!("{quality:?}")))
+            }
+        }
+
+        let test_cases = vec![
+            (false, "GETPIN\n", "D 1234\nOK\n"),
+            (
+                false,
+                "SETQUALITYBAR\nGETPIN\nD 42\nEND\n",
+                "OK\nINQUIRE QUALITY 1234\nD Some(42)\nOK\n",
+            ),
+            (
+                false,
+                "SETQUALITYBAR Quality:\nGETPIN\nD 1000\nEND\n",
+                "OK\nINQUIRE QUALITY 1234\nD Some(100)\nOK\n",
+            ),
+            (
+                false,
+                "SETQUALITYBAR\nGETPIN\nCAN\n",
+                "OK\nINQUIRE QUALITY 1234\nD None\nOK\n",
+            ),
+            (
+                false,
+                "SETQUALITYBAR\nGETPIN\nD strong\nEND\n",
+                "OK\nINQUIRE QUALITY 1234\nD None\nOK\n",
+            ),
+            // Rated without asking the client
+            (true, "GETPIN\n", "D 1234\nOK\n"),
+            (true, "SETQUALITYBAR\nGETPIN\n", "OK\nD Some(-73)\nOK\n"),
+        ];
+
+        for (local_quality, input, expected) in test_cases {
+            let config = Config {
+                local_quality,
+                ..Default::default()
+            };
+            assert_eq!(
+                run_session(config, QualityProvider, input),
+                format!("OK Greetings from Elephantine\n{expected}"),
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    fn listen_inquires_genpin() {
+        use crate::{provider::PinProvider, secret::SecretString, GetPinError, State};
+
+        /// Always asks for a PIN to be generated.
+        struct GenerateProvider;
+
+        impl PinProvider for GenerateProvider {
+            fn get_pin(&self, _state: &State) -> Result<SecretString, GetPinError> {
+                Err(GetPinError::Generate)
+            }
+        }
+
+        let test_cases = vec![
+            (
+                "SETGENPIN Generate\nGETPIN\nD gen%25pin\nEND\n",
+                "OK\nINQUIRE GENPIN\nD gen%25pin\nOK\n",
+            ),
+            (
+                "SETGENPIN Generate\nSETREPEAT Repeat:\nGETPIN\nD 1234\nEND\n",
+                "OK\nOK\nINQUIRE GENPIN\nS PIN_REPEATED 1\nD 1234\nOK\n",
+            ),
+            (
+                "SETGENPIN Generate\nGETPIN\nCAN\n",
+                "OK\nINQUIRE GENPIN\nERR 83886179 Operation cancelled\n",
+            ),
+        ];
+
+        for (input, expected) in test_cases {
+            assert_eq!(
+                run_session(Config::default(), GenerateProvider, input),
+                format!("OK Greetings from Elephantine\n{expected}"),
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    fn listen_inquires_genpin_on_repeat() {
+        let config = Config {
+            command: vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                r#"[ -n "$PINENTRY_REPEAT" ] && exit 10; echo 1234"#.to_string(),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        let provider = ConfiguredProvider::from(&config);
+        assert_eq!(
+            run_session(
+                config,
+                provider,
+                "SETGENPIN Generate\nSETREPEAT Repeat:\nGETPIN\nD 42\nEND\nNOP\n"
+            ),
+            "OK Greetings from Elephantine\nOK\nOK\nINQUIRE GENPIN\nS PIN_REPEATED 1\nD 42\nOK\nOK\n",
+        );
+    }
+
+    #[test]
+    fn get_pin_touches_file() {
+        use crate::request::{parse, Request};
+        use filetime::FileTime;
+
+        let path = std::env::temp_dir().join(format!("elephantine-touch-{}", std::process::id()));
+        std::fs::write(&path, "").unwrap();
+        let old = FileTime::from_unix_time(0, 0);
+        filetime::set_file_times(&path, old, old).unwrap();
+
+        let mut listener = listener(&["echo", "1234"], None);
+        let option = format!("OPTION touch-file={}", path.display());
+        listener.handle_req(parse(&option).unwrap(), &mut NoInquire);
+        listener.handle_req(Request::GetPin, &mut NoInquire);
+
+        let metadata = std::fs::metadata(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(FileTime::from_last_modification_time(&metadata) > old);
+        assert!(FileTime::from_last_access_time(&metadata) > old);
+    }
+
+    #[test]
+    fn get_pin_ignores_touch_failure() {
+        use super::{Action::Next, Response};
+        use crate::request::{parse, Request};
+
+        let mut listener = listener(&["echo", "1234"], None);
+        listener.handle_req(
+            parse("OPTION touch-file=/nonexistent/elephantine/S.gpg-agent").unwrap(),
+            &mut NoInquire,
+        );
+        assert!(matches!(
+            listener.handle_req(Request::GetPin, &mut NoInquire),
+            Next(resps) if resps.last() == Some(&Response::Ok(None))
+        ));
+    }
+
+    #[test]
+    fn get_pin_from_cache() {
+        let allow = "OPTION allow-external-password-cache\n";
+        let key = "SETKEYINFO n/FPR\n";
+
+        let test_cases = vec![
+            (true, format!("{allow}{key}GETPIN\nGETPIN\n"), true),
+            (false, format!("{allow}{key}GETPIN\nGETPIN\n"), false),
+            (true, format!("{key}GETPIN\nGETPIN\n"), false),
+            (true, format!("{allow}GETPIN\nGETPIN\n"), false),
+            (
+                true,
+                format!("{allow}SETKEYINFO --clear\nGETPIN\nGETPIN\n"),
+                false,
+            ),
+            (
+                true,
+                format!("{allow}{key}GETPIN\nSETERROR Bad PIN\nGETPIN\n"),
+                false,
+            ),
+        ];
+
+        for (cache, input, from_cache) in test_cases {
+            let config = Config {
+                command: vec!["echo".to_string(), "1234".to_string()].into(),
+                cache,
+                ..Default::default()
+            };
+            let provider = ConfiguredProvider::from(&config);
+            let output = run_session(config, provider, &input);
+            assert!(output.ends_with("D 1234\nOK\n"), "{input}");
+            assert_eq!(
+                output.contains("S PASSWORD_FROM_CACHE\nD 1234\nOK\n"),
+                from_cache,
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    fn listen_writes_pin_last() {
+        let key = "OPTION allow-external-password-cache\nSETKEYINFO n/FPR\n";
+        let test_cases = vec![
+            ("GETPIN\n".to_string(), "D 1234\nOK\n"),
+            (
+                "SETREPEAT\nGETPIN\n".to_string(),
+                "OK\nS PIN_REPEATED 1\nD 1234\nOK\n",
+            ),
+            (
+                format!("{key}GETPIN\nGETPIN\n"),
+                "OK\nOK\nD 1234\nOK\nS PASSWORD_FROM_CACHE\nD 1234\nOK\n",
+            ),
+        ];
+
+        for (input, expected) in test_cases {
+            let config = Config {
+                command: vec!["echo".to_string(), "1234".to_string()].into(),
+                cache: true,
+                ..Default::default()
+            };
+            let provider = ConfiguredProvider::from(&config);
+            assert_eq!(
+                run_session(config, provider, &input),
+                format!("OK Greetings from Elephantine\n{expected}"),
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    fn clear_passphrase() {
+        let test_cases = vec![
+            ("CLEARPASSPHRASE n/FPR\n", false),
+            ("CLEARPASSPHRASE n/OTHER\n", true),
+            ("", true),
+        ];
+
+        for (clear, from_cache) in test_cases {
+            let config = Config {
+                command: vec!["echo".to_string(), "1234".to_string()].into(),
+                cache: true,
+                ..Default::default()
+            };
+            let input = format!(
+                "OPTION allow-external-password-cache\nSETKEYINFO n/FPR\nGETPIN\n{clear}GETPIN\n"
+            );
+            let provider = ConfiguredProvider::from(&config);
+            let output = run_session(config, provider, &input);
+            assert_eq!(
+                output.contains("S PASSWORD_FROM_CACHE"),
+                from_cache,
+                "{clear}"
+            );
+        }
+
+        let mut output = vec![];
+        listener(&["true"], None)
+            .listen("CLEARPASSPHRASE n/FPR\n".as_bytes(), &mut output)
+            .unwrap();
+        assert_eq!(output, b"OK Greetings from Elephantine\nOK\n");
+    }
+
+    #[test]
+    fn listen_greets() {
+        let test_cases = vec![
+            (Config::default(), "OK Greetings from Elephantine\nOK\n"),
+            (
+                Config {
+                    greeting: Some("Hi".to_string()),
+                    ..Default::default()
+                },
+                "OK Hi\nOK\n",
+            ),
+            (
+                Config {
+                    greeting: None,
+                    ..Default::default()
+                },
+                "OK\nOK\n",
+            ),
+            (
+                Config {
+                    greeting: Some(String::new()),
+                    ..Default::default()
+                },
+                "OK\nOK\n",
+            ),
+            (
+                Config {
+                    no_greeting: true,
+                    ..Default::default()
+                },
+                "OK\n",
+            ),
+        ];
+
+        for (config, expected) in test_cases {
+            let provider = ConfiguredProvider::from(&config);
+            assert_eq!(
+                run_session(config.clone(), provider, "NOP\n"),
+                expected,
+                "{config:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn listen_io_errors() {
+        use super::Error;
+        use std::io::{self, ErrorKind, Write};
+
+        /// A writer that fails with `kind` after `remaining` bytes were written.
+        struct FailingWriter {
+            remaining: usize,
+            kind: ErrorKind,
+        }
+
+        impl Write for FailingWriter {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if self.remaining == 0 {
+                    return Err(self.kind.into());
+                }
+                let n = buf.len().min(self.remaining);
+                self.remaining -= n;
+                Ok(n)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let test_cases = vec![
+            (0, ErrorKind::BrokenPipe, None),
+            (10, ErrorKind::BrokenPipe, None),
+            (40, ErrorKind::BrokenPipe, None),
+            (
+                0,
+                ErrorKind::ConnectionReset,
+                Some(ErrorKind::ConnectionReset),
+            ),
+            (
+                40,
+                ErrorKind::PermissionDenied,
+                Some(ErrorKind::PermissionDenied),
+            ),
+        ];
+
+        for (remaining, kind, expected) in test_cases {
+            let mut output = FailingWriter { remaining, kind };
+            let result = Listener::from(Config::default())
+                .listen("NOP\nGETINFO pid\nNOP\n".as_bytes(), &mut output);
+            match expected {
+                None => assert!(result.is_ok(), "{remaining} {result:?}"),
+                Some(expected) => assert!(
+                    matches!(&result, Err(Error::Io(e)) if e.kind() == expected),
+                    "{remaining} {result:?}"
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn get_pin_falls_back() {
+        use super::Response;
+        use crate::request::Request;
+
+        let pinentry = r#"echo OK; while read -r line; do
+            case "$line" in GETPIN) echo "D 1234" ;; esac; echo OK
+        done"#;
+        let test_cases = vec![
+            (vec!["/nonexistent/command"], vec![], None),
+            (
+                vec!["/nonexistent/command"],
+                vec!["sh", "-c", pinentry],
+                Some("1234"),
+            ),
+            (
+                vec!["echo", "5678"],
+                vec!["sh", "-c", pinentry],
+                Some("5678"),
+            ),
+        ];
+
+        for (command, fallback, expected) in test_cases {
+            let mut listener = Listener::from(Config {
+                command: command.iter().map(ToString::to_string).collect(),
+                fallback_command: fallback.iter().map(ToString::to_string).collect(),
+                ..Default::default()
+            });
+            let resps = listener
+                .handle_req(Request::GetPin, &mut NoInquire)
+                .into_inner();
+            let pin = resps.iter().find_map(|r| match r {
+                Response::SecretD(pin) => Some(pin.expose()),
+                _ => None,
+            });
+            assert_eq!(pin, expected, "{command:?} {fallback:?}");
+        }
+    }
+
+    #[test]
+    fn get_pin_comments_fallback() {
+        use super::{Action::Next, Response};
+        use crate::request::Request;
+
+        let pinentry = r#"echo OK; while read -r line; do
+            case "$line" in GETPIN) echo "D 1234" ;; esac; echo OK
+        done"#;
+        let comment = || Response::Comment("PIN from the fallback pinentry".to_string());
+        let test_cases = vec![
+            (
+                "/nonexistent/command",
+                true,
+                vec![comment(), Response::SecretD("1234".into()), Response::ok()],
+            ),
+            (
+                "/nonexistent/command",
+                false,
+                vec![Response::SecretD("1234".into()), Response::ok()],
+            ),
+            (
+                "echo",
+                true,
+                vec![Response::SecretD(String::new().into()), Response::ok()],
+            ),
+        ];
+
+        for (command, enabled, expected) in test_cases {
+            let mut listener = Listener::from(Config {
+                command: vec![command.to_string()].into(),
+                fallback_command: vec!["sh".to_string(), "-c".to_string(), pinentry.to_string()]
+                    .into(),
+                ..Default::default()
+            })
+            .comment_fallback(enabled);
+            assert_eq!(
+                listener.handle_req(Request::GetPin, &mut NoInquire),
+                Next(expected),
+                "{command} {enabled}"
+            );
+        }
+    }
+
+    #[test]
+    fn redact() {
+        let test_cases = vec![
+            ("D hunter2", "D ***"),
+            ("D a b c", "D ***"),
+            ("D", "D"),
+            ("GETPIN", "GETPIN"),
+            ("SETDESC D hunter2", "SETDESC D hunter2"),
+            ("DESC hunter2", "DESC hunter2"),
+        ];
+
+        for (input, expected) in test_cases {
+            assert_eq!(super::redact(input), expected);
+        }
+    }
 
     #[test]
     fn test_listen() {
@@ -265,7 +3452,7 @@ mod test {
         let gid = users::get_current_gid();
         let pid = std::process::id();
 
-        let input = std::io::BufReader::new(std::io::Cursor::new(indoc! {"
+        let input = indoc! {"
             OPTION no-grab
             OPTION ttyname=not a tty
             OPTION ttytype=dumb
@@ -293,21 +3480,18 @@ mod test {
             SETREPEATOK Passphrase match.
             GETPIN
             BYE
-        "}));
+        "};
 
-        let mut output = std::io::Cursor::new(vec![]);
-        let mut listener = Listener::new(Config {
+        let config = Config {
             timeout: None,
             command: vec!["echo", "1234"]
                 .into_iter()
                 .map(std::string::ToString::to_string)
                 .collect(),
             ..Default::default()
-        });
-
-        listener.listen(input, &mut output).unwrap();
-
-        let output = String::from_utf8(output.into_inner()).unwrap();
+        };
+        let provider = ConfiguredProvider::from(&config);
+        let output = run_session(config, provider, input);
 
         assert_eq!(
             output,
@@ -330,9 +3514,9 @@ mod test {
                     OK
                     OK
                     OK
-                    D walker
+                    D elephantine
                     OK
-                    D 0.1.0
+                    D {}
                     OK
                     D - - - - {}/{} 0
                     OK
@@ -343,11 +3527,14 @@ mod test {
                     OK
                     OK
                     OK
-                    D 1234%0A
+                    D 1234
                     OK
                     OK closing connection
                 "},
-                uid, gid, pid,
+                super::version_string(),
+                uid,
+                gid,
+                pid,
             ),
         );
     }
@@ -1,41 +1,319 @@
-use clap_serde_derive::{clap::Parser, ClapSerde};
+use clap_serde_derive::{
+    clap::{self, CommandFactory, FromArgMatches, Parser, Subcommand},
+    ClapSerde,
+};
 use color_eyre::Result;
-use elephantine::config::Config;
-use elephantine::Listener;
+use elephantine::cache::Cache;
+use elephantine::config::{self, Config, SYSTEM_CONFIG_FILE};
+use elephantine::request::{Request, Set};
+use elephantine::{
+    connection::{self, IdleTimeout, NoInquire},
+    signal, GetPinError, Listener,
+};
 use std::{
-    io::{stdin, stdout, BufReader},
-    path::PathBuf,
+    fs::{self, File},
+    io::{self, stdin, stdout, BufReader, Write},
+    os::{fd::AsFd, unix::fs::FileTypeExt},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+    thread,
 };
 
 /// Implements the pinentry protocol and uses a configurable frontend for PIN input.
 #[derive(Parser)]
 #[command(version)]
 struct Args {
-    /// The debug level.
+    /// The debug level. From 2 on, each `OPTION` is answered with a comment showing how it was
+    /// recorded, and a `GETPIN` the fallback pinentry answered with a comment saying so.
     #[arg(short, long, env = "ELEPHANTINE_DEBUG", action = clap::ArgAction::Count)]
     debug: u8,
 
-    /// Path to the configuration file.
+    /// Path to the configuration file, in TOML, YAML (`.yaml` or `.yml`) or JSON (`.json`).
+    /// It is layered on `/etc/elephantine.toml`, and the options given as flags or in the
+    /// environment are layered on both, each overriding only the options it sets.
     #[arg(long, env = "ELEPHANTINE_CONFIG_FILE", value_name = "FILE", default_value = default_config_file())]
     config_file: PathBuf,
 
+    /// Listen for connections on a Unix domain socket instead of using stdin and stdout.
+    /// Send `SIGHUP` to reload the config for the connections that follow.
+    #[arg(long, env = "ELEPHANTINE_SOCKET", value_name = "PATH")]
+    socket: Option<PathBuf>,
+
+    /// Print a config file with the default values of all options and exit.
+    #[arg(long)]
+    generate_config: bool,
+
     /// The configuration options.
     #[command(flatten)]
     pub config: <Config as ClapSerde>::Opt,
+
+    #[command(subcommand)]
+    action: Option<Action>,
+}
+
+#[derive(Subcommand)]
+enum Action {
+    /// Ask for a PIN once with the configured backend and print it to stdout, instead of serving
+    /// the Assuan protocol. Exits with 1 if the user cancelled and 2 on any other error.
+    Get(GetArgs),
+    /// Serve `GetPin(prompt, desc)` on the session bus as `io.github.triarius.Elephantine`,
+    /// instead of the Assuan protocol, so that other applications can ask for PINs with the
+    /// configured backend.
+    #[cfg(feature = "dbus")]
+    Dbus,
+}
+
+#[derive(clap::Args)]
+struct GetArgs {
+    /// The prompt shown next to the input, like `SETPROMPT`.
+    #[arg(long)]
+    prompt: Option<String>,
+
+    /// The description shown above the input, like `SETDESC`.
+    #[arg(long)]
+    desc: Option<String>,
+
+    /// The title of the dialog, like `SETTITLE`.
+    #[arg(long)]
+    title: Option<String>,
+
+    /// Ask for the PIN a second time with this prompt, like `SETREPEAT`, and fail if the two
+    /// don't match.
+    #[arg(long, value_name = "PROMPT")]
+    repeat: Option<String>,
 }
 
 fn main() -> Result<()> {
     let args = Args::parse();
+    init_logger(args.debug);
 
-    let config = if args.config_file.exists() {
-        Config::try_from(&args.config_file)?
-    } else {
-        Config::from(args.config)
-    };
+    if args.generate_config {
+        print!("{}", Config::default().to_commented_toml()?);
+        return Ok(());
+    }
+
+    let config = load_config(&args.config_file)?;
 
-    let input = BufReader::new(stdin());
+    if config.harden {
+        harden();
+    }
+
+    match args.action {
+        Some(Action::Get(get)) => return get_pin(config, get),
+        #[cfg(feature = "dbus")]
+        Some(Action::Dbus) => return Ok(elephantine::dbus::serve(config)?),
+        None => {}
+    }
+
+    // Comments echoing the options the client set or saying that the fallback was used aren't
+    // part of the protocol, so only send them when debugging
+    let comments = args.debug >= 2;
+    if let Some(socket) = args.socket {
+        let reload = || load_config(&args.config_file);
+        return serve_socket(&socket, config, comments, reload);
+    }
+
+    // Only a single session is served, so it can be ended cleanly by a signal
+    if let Err(e) = signal::install() {
+        log::warn!("Failed to handle signals: {e}");
+    }
+    // Read stdin unbuffered, so that waiting for input sees everything the client has sent
+    let stdin = File::from(stdin().as_fd().try_clone_to_owned()?);
+    let input = BufReader::new(signal::Interruptible(IdleTimeout::new(
+        stdin,
+        config.idle_timeout,
+    )));
     let mut output = stdout();
-    Listener::new(config).listen(input, &mut output)
+    Listener::from(config)
+        .comment_options(comments)
+        .comment_fallback(comments)
+        .listen(input, &mut output)?;
+    Ok(())
+}
+
+/// Layer the system config file, `config_file` and the options given as flags or in the
+/// environment, and check that the result can be used.
+fn load_config(config_file: &Path) -> Result<Config> {
+    // Parse again without the default values, so that only the options given override the files
+    let flags =
+        Args::from_arg_matches(&config::without_defaults(Args::command()).try_get_matches()?)?;
+    let config = Config::layered(
+        &[PathBuf::from(SYSTEM_CONFIG_FILE), config_file.to_path_buf()],
+        flags.config,
+    )?;
+    config.validate()?;
+    Ok(config)
+}
+
+/// Accept connections on a Unix domain socket, serving each on its own thread with its own
+/// listener so that concurrent clients don't share state. Only the PIN cache is shared, so the
+/// socket is only accessible to its owner and connections from other users are rejected.
+///
+/// On `SIGHUP` the config is reloaded with `reload` for the connections accepted afterwards,
+/// while those being served keep the config they started with. If it fails, the old config is
+/// kept. The cached PINs are kept with the reloaded `cache_ttl`, or forgotten if the reloaded
+/// config disables the cache. With the `metrics` feature, `SIGUSR1` logs a summary of what the
+/// connections did.
+fn serve_socket(
+    path: &Path,
+    config: Config,
+    comments: bool,
+    reload: impl Fn() -> Result<Config>,
+) -> Result<()> {
+    // Replace a socket left behind by a previous run, but never any other kind of file
+    if fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        fs::remove_file(path)?;
+    }
+    let socket = connection::bind(path)?;
+    log::info!("Listening on {}", path.display());
+
+    if let Err(e) = signal::install_reload() {
+        log::warn!("Failed to handle SIGHUP, so the config can't be reloaded: {e}");
+    }
+
+    #[cfg(feature = "metrics")]
+    let metrics = Arc::new(elephantine::metrics::Metrics::default());
+    #[cfg(feature = "metrics")]
+    if let Err(e) = signal::install_report() {
+        log::warn!("Failed to handle SIGUSR1, so the metrics can't be reported: {e}");
+    }
+
+    let cache = Arc::new(Mutex::new(Cache::new(config.cache_ttl)));
+    let mut config = Arc::new(config);
+    for stream in socket.incoming() {
+        #[cfg(feature = "metrics")]
+        if signal::take_report() {
+            log::info!("Metrics: {metrics}");
+        }
+        if signal::take_reload() {
+            match reload() {
+                Ok(reloaded) => {
+                    log::info!("Reloaded the config");
+                    let mut cache = cache.lock().unwrap_or_else(PoisonError::into_inner);
+                    if !reloaded.cache {
+                        cache.clear();
+                    }
+                    cache.set_ttl(reloaded.cache_ttl);
+                    drop(cache);
+                    config = Arc::new(reloaded);
+                }
+                Err(e) => log::error!("Failed to reload the config, so keeping the old one: {e}"),
+            }
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            // Interrupted by `SIGHUP` or `SIGUSR1`, which were handled above
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                log::error!("Failed to accept connection: {e}");
+                continue;
+            }
+        };
+        match connection::same_user(&stream) {
+            Ok(true) => {}
+            Ok(false) => {
+                log::warn!("Rejected a connection from another user");
+                continue;
+            }
+            Err(e) => {
+                log::error!("Failed to get the credentials of the client: {e}");
+                continue;
+            }
+        }
+        let config = Arc::clone(&config);
+        let cache = Arc::clone(&cache);
+        #[cfg(feature = "metrics")]
+        let metrics = Arc::clone(&metrics);
+        thread::spawn(move || {
+            let result = stream.try_clone().map_err(Into::into).and_then(|input| {
+                let mut output = stream;
+                let input = BufReader::new(IdleTimeout::new(input, config.idle_timeout));
+                let listener = Listener::from(Config::clone(&config))
+                    .cache(cache)
+                    .comment_options(comments)
+                    .comment_fallback(comments);
+                #[cfg(feature = "metrics")]
+                let listener = listener.observer(metrics);
+                let mut listener = listener;
+                listener.listen(input, &mut output)
+            });
+            if let Err(e) = result {
+                log::error!("Connection failed: {e}");
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Ask for a PIN once and print it, exiting with 1 if the user cancelled and 2 on any other
+/// error.
+fn get_pin(config: Config, args: GetArgs) -> Result<()> {
+    let mut listener = Listener::from(config);
+    let settings = [
+        args.prompt.map(|p| Set::Prompt(p.into())),
+        args.desc.map(|d| Set::Desc(d.into())),
+        args.title.map(|t| Set::Title(t.into())),
+        args.repeat.map(|r| Set::Repeat(Some(r.into()))),
+    ];
+    for set in settings.into_iter().flatten() {
+        listener.handle_req(Request::Set(set), &mut NoInquire);
+    }
+
+    match listener.get_pin_once() {
+        Ok(pin) => {
+            let mut stdout = stdout().lock();
+            writeln!(stdout, "{}", pin.expose())?;
+            stdout.flush()?;
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("elephantine: {e}");
+            std::process::exit(if matches!(e, GetPinError::Canceled) {
+                1
+            } else {
+                2
+            });
+        }
+    }
+}
+
+/// Keep secrets out of core dumps and swap. Failures are logged, as locking memory can fail if
+/// the limit on locked memory is too low.
+#[cfg(unix)]
+fn harden() {
+    let no_core = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: `setrlimit` only reads the limit passed to it
+    if unsafe { libc::setrlimit(libc::RLIMIT_CORE, &raw const no_core) } != 0 {
+        log::warn!(
+            "Failed to disable core dumps: {}",
+            io::Error::last_os_error()
+        );
+    }
+    // SAFETY: `mlockall` doesn't access any memory of ours
+    if unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) } != 0 {
+        log::warn!("Failed to lock memory: {}", io::Error::last_os_error());
+    }
+}
+
+#[cfg(not(unix))]
+fn harden() {}
+
+/// Log to stderr, as stdout is used for the protocol. `RUST_LOG` overrides the debug level.
+fn init_logger(debug: u8) {
+    let level = match debug {
+        0 => log::LevelFilter::Warn,
+        1 => log::LevelFilter::Info,
+        2 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    };
+    env_logger::Builder::new()
+        .filter_level(level)
+        .parse_default_env()
+        .init();
 }
 
 fn default_config_file() -> String {
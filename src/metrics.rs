@@ -0,0 +1,188 @@
+//! Counting what the sessions of a daemon did, so operators can see how often it is used and how
+//! often it fails.
+
+use crate::{
+    assuan::{self, CANCELED, GENERAL, TIMEOUT},
+    observer::SessionObserver,
+    request::Request,
+    response::Response,
+};
+use std::{
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Counts the sessions, `GETPIN` requests and failed dialogs of one or more listeners. Its
+/// [`Display`](fmt::Display) is a one-line summary for logging.
+///
+/// ```
+/// use elephantine::{config::Config, metrics::Metrics, provider::PinProvider};
+/// use elephantine::{secret::SecretString, GetPinError, Listener, State};
+/// use std::sync::Arc;
+///
+/// #[derive(Debug)]
+/// struct Canceled;
+///
+/// impl PinProvider for Canceled {
+///     fn get_pin(&self, _state: &State) -> Result<SecretString, GetPinError> {
+///         Err(GetPinError::Canceled)
+///     }
+/// }
+///
+/// let metrics = Arc::new(Metrics::default());
+/// for _ in 0..2 {
+///     Listener::new(Config::default(), Canceled)
+///         .observer(Arc::clone(&metrics))
+///         .listen("GETPIN\n".as_bytes(), &mut vec![])
+///         .unwrap();
+/// }
+/// assert_eq!(
+///     metrics.to_string(),
+///     "sessions: 2, GETPIN requests: 2, cancelled: 2, timed out: 0, backend failures: 0"
+/// );
+/// ```
+#[derive(Debug, Default)]
+pub struct Metrics {
+    sessions: AtomicUsize,
+    getpins: AtomicUsize,
+    cancels: AtomicUsize,
+    timeouts: AtomicUsize,
+    failures: AtomicUsize,
+}
+
+impl SessionObserver for Metrics {
+    fn on_session(&self) {
+        self.sessions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_request(&self, req: &Request) {
+        if matches!(req, Request::GetPin) {
+            self.getpins.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn on_response(&self, resp: &Response) {
+        let Response::Err(code, _) = resp else {
+            return;
+        };
+        let counter = match code {
+            c if *c == assuan::pinentry_error(CANCELED) => &self.cancels,
+            c if *c == assuan::pinentry_error(TIMEOUT) => &self.timeouts,
+            c if *c == assuan::pinentry_error(GENERAL) => &self.failures,
+            _ => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sessions: {}, GETPIN requests: {}, cancelled: {}, timed out: {}, backend failures: {}",
+            self.sessions.load(Ordering::Relaxed),
+            self.getpins.load(Ordering::Relaxed),
+            self.cancels.load(Ordering::Relaxed),
+            self.timeouts.load(Ordering::Relaxed),
+            self.failures.load(Ordering::Relaxed),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Metrics;
+    use crate::{
+        config::Config, provider::PinProvider, secret::SecretString, CommandError, GetPinError,
+        Listener, State,
+    };
+    use std::{sync::Arc, time::Duration};
+
+    /// Fails with the error its function makes, or returns a PIN if it makes none.
+    #[derive(Debug)]
+    struct Failing(fn() -> Option<GetPinError>);
+
+    impl PinProvider for Failing {
+        fn get_pin(&self, _state: &State) -> Result<SecretString, GetPinError> {
+            match (self.0)() {
+                Some(e) => Err(e),
+                None => Ok(SecretString::from("1234")),
+            }
+        }
+    }
+
+    #[test]
+    fn metrics() {
+        let test_cases = vec![
+            (Failing(|| None), "NOP\n", [1, 0, 0, 0, 0]),
+            (Failing(|| None), "GETPIN\nGETPIN\n", [1, 2, 0, 0, 0]),
+            (
+                Failing(|| Some(GetPinError::Canceled)),
+                "GETPIN\n",
+                [1, 1, 1, 0, 0],
+            ),
+            (Failing(|| None), "CANCEL\n", [1, 0, 1, 0, 0]),
+            (
+                Failing(|| Some(GetPinError::Timeout(Duration::from_secs(1)))),
+                "GETPIN\n",
+                [1, 1, 0, 1, 0],
+            ),
+            (
+                Failing(|| {
+                    Some(GetPinError::Command(CommandError {
+                        code: 1,
+                        stderr: "No display".to_string(),
+                    }))
+                }),
+                "GETPIN\nGETPIN\n",
+                [1, 2, 0, 0, 2],
+            ),
+            (
+                Failing(|| Some(GetPinError::Malformed("No pin".to_string()))),
+                "GETPIN\nGETPIN\n",
+                [1, 2, 0, 0, 2],
+            ),
+            (
+                Failing(|| Some(GetPinError::MissingEnv("PIN".to_string()))),
+                "GETPIN\n",
+                [1, 1, 0, 0, 1],
+            ),
+            (
+                Failing(|| {
+                    Some(GetPinError::Setup(
+                        std::io::ErrorKind::NotFound.into(),
+                        vec!["missing".to_string()],
+                    ))
+                }),
+                "GETPIN\nGETPIN\n",
+                [1, 1, 0, 0, 1],
+            ),
+            (
+                Failing(|| {
+                    Some(GetPinError::Output(
+                        SecretString::try_from(vec![0xff]).unwrap_err(),
+                    ))
+                }),
+                "GETPIN\n",
+                [1, 1, 0, 0, 1],
+            ),
+            (Failing(|| None), "FOO\n", [1, 0, 0, 0, 0]),
+        ];
+
+        for (provider, input, [sessions, getpins, cancels, timeouts, failures]) in test_cases {
+            let metrics = Arc::new(Metrics::default());
+            Listener::new(Config::default(), provider)
+                .observer(Arc::clone(&metrics))
+                .listen(input.as_bytes(), &mut vec![])
+                .unwrap();
+            assert_eq!(
+                metrics.to_string(),
+                format!(
+                    "sessions: {sessions}, GETPIN requests: {getpins}, cancelled: {cancels}, \
+                     timed out: {timeouts}, backend failures: {failures}"
+                ),
+                "{input}"
+            );
+        }
+    }
+}
@@ -0,0 +1,180 @@
+//! Observing the requests and responses of a session, e.g. for auditing or metrics.
+
+use crate::{request::Request, response::Response};
+use std::{
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+/// What the payload of a `D` line holding a secret is replaced with before it is observed.
+pub const MASK: &str = "***";
+
+/// Called by a [`Listener`](crate::Listener) when a session starts, and with every request it
+/// reads and every response it writes. All methods do nothing by default.
+///
+/// Secrets are never observed: a `D` line holding a PIN is passed with its payload replaced by
+/// [`MASK`].
+pub trait SessionObserver: Debug + Send + Sync {
+    fn on_session(&self) {}
+
+    fn on_request(&self, _req: &Request) {}
+
+    fn on_response(&self, _resp: &Response) {}
+}
+
+/// An observer that observes nothing.
+#[derive(Debug, Default)]
+pub struct NoObserver;
+
+impl SessionObserver for NoObserver {}
+
+impl<T: SessionObserver + ?Sized> SessionObserver for Arc<T> {
+    fn on_session(&self) {
+        (**self).on_session();
+    }
+
+    fn on_request(&self, req: &Request) {
+        (**self).on_request(req);
+    }
+
+    fn on_response(&self, resp: &Response) {
+        (**self).on_response(resp);
+    }
+}
+
+/// Counts the `GETPIN` requests of one or more sessions.
+///
+/// ```
+/// use elephantine::{config::Config, observer::GetPinCounter, provider::PinProvider};
+/// use elephantine::{secret::SecretString, GetPinError, Listener, State};
+/// use std::sync::Arc;
+///
+/// #[derive(Debug)]
+/// struct Fixed;
+///
+/// impl PinProvider for Fixed {
+///     fn get_pin(&self, _state: &State) -> Result<SecretString, GetPinError> {
+///         Ok(SecretString::from("1234"))
+///     }
+/// }
+///
+/// let counter = Arc::new(GetPinCounter::default());
+/// let mut output = vec![];
+/// Listener::new(Config::default(), Fixed)
+///     .observer(Arc::clone(&counter))
+///     .listen("GETPIN\nNOP\nGETPIN\n".as_bytes(), &mut output)
+///     .unwrap();
+/// assert_eq!(counter.count(), 2);
+/// ```
+#[derive(Debug, Default)]
+pub struct GetPinCounter(AtomicUsize);
+
+impl GetPinCounter {
+    /// The number of `GETPIN` requests observed so far.
+    #[must_use]
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl SessionObserver for GetPinCounter {
+    fn on_request(&self, req: &Request) {
+        if matches!(req, Request::GetPin) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Pass `resp` to `observer`, masking any secret.
+pub(crate) fn observe_response(observer: &dyn SessionObserver, resp: &Response) {
+    match resp {
+        Response::SecretD(_) => observer.on_response(&Response::D(MASK.to_string())),
+        resp => observer.on_response(resp),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{GetPinCounter, SessionObserver};
+    use crate::{
+        config::Config, request::Request, response::Response, test_support::FixedPin, Listener,
+    };
+    use std::sync::{Arc, Mutex};
+
+    /// Records everything it observes as it would be sent.
+    #[derive(Debug, Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl SessionObserver for Recorder {
+        fn on_request(&self, req: &Request) {
+            self.0.lock().unwrap().push(format!("{req:?}"));
+        }
+
+        fn on_response(&self, resp: &Response) {
+            self.0.lock().unwrap().push(resp.to_string());
+        }
+    }
+
+    #[test]
+    fn observer() {
+        let test_cases = vec![
+            (
+                "NOP\n",
+                vec!["OK Greetings from Elephantine", "Nop", "OK"],
+                0,
+            ),
+            (
+                "GETPIN\nBYE\n",
+                vec![
+                    "OK Greetings from Elephantine",
+                    "GetPin",
+                    "D ***",
+                    "OK",
+                    "Bye",
+                    "OK closing connection",
+                ],
+                1,
+            ),
+            (
+                "FOO\nSETREPEAT Again\nGETPIN\n",
+                vec![
+                    "OK Greetings from Elephantine",
+                    "ERR 536870981 Unknown command",
+                    r#"Set(Repeat(Some("Again")))"#,
+                    "OK",
+                    "GetPin",
+                    "S PIN_REPEATED 1",
+                    "D ***",
+                    "OK",
+                ],
+                1,
+            ),
+        ];
+
+        for (input, expected, getpins) in test_cases {
+            let recorder = Arc::new(Recorder::default());
+            let counter = Arc::new(GetPinCounter::default());
+            let mut output = vec![];
+            Listener::new(Config::default(), FixedPin("1234"))
+                .observer(Arc::clone(&recorder))
+                .listen(input.as_bytes(), &mut output)
+                .unwrap();
+            Listener::new(Config::default(), FixedPin("1234"))
+                .observer(Arc::clone(&counter))
+                .listen(input.as_bytes(), &mut vec![])
+                .unwrap();
+
+            assert_eq!(*recorder.0.lock().unwrap(), expected, "{input}");
+            assert_eq!(counter.count(), getpins, "{input}");
+            // Only the observer's copy is masked
+            assert_eq!(
+                String::from_utf8(output).unwrap().contains("D 1234"),
+                getpins > 0,
+                "{input}"
+            );
+        }
+    }
+}
@@ -0,0 +1,280 @@
+//! Getting PINs from `pass`, the standard Unix password manager.
+
+use crate::{
+    config::Config,
+    keyring::{self, Keyring},
+    provider::{self, ConfiguredProvider, Origin, PinProvider, Rate},
+    secret::SecretString,
+    GetPinError, State,
+};
+use std::{
+    io, mem,
+    path::PathBuf,
+    process::{Command, Stdio},
+    time::Duration,
+};
+use zeroize::Zeroize;
+
+/// The command run to show an entry, followed by `show` and the entry.
+const PASS_COMMAND: &str = "pass";
+
+/// The variable `pass` reads the location of the password store from.
+const STORE_DIR_VAR: &str = "PASSWORD_STORE_DIR";
+
+/// A provider that reads the PIN from the first line of an entry in the password store, and asks
+/// `prompt` for it if there is no such entry.
+///
+/// The entry is `entry` with `{keyinfo}` replaced by the key set with `SETKEYINFO`, e.g.
+/// `n/FINGERPRINT`. The store is used like every keyring, as `keyring::get_pin` describes, except
+/// that PINs are never written to it.
+///
+/// `pass` is only run if the entry exists in the store, which is `PASSWORD_STORE_DIR` or else
+/// `~/.password-store`, as it decrypts the entry with gpg-agent, which may ask us for a PIN.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PassProvider<P> {
+    prompt: P,
+    pass: Pass,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Pass {
+    command: Vec<String>,
+    entry: String,
+    store_dir: Option<PathBuf>,
+    timeout: Option<Duration>,
+}
+
+impl<P: PinProvider> PassProvider<P> {
+    #[must_use]
+    pub fn new(prompt: P, entry: String) -> Self {
+        let store_dir = std::env::var_os(STORE_DIR_VAR)
+            .map(PathBuf::from)
+            .or_else(|| {
+                directories::BaseDirs::new().map(|dirs| dirs.home_dir().join(".password-store"))
+            });
+        Self {
+            prompt,
+            pass: Pass {
+                command: vec![PASS_COMMAND.to_string()],
+                entry,
+                store_dir,
+                timeout: None,
+            },
+        }
+    }
+
+    /// Run `command` instead of `pass`. It is passed the same arguments.
+    #[must_use]
+    pub fn command(mut self, command: Vec<String>) -> Self {
+        self.pass.command = command;
+        self
+    }
+
+    /// Look for entries in `dir` instead of the password store `pass` uses by default. `None`
+    /// runs `pass` without checking that the entry exists.
+    #[must_use]
+    pub fn store_dir(mut self, dir: Option<PathBuf>) -> Self {
+        self.pass.store_dir = dir;
+        self
+    }
+
+    /// Kill `pass` if it has not exited after `timeout`.
+    #[must_use]
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.pass.timeout = timeout;
+        self
+    }
+}
+
+impl From<&Config> for PassProvider<Box<ConfiguredProvider>> {
+    fn from(config: &Config) -> Self {
+        Self::new(Box::new(keyring::prompt(config)), config.pass_entry.clone())
+            .timeout(config.timeout)
+    }
+}
+
+impl<P: PinProvider> PinProvider for PassProvider<P> {
+    /// Get the PIN from the password store, or else from `prompt`
+    ///
+    /// # Errors
+    /// Any error of `prompt`
+    fn get_pin(&self, state: &State) -> Result<SecretString, GetPinError> {
+        self.get_pin_with_origin(state, None).map(|(pin, _)| pin)
+    }
+
+    fn get_pin_with_quality(
+        &self,
+        state: &State,
+        quality: &mut dyn FnMut(&SecretString) -> Result<Option<i32>, GetPinError>,
+    ) -> Result<SecretString, GetPinError> {
+        self.get_pin_with_origin(state, Some(quality))
+            .map(|(pin, _)| pin)
+    }
+
+    fn get_pin_with_origin(
+        &self,
+        state: &State,
+        quality: Option<&mut Rate<'_>>,
+    ) -> Result<(SecretString, Origin), GetPinError> {
+        keyring::get_pin(&self.pass, state, false, || {
+            provider::ask(&self.prompt, state, quality)
+        })
+    }
+}
+
+impl Keyring for Pass {
+    const NAME: &'static str = "password store";
+
+    type Error = GetPinError;
+
+    fn lookup(&self, key: &str) -> Result<Option<SecretString>, Self::Error> {
+        let entry = self.entry.replace("{keyinfo}", key);
+        if let Some(dir) = &self.store_dir {
+            if !dir.join(format!("{entry}.gpg")).is_file() {
+                log::debug!("{entry} is not in the password store");
+                return Ok(None);
+            }
+        }
+        let (program, args) = self.command.split_first().ok_or_else(|| {
+            GetPinError::Setup(
+                io::Error::new(io::ErrorKind::InvalidInput, "Empty command"),
+                self.command.clone(),
+            )
+        })?;
+        let mut child = Command::new(program);
+        child
+            .args(args)
+            .args(["show", &entry])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        // `SETTIMEOUT` is meant for the dialog, so only the configured timeout applies
+        let mut output =
+            provider::run_command(child, &self.command, &State::default(), self.timeout)?;
+        let mut stdout = mem::take(&mut output.stdout);
+
+        if !output.status.success() {
+            stdout.zeroize();
+            return Err(provider::command_error(&output));
+        }
+
+        // Only the first line is the PIN, the rest of the entry is wiped
+        let end = stdout
+            .iter()
+            .position(|&b| b == b'\n')
+            .unwrap_or(stdout.len());
+        stdout[end..].zeroize();
+        stdout.truncate(end);
+        SecretString::try_from(stdout)
+            .map(Some)
+            .map_err(GetPinError::Output)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PassProvider;
+    use crate::{
+        config::Config,
+        provider::PinProvider,
+        secret::SecretString,
+        test_support::{run_session, state},
+        GetPinError, State,
+    };
+    use std::{path::Path, time::Duration};
+
+    #[derive(Debug)]
+    struct Entered;
+
+    impl PinProvider for Entered {
+        fn get_pin(&self, _state: &State) -> Result<SecretString, GetPinError> {
+            Ok(SecretString::from("entered"))
+        }
+    }
+
+    /// A stub of `pass` that runs `script` with its arguments, on a store in `dir`.
+    fn provider(script: &str, dir: &Path) -> PassProvider<Entered> {
+        PassProvider::new(Entered, "gpg/{keyinfo}".to_string())
+            .command(vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                script.to_string(),
+                "pass".to_string(),
+            ])
+            .store_dir(Some(dir.to_path_buf()))
+            .timeout(Some(Duration::from_millis(200)))
+    }
+
+    #[test]
+    fn get_pin() {
+        const ALLOWED: &[&str] = &["OPTION allow-external-password-cache", "SETKEYINFO n/ABCD"];
+        const MISSING: &[&str] = &["OPTION allow-external-password-cache", "SETKEYINFO n/EF01"];
+
+        let dir = std::env::temp_dir().join(format!("elephantine-pass-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("gpg/n")).unwrap();
+        std::fs::write(dir.join("gpg/n/ABCD.gpg"), "").unwrap();
+
+        let test_cases = vec![
+            (ALLOWED, r#"echo "$*""#, "show gpg/n/ABCD"),
+            (ALLOWED, r"printf '1234\nuser: me\n'", "1234"),
+            (ALLOWED, "printf 1234", "1234"),
+            (MISSING, "echo 1234", "entered"),
+            (ALLOWED, "echo 1234; exit 2", "entered"),
+            (ALLOWED, "sleep 5; echo 1234", "entered"),
+            (&["SETKEYINFO n/ABCD"], "echo 1234", "entered"),
+            (
+                &[
+                    "OPTION allow-external-password-cache",
+                    "SETKEYINFO n/ABCD",
+                    "SETERROR Bad passphrase",
+                ],
+                "echo 1234",
+                "entered",
+            ),
+        ];
+
+        for (requests, script, expected) in test_cases {
+            let pin = provider(script, &dir).get_pin(&state(requests));
+            assert_eq!(pin.unwrap().expose(), expected, "{script}");
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn listen_marks_stored_pins() {
+        const ALLOW: &str = "OPTION allow-external-password-cache\n";
+
+        let dir =
+            std::env::temp_dir().join(format!("elephantine-pass-listen-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("gpg/n")).unwrap();
+        std::fs::write(dir.join("gpg/n/ABCD.gpg"), "").unwrap();
+
+        let test_cases = vec![
+            (
+                format!("{ALLOW}SETKEYINFO n/ABCD\nGETPIN\n"),
+                "OK\nOK\nS PASSWORD_FROM_CACHE\nD 1234\nOK\n",
+            ),
+            (
+                format!("{ALLOW}SETKEYINFO n/ABCD\nSETREPEAT\nGETPIN\n"),
+                "OK\nOK\nOK\nS PASSWORD_FROM_CACHE\nD 1234\nOK\n",
+            ),
+            (
+                format!("{ALLOW}SETKEYINFO n/EF01\nGETPIN\n"),
+                "OK\nOK\nD entered\nOK\n",
+            ),
+            (
+                format!("{ALLOW}SETKEYINFO n/EF01\nSETREPEAT\nGETPIN\n"),
+                "OK\nOK\nOK\nS PIN_REPEATED 1\nD entered\nOK\n",
+            ),
+        ];
+
+        for (input, expected) in test_cases {
+            assert_eq!(
+                run_session(Config::default(), provider("echo 1234", &dir), &input),
+                format!("OK Greetings from Elephantine\n{expected}"),
+                "{input:?}"
+            );
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,1063 @@
+//! Backends that show a dialog to the user to get a PIN.
+
+#[cfg(feature = "systemd")]
+use crate::ask_password::AskPasswordProvider;
+#[cfg(feature = "async")]
+use crate::asynchronous;
+#[cfg(all(feature = "keychain", target_os = "macos"))]
+use crate::keychain::KeychainProvider;
+use crate::pass::PassProvider;
+#[cfg(feature = "secret-service")]
+use crate::secret_service::SecretServiceProvider;
+#[cfg(unix)]
+use crate::tty::TtyProvider;
+use crate::{
+    config::{Backend, BackendFormat, Config, DEFAULT_CANCEL_EXIT_CODE},
+    secret::SecretString,
+    signal::{self, TrackedChild},
+    CommandError, GetPinError, State,
+};
+use std::{
+    ffi::OsString,
+    io::{self, Read},
+    mem,
+    process::{Child, Command, Output, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+use zeroize::{Zeroize, Zeroizing};
+
+/// The exit code a command uses to report that the user asked for a PIN to be generated.
+pub(crate) const EXIT_GENERATE: i32 = 10;
+
+/// The initial capacity of the buffers used to read the output of a command.
+const PIPE_BUFFER_CAPACITY: usize = 4096;
+
+/// The variables passed on from our environment to commands if it is cleared. Besides the
+/// display, commands need `PATH` to run others and `XDG_RUNTIME_DIR` to find the Wayland socket.
+const PASSTHROUGH_ENV: &[&str] = &[
+    "DISPLAY",
+    "WAYLAND_DISPLAY",
+    "XAUTHORITY",
+    "XDG_RUNTIME_DIR",
+    "PATH",
+];
+
+/// The command that selects the tty backend instead of being run.
+const TTY_COMMAND: &str = "@tty";
+
+/// A pipe being read to the end on a separate thread. What was read is wiped unless it is taken,
+/// even if the thread is never joined.
+type Reading = thread::JoinHandle<Zeroizing<Vec<u8>>>;
+
+/// How often to poll a running command to see if it has exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A backend that asks the user for a PIN.
+///
+/// A provider only ever asks for a single PIN. If the client sent `SETREPEAT`, the listener calls
+/// [`get_pin`](PinProvider::get_pin) a second time with a state that asks for the repetition,
+/// and compares the two itself, so a provider never reports a mismatch. If they don't match, the
+/// listener asks again with [`State::repeat_error`] set, which the provider should show.
+///
+/// A provider reports that the user dismissed the dialog with [`GetPinError::Canceled`], which
+/// ends the attempts. Any other error is returned to the client as it is.
+///
+/// A provider that keeps PINs in storage outside of gpg-agent, like a keyring, may only use it if
+/// [`State::external_cache_allowed`], and should say so with
+/// [`get_pin_with_origin`](PinProvider::get_pin_with_origin) when it returns a stored PIN.
+pub trait PinProvider {
+    /// Get a PIN from the user, using `state` to describe the dialog.
+    ///
+    /// # Errors
+    /// If the PIN could not be obtained, e.g. because the user cancelled the dialog.
+    fn get_pin(&self, state: &State) -> Result<SecretString, GetPinError>;
+
+    /// Get a PIN from the user while showing a quality bar.
+    ///
+    /// `quality` rates a passphrase from -100 to 100, or returns `None` if no rating is
+    /// available. Providers that can't show a quality bar don't need to implement this. The
+    /// built-in dialogs, like [`CommandProvider`], only see the PIN once it has been entered, so
+    /// they have nowhere to show a rating and ask without one.
+    ///
+    /// # Errors
+    /// If the PIN could not be obtained, or if `quality` fails.
+    fn get_pin_with_quality(
+        &self,
+        state: &State,
+        quality: &mut dyn FnMut(&SecretString) -> Result<Option<i32>, GetPinError>,
+    ) -> Result<SecretString, GetPinError> {
+        let _ = quality;
+        self.get_pin(state)
+    }
+
+    /// Get a PIN like [`get_pin`](Self::get_pin), or with a quality bar if `quality` is given,
+    /// and where it came from.
+    ///
+    /// The listener tells the client that a [`Origin::Stored`] PIN came from a cache with
+    /// `S PASSWORD_FROM_CACHE`, so that gpg-agent asks the user if it is wrong. Providers that
+    /// don't keep PINs don't need to implement this.
+    ///
+    /// # Errors
+    /// If the PIN could not be obtained, or if `quality` fails.
+    fn get_pin_with_origin(
+        &self,
+        state: &State,
+        quality: Option<&mut Rate<'_>>,
+    ) -> Result<(SecretString, Origin), GetPinError> {
+        ask(self, state, quality).map(|pin| (pin, Origin::User))
+    }
+}
+
+/// Rates a passphrase from -100 to 100, or returns `None` if no rating is available, for a
+/// quality bar.
+pub type Rate<'a> = dyn FnMut(&SecretString) -> Result<Option<i32>, GetPinError> + 'a;
+
+/// Where a provider got a PIN from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    /// The user entered it.
+    User,
+    /// It was taken from storage outside of gpg-agent, like a keyring, without asking the user.
+    Stored,
+}
+
+/// Ask the user for a PIN with `provider`, showing a quality bar if `quality` is given.
+///
+/// # Errors
+/// If the PIN could not be obtained, or if `quality` fails.
+pub(crate) fn ask<P: PinProvider + ?Sized>(
+    provider: &P,
+    state: &State,
+    quality: Option<&mut Rate<'_>>,
+) -> Result<SecretString, GetPinError> {
+    match quality {
+        Some(quality) => provider.get_pin_with_quality(state, quality),
+        None => provider.get_pin(state),
+    }
+}
+
+impl<T: PinProvider + ?Sized> PinProvider for Box<T> {
+    fn get_pin(&self, state: &State) -> Result<SecretString, GetPinError> {
+        (**self).get_pin(state)
+    }
+
+    fn get_pin_with_quality(
+        &self,
+        state: &State,
+        quality: &mut dyn FnMut(&SecretString) -> Result<Option<i32>, GetPinError>,
+    ) -> Result<SecretString, GetPinError> {
+        (**self).get_pin_with_quality(state, quality)
+    }
+
+    fn get_pin_with_origin(
+        &self,
+        state: &State,
+        quality: Option<&mut Rate<'_>>,
+    ) -> Result<(SecretString, Origin), GetPinError> {
+        (**self).get_pin_with_origin(state, quality)
+    }
+}
+
+/// The provider selected by the backend in the config.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ConfiguredProvider {
+    Command(CommandProvider),
+    #[cfg(unix)]
+    Tty(TtyProvider),
+    #[cfg(feature = "systemd")]
+    AskPassword(AskPasswordProvider),
+    Env(EnvProvider),
+    #[cfg(feature = "secret-service")]
+    SecretService(SecretServiceProvider<Box<ConfiguredProvider>>),
+    #[cfg(all(feature = "keychain", target_os = "macos"))]
+    Keychain(KeychainProvider<Box<ConfiguredProvider>>),
+    Pass(PassProvider<Box<ConfiguredProvider>>),
+}
+
+impl From<&Config> for ConfiguredProvider {
+    fn from(config: &Config) -> Self {
+        if config.backend == Backend::Tty || *config.command == [TTY_COMMAND] {
+            #[cfg(unix)]
+            return Self::Tty(TtyProvider::from(config));
+            #[cfg(not(unix))]
+            log::warn!("The tty backend is only available on Unix");
+        }
+        if config.backend == Backend::SecretService {
+            #[cfg(feature = "secret-service")]
+            return Self::SecretService(SecretServiceProvider::from(config));
+            #[cfg(not(feature = "secret-service"))]
+            log::warn!(
+                "Support for the secret-service backend was not enabled when elephantine was built"
+            );
+        }
+        if config.backend == Backend::Keychain {
+            #[cfg(all(feature = "keychain", target_os = "macos"))]
+            return Self::Keychain(KeychainProvider::from(config));
+            #[cfg(not(all(feature = "keychain", target_os = "macos")))]
+            log::warn!(
+                "Support for the keychain backend was not enabled when elephantine was built"
+            );
+        }
+        if config.backend == Backend::Pass {
+            return Self::Pass(PassProvider::from(config));
+        }
+        if config.backend == Backend::Env {
+            return Self::Env(EnvProvider::new(config.pin_env_var.clone()));
+        }
+        if config.backend == Backend::Systemd {
+            #[cfg(feature = "systemd")]
+            return Self::AskPassword(AskPasswordProvider::from(config));
+            #[cfg(not(feature = "systemd"))]
+            log::warn!(
+                "Support for the systemd backend was not enabled when elephantine was built"
+            );
+        }
+        Self::Command(CommandProvider::from(config))
+    }
+}
+
+impl PinProvider for ConfiguredProvider {
+    fn get_pin(&self, state: &State) -> Result<SecretString, GetPinError> {
+        match self {
+            Self::Command(provider) => provider.get_pin(state),
+            #[cfg(unix)]
+            Self::Tty(provider) => provider.get_pin(state),
+            #[cfg(feature = "systemd")]
+            Self::AskPassword(provider) => provider.get_pin(state),
+            Self::Env(provider) => provider.get_pin(state),
+            #[cfg(feature = "secret-service")]
+            Self::SecretService(provider) => provider.get_pin(state),
+            #[cfg(all(feature = "keychain", target_os = "macos"))]
+            Self::Keychain(provider) => provider.get_pin(state),
+            Self::Pass(provider) => provider.get_pin(state),
+        }
+    }
+
+    fn get_pin_with_quality(
+        &self,
+        state: &State,
+        quality: &mut dyn FnMut(&SecretString) -> Result<Option<i32>, GetPinError>,
+    ) -> Result<SecretString, GetPinError> {
+        match self {
+            Self::Command(provider) => provider.get_pin_with_quality(state, quality),
+            #[cfg(unix)]
+            Self::Tty(provider) => provider.get_pin_with_quality(state, quality),
+            #[cfg(feature = "systemd")]
+            Self::AskPassword(provider) => provider.get_pin_with_quality(state, quality),
+            Self::Env(provider) => provider.get_pin_with_quality(state, quality),
+            #[cfg(feature = "secret-service")]
+            Self::SecretService(provider) => provider.get_pin_with_quality(state, quality),
+            #[cfg(all(feature = "keychain", target_os = "macos"))]
+            Self::Keychain(provider) => provider.get_pin_with_quality(state, quality),
+            Self::Pass(provider) => provider.get_pin_with_quality(state, quality),
+        }
+    }
+
+    fn get_pin_with_origin(
+        &self,
+        state: &State,
+        quality: Option<&mut Rate<'_>>,
+    ) -> Result<(SecretString, Origin), GetPinError> {
+        match self {
+            Self::Command(provider) => provider.get_pin_with_origin(state, quality),
+            #[cfg(unix)]
+            Self::Tty(provider) => provider.get_pin_with_origin(state, quality),
+            #[cfg(feature = "systemd")]
+            Self::AskPassword(provider) => provider.get_pin_with_origin(state, quality),
+            Self::Env(provider) => provider.get_pin_with_origin(state, quality),
+            #[cfg(feature = "secret-service")]
+            Self::SecretService(provider) => provider.get_pin_with_origin(state, quality),
+            #[cfg(all(feature = "keychain", target_os = "macos"))]
+            Self::Keychain(provider) => provider.get_pin_with_origin(state, quality),
+            Self::Pass(provider) => provider.get_pin_with_origin(state, quality),
+        }
+    }
+}
+
+/// The command and env backends run without blocking the runtime, and the tty and systemd
+/// backends wait for the user on a blocking thread. The others show their dialog on the runtime's
+/// thread.
+#[cfg(feature = "async")]
+impl asynchronous::AsyncPinProvider for ConfiguredProvider {
+    async fn get_pin(&self, state: &State) -> Result<SecretString, GetPinError> {
+        match self {
+            Self::Command(provider) => {
+                asynchronous::AsyncPinProvider::get_pin(provider, state).await
+            }
+            Self::Env(provider) => asynchronous::AsyncPinProvider::get_pin(provider, state).await,
+            #[cfg(unix)]
+            Self::Tty(provider) => asynchronous::spawn_blocking(provider.clone(), state).await,
+            #[cfg(feature = "systemd")]
+            Self::AskPassword(provider) => {
+                asynchronous::spawn_blocking(provider.clone(), state).await
+            }
+            provider => PinProvider::get_pin(provider, state),
+        }
+    }
+}
+
+/// A provider that runs an external command and reads the PIN from its stdout.
+///
+/// The dialog is described to the command with `PINENTRY_*` environment variables and by
+/// substituting placeholders like `{prompt}` in its arguments. The command should exit with 0 and
+/// print the PIN to stdout, or exit with the cancel exit code, 1 by default, if the user cancelled
+/// the dialog. Any other exit code is an error. If the client set a
+/// label for the generate button with `SETGENPIN`, it is exported as `PINENTRY_GENPIN` and the
+/// command can exit with 10 to have the client generate the PIN instead.
+///
+/// With [`pin_fd`](Self::pin_fd), the PIN is read from a pipe the command inherits instead of
+/// from its stdout.
+///
+/// The command doesn't show a quality bar, even if the client sent `SETQUALITYBAR`, as it only
+/// hands back the PIN once the user is done and can't be given a rating while they type.
+#[derive(Debug, PartialEq, Eq)]
+pub struct CommandProvider {
+    command: Vec<String>,
+    timeout: Option<Duration>,
+    trim_trailing_newline: bool,
+    clean_env: bool,
+    cancel_exit_code: i32,
+    format: BackendFormat,
+    pin_fd: Option<i32>,
+}
+
+impl CommandProvider {
+    #[must_use]
+    pub fn new(command: Vec<String>) -> Self {
+        Self {
+            command,
+            timeout: None,
+            trim_trailing_newline: true,
+            clean_env: false,
+            cancel_exit_code: DEFAULT_CANCEL_EXIT_CODE,
+            format: BackendFormat::Raw,
+            pin_fd: None,
+        }
+    }
+
+    /// Kill the command if it has not exited after `timeout`.
+    #[must_use]
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Whether to run the command with only the `PINENTRY_*` variables and a few others needed to
+    /// show a dialog, rather than our whole environment.
+    #[must_use]
+    pub fn clean_env(mut self, clean: bool) -> Self {
+        self.clean_env = clean;
+        self
+    }
+
+    /// The exit code with which the command reports that the user cancelled the dialog.
+    #[must_use]
+    pub fn cancel_exit_code(mut self, code: i32) -> Self {
+        self.cancel_exit_code = code;
+        self
+    }
+
+    /// How the command prints the PIN.
+    #[must_use]
+    pub fn format(mut self, format: BackendFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Whether to strip a single trailing newline from the command's output.
+    #[must_use]
+    pub fn trim_trailing_newline(mut self, trim: bool) -> Self {
+        self.trim_trailing_newline = trim;
+        self
+    }
+
+    /// Read the PIN from the write end of a pipe that the command inherits as file descriptor
+    /// `fd`, rather than from its stdout, which is then ignored. The pipe is read until every copy
+    /// of it is closed, so the command must not leave a process running that holds it. Only
+    /// supported on Unix.
+    #[must_use]
+    pub fn pin_fd(mut self, fd: Option<i32>) -> Self {
+        self.pin_fd = fd;
+        self
+    }
+
+    /// Prepare the command, with a pipe for the PIN if `pin_fd` is set. The pipe is read on a
+    /// separate thread.
+    ///
+    /// # Errors
+    /// `GetPinError::Setup` if the command is empty or the pipe can't be created
+    fn command(&self, state: &State) -> Result<(Command, Option<Reading>), GetPinError> {
+        let mut child = command(&self.command, state, self.clean_env, &[])?;
+        let pin = self
+            .pin_fd
+            .map(|fd| pipe_to_fd(&mut child, fd))
+            .transpose()
+            .map_err(|e| GetPinError::Setup(e, self.command.clone()))?;
+        Ok((child, pin))
+    }
+
+    /// The PIN printed by the command, or written to `pin_fd`, or the error its exit code stands
+    /// for.
+    fn pin_from(
+        &self,
+        state: &State,
+        output: Result<Output, GetPinError>,
+        pin: Option<Reading>,
+    ) -> Result<SecretString, GetPinError> {
+        let Some(pin) = pin else {
+            return self.pin(state, output?);
+        };
+        let mut pin = pin.join().unwrap_or_default();
+        let mut output = output?;
+        output.stdout.zeroize();
+        output.stdout = mem::take(&mut *pin);
+        self.pin(state, output)
+    }
+
+    /// The PIN printed by the command, or the error its exit code stands for.
+    fn pin(&self, state: &State, mut output: Output) -> Result<SecretString, GetPinError> {
+        let pin = match output.status.code() {
+            Some(0) if self.format == BackendFormat::Json => json_pin(state, &output.stdout),
+            Some(0) => SecretString::try_from(mem::take(&mut output.stdout))
+                .map_err(GetPinError::Output)
+                .map(|mut pin| {
+                    if self.trim_trailing_newline {
+                        pin.trim_trailing_newline();
+                    }
+                    pin
+                }),
+            Some(code) if code == self.cancel_exit_code => Err(GetPinError::Canceled),
+            Some(EXIT_GENERATE) if state.genpin.is_some() => Err(GetPinError::Generate),
+            _ => Err(command_error(&output)),
+        };
+        output.stdout.zeroize();
+
+        pin
+    }
+}
+
+impl From<&Config> for CommandProvider {
+    fn from(config: &Config) -> Self {
+        Self::new(config.backend_command().into_owned())
+            .timeout(config.timeout)
+            .trim_trailing_newline(config.trim_trailing_newline)
+            .clean_env(config.clean_env)
+            .cancel_exit_code(config.cancel_exit_code)
+            .pin_fd(config.pin_fd)
+            .format(if cfg!(feature = "json") {
+                config.backend_format
+            } else {
+                if config.backend_format == BackendFormat::Json {
+                    log::warn!(
+                        "Support for JSON output was not enabled when elephantine was built"
+                    );
+                }
+                BackendFormat::Raw
+            })
+    }
+}
+
+impl PinProvider for CommandProvider {
+    /// Get the PIN using the an external process
+    ///
+    /// # Errors
+    /// `GetPinError::Setup` if there was a failure to setup the process
+    /// `GenPinError::Output` if there was an error reading the output of the process
+    /// `GenPinError::Command` if the command failed
+    /// `GenPinError::Timeout` if the command did not exit before the timeout
+    /// `GenPinError::Canceled` if the command exited with the cancel exit code
+    /// `GenPinError::Generate` if the command exited with `EXIT_GENERATE` and `SETGENPIN` was set
+    fn get_pin(&self, state: &State) -> Result<SecretString, GetPinError> {
+        let (child, pin) = self.command(state)?;
+        let output = run_command(child, &self.command, state, self.timeout);
+        self.pin_from(state, output, pin)
+    }
+}
+
+#[cfg(feature = "async")]
+impl asynchronous::AsyncPinProvider for CommandProvider {
+    /// Get the PIN using an external process, like [`PinProvider::get_pin`], without blocking
+    /// the runtime while it runs.
+    ///
+    /// # Errors
+    /// The same errors as [`PinProvider::get_pin`]
+    async fn get_pin(&self, state: &State) -> Result<SecretString, GetPinError> {
+        let (child, pin) = self.command(state)?;
+        let output = asynchronous::run_command(child, &self.command, state, self.timeout).await;
+        self.pin_from(state, output, pin)
+    }
+}
+
+/// The output of a command that prints JSON.
+#[cfg(feature = "json")]
+#[derive(serde::Deserialize)]
+struct JsonOutput {
+    #[serde(default)]
+    pin: Option<String>,
+    #[serde(default)]
+    cancelled: bool,
+    #[serde(default)]
+    generated: bool,
+}
+
+#[cfg(feature = "json")]
+impl Drop for JsonOutput {
+    fn drop(&mut self) {
+        self.pin.zeroize();
+    }
+}
+
+/// The PIN in the output of a command that prints JSON, or the error the output stands for.
+///
+/// # Errors
+/// `GetPinError::Malformed` if the output is not an object with a PIN
+/// `GetPinError::Canceled` if `cancelled` is true
+/// `GetPinError::Generate` if `generated` is true and `SETGENPIN` was set
+#[cfg(feature = "json")]
+fn json_pin(state: &State, stdout: &[u8]) -> Result<SecretString, GetPinError> {
+    use serde_json::error::Category;
+
+    let mut output: JsonOutput = serde_json::from_slice(stdout).map_err(|e| {
+        // Only the position is reported, as the message may quote the PIN
+        let problem = match e.classify() {
+            Category::Data => "Unexpected value",
+            Category::Io | Category::Syntax | Category::Eof => "Invalid JSON",
+        };
+        GetPinError::Malformed(format!(
+            "{problem} at line {} column {}",
+            e.line(),
+            e.column()
+        ))
+    })?;
+    if output.cancelled {
+        Err(GetPinError::Canceled)
+    } else if output.generated {
+        if state.genpin.is_some() {
+            Err(GetPinError::Generate)
+        } else {
+            Err(GetPinError::Malformed(
+                "Asked to generate a PIN without SETGENPIN".to_string(),
+            ))
+        }
+    } else {
+        output
+            .pin
+            .take()
+            .map(SecretString::from)
+            .ok_or_else(|| GetPinError::Malformed("No pin".to_string()))
+    }
+}
+
+#[cfg(not(feature = "json"))]
+fn json_pin(_state: &State, _stdout: &[u8]) -> Result<SecretString, GetPinError> {
+    Err(GetPinError::Malformed(
+        "Support for JSON output was not enabled when elephantine was built".to_string(),
+    ))
+}
+
+/// A provider that takes the PIN from an environment variable without asking the user, for
+/// automated tests of clients. The PIN is never logged.
+#[derive(Debug, PartialEq, Eq)]
+pub struct EnvProvider {
+    var: String,
+}
+
+impl EnvProvider {
+    #[must_use]
+    pub fn new(var: String) -> Self {
+        Self { var }
+    }
+}
+
+impl PinProvider for EnvProvider {
+    /// Get the PIN from the environment variable
+    ///
+    /// # Errors
+    /// `GetPinError::MissingEnv` if the variable is not set
+    /// `GenPinError::Output` if the PIN is not valid UTF-8
+    fn get_pin(&self, _state: &State) -> Result<SecretString, GetPinError> {
+        self.pin_from(std::env::var_os(&self.var))
+    }
+}
+
+impl EnvProvider {
+    /// The PIN in `value`, the value of the variable if it is set. The environment is read by the
+    /// caller, so that this can be tested without changing it while other tests run commands.
+    ///
+    /// # Errors
+    /// `GetPinError::MissingEnv` if the variable is not set
+    /// `GenPinError::Output` if the PIN is not valid UTF-8
+    fn pin_from(&self, value: Option<OsString>) -> Result<SecretString, GetPinError> {
+        let pin = value.ok_or_else(|| GetPinError::MissingEnv(self.var.clone()))?;
+        // Wiped if it is not valid UTF-8
+        SecretString::try_from(pin.into_encoded_bytes()).map_err(GetPinError::Output)
+    }
+}
+
+#[cfg(feature = "async")]
+impl asynchronous::AsyncPinProvider for EnvProvider {
+    async fn get_pin(&self, state: &State) -> Result<SecretString, GetPinError> {
+        PinProvider::get_pin(self, state)
+    }
+}
+
+/// Run a dialog command with the given state and wait for it to exit.
+///
+/// If `clean_env` is set, the command only gets the variables in `PASSTHROUGH_ENV` from our
+/// environment.
+///
+/// # Errors
+/// `GetPinError::Setup` if there was a failure to setup the process
+/// `GenPinError::Timeout` if the command did not exit before the timeout
+pub(crate) fn run(
+    command: &[String],
+    state: &State,
+    timeout: Option<Duration>,
+    clean_env: bool,
+    env: &[(&str, &str)],
+) -> Result<Output, GetPinError> {
+    let child = self::command(command, state, clean_env, env)?;
+    run_command(child, command, state, timeout)
+}
+
+/// Run a dialog command prepared by [`command`] and wait for it to exit.
+///
+/// # Errors
+/// `GetPinError::Setup` if there was a failure to setup the process
+/// `GenPinError::Timeout` if the command did not exit before the timeout
+pub(crate) fn run_command(
+    mut child: Command,
+    command: &[String],
+    state: &State,
+    timeout: Option<Duration>,
+) -> Result<Output, GetPinError> {
+    let setup_err = |e| GetPinError::Setup(e, command.to_vec());
+    let timeout = state.timeout(timeout);
+    let spawned = child.spawn();
+    // Closes our copy of any pipe the command inherited, so that it can be read to the end
+    drop(child);
+    let child = spawned.map_err(setup_err)?;
+
+    let _tracked = TrackedChild::new(&child);
+    let mut output = wait_with_timeout(child, timeout)
+        .map_err(setup_err)?
+        .ok_or_else(|| GetPinError::Timeout(timeout.unwrap_or_default()))?;
+    // The command was killed because we are terminating
+    if signal::terminating() {
+        output.stdout.zeroize();
+        return Err(GetPinError::Canceled);
+    }
+    Ok(output)
+}
+
+/// Prepare a dialog command to be run with the given state, with its output piped.
+///
+/// If `clean_env` is set, the command only gets the variables in `PASSTHROUGH_ENV` from our
+/// environment.
+///
+/// # Errors
+/// `GetPinError::Setup` if the command is empty
+pub(crate) fn command(
+    command: &[String],
+    state: &State,
+    clean_env: bool,
+    env: &[(&str, &str)],
+) -> Result<Command, GetPinError> {
+    let (program, args) = command.split_first().ok_or_else(|| {
+        GetPinError::Setup(
+            io::Error::new(io::ErrorKind::InvalidInput, "No backend command configured"),
+            command.to_vec(),
+        )
+    })?;
+    let mut child = Command::new(state.substitute(program));
+    if clean_env {
+        child.env_clear().envs(
+            PASSTHROUGH_ENV
+                .iter()
+                .filter_map(|k| std::env::var_os(k).map(|v| (k, v))),
+        );
+    }
+    child
+        .args(args.iter().map(|a| state.substitute(a)))
+        .envs(state.env())
+        .envs(env.iter().copied())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    Ok(child)
+}
+
+pub(crate) fn command_error(output: &Output) -> GetPinError {
+    GetPinError::Command(CommandError {
+        code: output.status.code().unwrap_or(1),
+        stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+    })
+}
+
+/// Wait for a child process to exit and collect its output, killing it if it is still running
+/// after `timeout`.
+///
+/// Returns `None` if the child was killed because the timeout expired.
+fn wait_with_timeout(
+    mut child: Child,
+    timeout: Option<Duration>,
+) -> std::io::Result<Option<Output>> {
+    // Drain the pipes on separate threads so a chatty child can't block on a full pipe while we
+    // are waiting for it to exit.
+    let stdout = drain(child.stdout.take());
+    let stderr = drain(child.stderr.take());
+
+    let deadline = timeout.map(|t| Instant::now() + t);
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        match deadline {
+            Some(deadline) if Instant::now() >= deadline => {
+                // The child may have exited between the last poll and the deadline.
+                if let Some(status) = child.try_wait()? {
+                    break status;
+                }
+                child.kill()?;
+                child.wait()?;
+                // Anything read so far is wiped once the pipes are closed, without waiting here
+                return Ok(None);
+            }
+            Some(deadline) => thread::sleep(POLL_INTERVAL.min(deadline - Instant::now())),
+            None => break child.wait()?,
+        }
+    };
+
+    Ok(Some(Output {
+        status,
+        stdout: mem::take(&mut *stdout.join().unwrap_or_default()),
+        stderr: mem::take(&mut *stderr.join().unwrap_or_default()),
+    }))
+}
+
+/// Have `child` inherit the write end of a pipe as file descriptor `fd`, and read the pipe on a
+/// separate thread until every copy of the write end is closed, including the one `child` holds
+/// until it is dropped.
+#[cfg(unix)]
+fn pipe_to_fd(child: &mut Command, fd: i32) -> io::Result<Reading> {
+    use std::os::{fd::AsRawFd, unix::process::CommandExt};
+
+    let (reader, writer) = io::pipe()?;
+    // SAFETY: only async-signal-safe functions are called between forking and executing
+    unsafe {
+        child.pre_exec(move || {
+            let result = if writer.as_raw_fd() == fd {
+                // Duplicating an fd onto itself would keep it closed on exec
+                libc::fcntl(fd, libc::F_SETFD, 0)
+            } else {
+                libc::dup2(writer.as_raw_fd(), fd)
+            };
+            if result < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        });
+    }
+    Ok(drain(Some(reader)))
+}
+
+#[cfg(not(unix))]
+fn pipe_to_fd(_child: &mut Command, _fd: i32) -> io::Result<Reading> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Reading the PIN from a file descriptor is only supported on Unix",
+    ))
+}
+
+/// Read a pipe to the end on a separate thread.
+///
+/// The buffer is preallocated so that reading a short secret does not leave copies behind in
+/// memory freed by reallocating it.
+fn drain(pipe: Option<impl Read + Send + 'static>) -> Reading {
+    thread::spawn(move || {
+        let mut buf = Zeroizing::new(Vec::with_capacity(PIPE_BUFFER_CAPACITY));
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        buf
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CommandProvider, PinProvider};
+    use crate::{test_support::state, GetPinError};
+    use std::time::Duration;
+
+    fn provider(command: &[&str], timeout: Option<Duration>) -> CommandProvider {
+        CommandProvider::new(command.iter().map(ToString::to_string).collect()).timeout(timeout)
+    }
+
+    #[test]
+    fn get_pin_timeout() {
+        let provider = provider(&["sleep", "5"], Some(Duration::from_millis(100)));
+        assert!(matches!(
+            provider.get_pin(&state(&[])),
+            Err(GetPinError::Timeout(_))
+        ));
+    }
+
+    #[test]
+    fn get_pin_settimeout_overrides_config() {
+        let provider = provider(&["sleep", "1"], Some(Duration::from_millis(100)));
+        assert_eq!(
+            provider
+                .get_pin(&state(&["SETTIMEOUT 5"]))
+                .unwrap()
+                .expose(),
+            ""
+        );
+    }
+
+    #[test]
+    fn get_pin_exports_state() {
+        let provider = provider(
+            &[
+                "sh",
+                "-c",
+                "printf '%s|%s|%s' \"$PINENTRY_DESC\" \"$PINENTRY_PROMPT\" \"${PINENTRY_TITLE-unset}\"",
+            ],
+            None,
+        );
+        let state = state(&["SETDESC line 1%0Aline 2", "SETPROMPT PIN:"]);
+        assert_eq!(
+            provider.get_pin(&state).unwrap().expose(),
+            "line 1\nline 2|PIN:|unset"
+        );
+    }
+
+    #[test]
+    fn get_pin_substitutes_placeholders() {
+        let provider = provider(&["printf", "%s", "{prompt}"], None);
+        let state = state(&["SETPROMPT Passphrase:"]);
+        assert_eq!(provider.get_pin(&state).unwrap().expose(), "Passphrase:");
+    }
+
+    #[test]
+    fn get_pin_generate() {
+        let provider = provider(&["sh", "-c", "exit 10"], None);
+        assert!(matches!(
+            provider.get_pin(&state(&["SETGENPIN Generate"])),
+            Err(GetPinError::Generate)
+        ));
+        assert!(matches!(
+            provider.get_pin(&state(&[])),
+            Err(GetPinError::Command(_))
+        ));
+    }
+
+    #[test]
+    fn get_pin_cancel_exit_code() {
+        let test_cases = vec![
+            ("echo 1234", 1, Ok("1234")),
+            ("exit 1", 1, Err("canceled")),
+            ("exit 2", 1, Err("command")),
+            ("exit 1", 130, Err("command")),
+            ("exit 130", 130, Err("canceled")),
+        ];
+
+        for (script, cancel_exit_code, expected) in test_cases {
+            let provider = provider(&["sh", "-c", script], None).cancel_exit_code(cancel_exit_code);
+            let pin = provider.get_pin(&state(&[]));
+            match expected {
+                Ok(expected) => assert_eq!(pin.unwrap().expose(), expected, "{script}"),
+                Err("canceled") => assert!(matches!(pin, Err(GetPinError::Canceled)), "{script}"),
+                Err(_) => assert!(matches!(pin, Err(GetPinError::Command(_))), "{script}"),
+            }
+        }
+    }
+
+    #[test]
+    fn get_pin_from_fd() {
+        let test_cases = vec![
+            ("echo 5678; printf 1234 >&5", Ok("1234")),
+            ("echo 5678", Ok("")),
+            ("printf 1234 >&5; exit 1", Err("canceled")),
+            ("printf 1234 >&5; exit 2", Err("command")),
+        ];
+
+        for (script, expected) in test_cases {
+            let provider = provider(&["sh", "-c", script], None).pin_fd(Some(5));
+            let pin = provider.get_pin(&state(&[]));
+            match expected {
+                Ok(expected) => assert_eq!(pin.unwrap().expose(), expected, "{script}"),
+                Err("canceled") => assert!(matches!(pin, Err(GetPinError::Canceled)), "{script}"),
+                Err(_) => assert!(matches!(pin, Err(GetPinError::Command(_))), "{script}"),
+            }
+        }
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn get_pin_json() {
+        use crate::config::BackendFormat;
+
+        let test_cases = vec![
+            (r#"{"pin":"1234","generated":false}"#, vec![], Ok("1234")),
+            (r#"{"pin":"12\"34\n"}"#, vec![], Ok("12\"34\n")),
+            (r#"{"cancelled":true}"#, vec![], Err("canceled")),
+            (
+                r#"{"generated":true}"#,
+                vec!["SETGENPIN Generate"],
+                Err("generate"),
+            ),
+            (r#"{"generated":true}"#, vec![], Err("malformed")),
+            ("{}", vec![], Err("malformed")),
+            ("1234", vec![], Err("malformed")),
+            (r#"{"pin":1234}"#, vec![], Err("malformed")),
+        ];
+
+        for (json, requests, expected) in test_cases {
+            let provider = provider(&["printf", "%s", json], None).format(BackendFormat::Json);
+            let pin = provider.get_pin(&state(&requests));
+            match expected {
+                Ok(expected) => assert_eq!(pin.unwrap().expose(), expected, "{json}"),
+                Err("canceled") => assert!(matches!(pin, Err(GetPinError::Canceled)), "{json}"),
+                Err("generate") => assert!(matches!(pin, Err(GetPinError::Generate)), "{json}"),
+                Err(_) => assert!(matches!(pin, Err(GetPinError::Malformed(_))), "{json}"),
+            }
+        }
+    }
+
+    #[test]
+    fn get_pin_empty_command() {
+        let provider = provider(&[], None);
+        assert!(matches!(
+            provider.get_pin(&state(&[])),
+            Err(GetPinError::Setup(ref e, _)) if e.to_string() == "No backend command configured"
+        ));
+    }
+
+    #[test]
+    fn get_pin_settimeout_disables_timeout() {
+        let provider = provider(
+            &["sh", "-c", "sleep 0.3; echo 1234"],
+            Some(Duration::from_millis(100)),
+        );
+        assert_eq!(
+            provider
+                .get_pin(&state(&["SETTIMEOUT 0"]))
+                .unwrap()
+                .expose(),
+            "1234"
+        );
+    }
+
+    #[test]
+    fn get_pin_zero_timeout() {
+        let provider = provider(&["sh", "-c", "sleep 0.1; echo 1234"], Some(Duration::ZERO));
+        assert_eq!(provider.get_pin(&state(&[])).unwrap().expose(), "1234");
+    }
+
+    #[test]
+    fn configured_provider() {
+        use super::ConfiguredProvider;
+        use crate::config::{Backend, Config};
+
+        let test_cases = vec![
+            (Backend::Command, "pass", false),
+            (Backend::Tty, "pass", true),
+            (Backend::Command, "@tty", true),
+            (Backend::Command, "@tty --flag", false),
+        ];
+
+        for (backend, command, tty) in test_cases {
+            let config = Config {
+                backend,
+                command: command.parse().unwrap(),
+                ..Default::default()
+            };
+            assert_eq!(
+                matches!(
+                    ConfiguredProvider::from(&config),
+                    ConfiguredProvider::Tty(_)
+                ),
+                tty,
+                "{backend:?} {command}"
+            );
+        }
+    }
+
+    #[test]
+    fn env_provider() {
+        use super::EnvProvider;
+
+        let var = format!("ELEPHANTINE_TEST_PIN_{}", std::process::id());
+        let provider = EnvProvider::new(var.clone());
+        assert!(matches!(
+            provider.get_pin(&state(&[])),
+            Err(GetPinError::MissingEnv(ref v)) if *v == var
+        ));
+        assert_eq!(
+            provider.pin_from(Some("12 34".into())).unwrap().expose(),
+            "12 34"
+        );
+        assert!(matches!(
+            provider.pin_from(None),
+            Err(GetPinError::MissingEnv(ref v)) if *v == var
+        ));
+    }
+
+    #[test]
+    fn get_pin_clean_env() {
+        let home = std::env::var("HOME").unwrap_or_else(|_| "unset".to_string());
+        let test_cases = vec![
+            (false, format!("{home}|PIN:")),
+            (true, "unset|PIN:".to_string()),
+        ];
+
+        for (clean, expected) in test_cases {
+            let provider = provider(
+                &[
+                    "sh",
+                    "-c",
+                    "printf '%s|%s' \"${HOME-unset}\" \"$PINENTRY_PROMPT\"",
+                ],
+                None,
+            )
+            .clean_env(clean);
+            assert_eq!(
+                provider
+                    .get_pin(&state(&["SETPROMPT PIN:"]))
+                    .unwrap()
+                    .expose(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn get_pin_within_timeout() {
+        let provider = provider(&["echo", "1234"], Some(Duration::from_secs(5)));
+        assert_eq!(provider.get_pin(&state(&[])).unwrap().expose(), "1234");
+    }
+
+    #[test]
+    fn get_pin_trims_trailing_newline() {
+        let test_cases = vec![
+            (vec!["echo", "1234"], true, "1234"),
+            (vec!["echo", "1234"], false, "1234\n"),
+            (vec!["printf", "1234"], true, "1234"),
+            (vec!["printf", "1234\n\n"], true, "1234\n"),
+            (vec!["printf", "1234\r\n"], true, "1234"),
+            (vec!["printf", "1234\r\n"], false, "1234\r\n"),
+            (vec!["printf", "12\r34"], true, "12\r34"),
+        ];
+
+        for (command, trim, expected) in test_cases {
+            let provider = provider(&command, None).trim_trailing_newline(trim);
+            assert_eq!(
+                provider.get_pin(&state(&[])).unwrap().expose(),
+                expected,
+                "{command:?}"
+            );
+        }
+    }
+}
@@ -1,9 +1,10 @@
+use crate::response::{unescape, Escaped};
 use nom::{
     branch::alt,
     bytes::complete::{tag, take_till},
     character::complete::{not_line_ending, space0, space1, u64},
-    combinator::{eof, map, map_res, opt},
-    error::Error as NomError,
+    combinator::{eof, map, map_opt, opt},
+    error::{Error as NomError, ErrorKind},
     sequence::{preceded, separated_pair, terminated, tuple},
     IResult,
 };
@@ -11,11 +12,14 @@ use paste::paste;
 use std::{
     borrow::Cow,
     fmt::{self, Display, Formatter},
+    str::FromStr,
+    string::FromUtf8Error,
 };
 use thiserror::Error;
-use urlencoding::decode;
+use zeroize::Zeroize;
 
 #[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Request<'a> {
     Set(Set<'a>),
     Option(OptionReq<'a>),
@@ -27,6 +31,7 @@ pub enum Request<'a> {
     GetInfoVersion,
     GetInfoTtyinfo,
     GetInfoPid,
+    ClearPassphrase(Cow<'a, str>),
     Bye,
     Reset,
     End,
@@ -38,6 +43,7 @@ pub enum Request<'a> {
 }
 
 #[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Set<'a> {
     Timeout(u64),
     Desc(Cow<'a, str>),
@@ -47,17 +53,106 @@ pub enum Set<'a> {
     Cancel(Cow<'a, str>),
     Notok(Cow<'a, str>),
     Error(Cow<'a, str>),
-    Keyinfo(Cow<'a, str>),
+    Keyinfo(Keyinfo<'a>),
     Genpin(Cow<'a, str>),
     GenpinTt(Cow<'a, str>),
-    Repeat(Cow<'a, str>),
+    /// Without a label, a default one is used.
+    Repeat(Option<Cow<'a, str>>),
     Repeaterror(Cow<'a, str>),
     Repeatok(Cow<'a, str>),
     Qualitybar(Option<Cow<'a, str>>),
     QualitybarTt(Cow<'a, str>),
 }
 
+/// The prefixes gpg-agent puts before a keygrip in `SETKEYINFO`: `n/` for a normal key, `s/` for
+/// an SSH key and `u/` for a cache ID given by the user.
+const KEYINFO_PREFIXES: &[&str] = &["n/", "s/", "u/"];
+
+/// The key a dialog is for, as set with `SETKEYINFO`.
+///
+/// A PIN may only be cached if the key has one of the prefixes used by gpg-agent, e.g.
+/// `n/FINGERPRINT`. The client sends `--clear` to ask for a PIN that must not be cached.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Keyinfo<'a> {
+    raw: Cow<'a, str>,
+    cacheable: bool,
+}
+
+impl<'a> From<Cow<'a, str>> for Keyinfo<'a> {
+    fn from(raw: Cow<'a, str>) -> Self {
+        let cacheable = KEYINFO_PREFIXES
+            .iter()
+            .any(|p| raw.len() > p.len() && raw.starts_with(p));
+        Self { raw, cacheable }
+    }
+}
+
+impl<'a> From<&'a str> for Keyinfo<'a> {
+    fn from(raw: &'a str) -> Self {
+        Self::from(Cow::from(raw))
+    }
+}
+
+/// Only an owned value can be wiped, as a borrowed one belongs to the request it was parsed from.
+impl Zeroize for Keyinfo<'_> {
+    fn zeroize(&mut self) {
+        if let Cow::Owned(raw) = &mut self.raw {
+            raw.zeroize();
+        }
+        self.cacheable = false;
+    }
+}
+
+/// Serialized as the value sent by the client.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Keyinfo<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.raw)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Keyinfo<'_> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(|raw| Self::from(Cow::Owned(raw)))
+    }
+}
+
+impl Keyinfo<'_> {
+    /// The value as sent by the client, e.g. `n/FINGERPRINT`.
+    #[must_use]
+    pub fn raw(&self) -> &str {
+        &self.raw
+    }
+
+    /// Whether the PIN for the key may be cached.
+    #[must_use]
+    pub fn cacheable(&self) -> bool {
+        self.cacheable
+    }
+
+    /// The key without its prefix, e.g. `FINGERPRINT`.
+    #[must_use]
+    pub fn key(&self) -> &str {
+        if self.cacheable {
+            &self.raw[2..]
+        } else {
+            &self.raw
+        }
+    }
+
+    /// Copy the value if it is borrowed.
+    #[must_use]
+    pub fn into_owned(self) -> Keyinfo<'static> {
+        Keyinfo {
+            raw: owned(self.raw),
+            cacheable: self.cacheable,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum OptionReq<'a> {
     Bool(Cow<'a, str>),
     KV(Cow<'a, str>, Cow<'a, str>),
@@ -65,13 +160,25 @@ pub enum OptionReq<'a> {
 
 #[derive(Debug, Error)]
 pub enum Error {
-    ParseError(String),
+    ParseError(#[source] nom::Err<NomError<String>>),
+    /// A percent escaped value is not valid UTF-8 once decoded.
+    Decode {
+        value: String,
+        #[source]
+        source: FromUtf8Error,
+    },
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         match self {
-            Error::ParseError(e) => write!(f, "Parse error: {e}"),
+            Error::ParseError(nom::Err::Error(e) | nom::Err::Failure(e)) => {
+                write!(f, "Parse error: {}", e.input)
+            }
+            Error::ParseError(nom::Err::Incomplete(_)) => {
+                write!(f, "Parse error: Incomplete input")
+            }
+            Error::Decode { value, .. } => write!(f, "Not valid UTF-8 once decoded: {value}"),
         }
     }
 }
@@ -89,21 +196,220 @@ impl Display for Error {
 /// # Errors
 /// Will return an error if the input string is not a valid command.
 pub fn parse(s: &str) -> Result<Request<'_>, Error> {
-    parse_command(s).map(|(_, c)| c).map_err(|e| match e {
-        nom::Err::Error(NomError { input, .. }) | nom::Err::Failure(NomError { input, .. }) => {
-            Error::ParseError(input.to_string())
+    Request::try_from(s)
+}
+
+impl<'a> TryFrom<&'a str> for Request<'a> {
+    type Error = Error;
+
+    /// Parse a request, borrowing its arguments from `s` where possible.
+    fn try_from(s: &'a str) -> Result<Self, Error> {
+        parse_command(s).map(|(_, c)| c).map_err(|e| {
+            if let nom::Err::Failure(NomError {
+                input,
+                code: ErrorKind::MapRes,
+            }) = e
+            {
+                // nom only keeps the value that failed, so decode it again for the cause
+                if let Err(source) = urlencoding::decode(input) {
+                    return Error::Decode {
+                        value: input.to_string(),
+                        source,
+                    };
+                }
+            }
+            Error::ParseError(e.to_owned())
+        })
+    }
+}
+
+impl FromStr for Request<'static> {
+    type Err = Error;
+
+    /// Parse a request into one that owns its arguments.
+    ///
+    /// ```
+    /// use elephantine::request::Request;
+    ///
+    /// assert_eq!("GETPIN".parse::<Request>().unwrap(), Request::GetPin);
+    /// ```
+    fn from_str(s: &str) -> Result<Self, Error> {
+        parse(s).map(Request::into_owned)
+    }
+}
+
+impl Request<'_> {
+    /// Copy any borrowed arguments so the request no longer borrows from its input.
+    #[must_use]
+    pub fn into_owned(self) -> Request<'static> {
+        use Request::*;
+        match self {
+            Set(s) => Set(s.into_owned()),
+            Option(o) => Option(o.into_owned()),
+            ClearPassphrase(key) => ClearPassphrase(owned(key)),
+            Confirm => Confirm,
+            ConfirmOneButton => ConfirmOneButton,
+            Message => Message,
+            GetPin => GetPin,
+            GetInfoFlavor => GetInfoFlavor,
+            GetInfoVersion => GetInfoVersion,
+            GetInfoTtyinfo => GetInfoTtyinfo,
+            GetInfoPid => GetInfoPid,
+            Bye => Bye,
+            Reset => Reset,
+            End => End,
+            Help => Help,
+            Quit => Quit,
+            Cancel => Cancel,
+            Auth => Auth,
+            Nop => Nop,
+        }
+    }
+}
+
+impl Set<'_> {
+    /// Copy the value if it is borrowed.
+    #[must_use]
+    pub fn into_owned(self) -> Set<'static> {
+        use Set::*;
+        match self {
+            Timeout(t) => Timeout(t),
+            Desc(v) => Desc(owned(v)),
+            Prompt(v) => Prompt(owned(v)),
+            Title(v) => Title(owned(v)),
+            Ok(v) => Ok(owned(v)),
+            Cancel(v) => Cancel(owned(v)),
+            Notok(v) => Notok(owned(v)),
+            Error(v) => Error(owned(v)),
+            Keyinfo(v) => Keyinfo(v.into_owned()),
+            Genpin(v) => Genpin(owned(v)),
+            GenpinTt(v) => GenpinTt(owned(v)),
+            Repeat(v) => Repeat(v.map(owned)),
+            Repeaterror(v) => Repeaterror(owned(v)),
+            Repeatok(v) => Repeatok(owned(v)),
+            Qualitybar(v) => Qualitybar(v.map(owned)),
+            QualitybarTt(v) => QualitybarTt(owned(v)),
+        }
+    }
+}
+
+impl OptionReq<'_> {
+    /// Copy the key and value if they are borrowed.
+    #[must_use]
+    pub fn into_owned(self) -> OptionReq<'static> {
+        match self {
+            OptionReq::Bool(k) => OptionReq::Bool(owned(k)),
+            OptionReq::KV(k, v) => OptionReq::KV(owned(k), owned(v)),
+        }
+    }
+}
+
+impl Display for Request<'_> {
+    /// Write the request in its wire form, percent escaping its arguments.
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        use Request::*;
+        match self {
+            Set(s) => write!(f, "{s}"),
+            Option(o) => write!(f, "{o}"),
+            ClearPassphrase(key) => write!(f, "CLEARPASSPHRASE {}", Escaped(key)),
+            Confirm => write!(f, "CONFIRM"),
+            ConfirmOneButton => write!(f, "CONFIRM --one-button"),
+            Message => write!(f, "MESSAGE"),
+            GetPin => write!(f, "GETPIN"),
+            GetInfoFlavor => write!(f, "GETINFO flavor"),
+            GetInfoVersion => write!(f, "GETINFO version"),
+            GetInfoTtyinfo => write!(f, "GETINFO ttyinfo"),
+            GetInfoPid => write!(f, "GETINFO pid"),
+            Bye => write!(f, "BYE"),
+            Reset => write!(f, "RESET"),
+            End => write!(f, "END"),
+            Help => write!(f, "HELP"),
+            Quit => write!(f, "QUIT"),
+            Cancel => write!(f, "CANCEL"),
+            Auth => write!(f, "AUTH"),
+            Nop => write!(f, "NOP"),
         }
-        nom::Err::Incomplete(_n) => Error::ParseError("Incomplete input".to_string()),
-    })
+    }
+}
+
+impl Display for Set<'_> {
+    /// Write the `SET` request in its wire form, percent escaping the value. A leading space or
+    /// tab is escaped too, as it would be taken for the separator before the value.
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        use Set::*;
+        let (name, value) = match self {
+            Timeout(t) => return write!(f, "SETTIMEOUT {t}"),
+            Qualitybar(None) => return write!(f, "SETQUALITYBAR"),
+            Repeat(None) => return write!(f, "SETREPEAT"),
+            Desc(v) => ("DESC", v),
+            Prompt(v) => ("PROMPT", v),
+            Title(v) => ("TITLE", v),
+            Ok(v) => ("OK", v),
+            Cancel(v) => ("CANCEL", v),
+            Notok(v) => ("NOTOK", v),
+            Error(v) => ("ERROR", v),
+            Keyinfo(v) => ("KEYINFO", &v.raw),
+            Genpin(v) => ("GENPIN", v),
+            GenpinTt(v) => ("GENPIN_TT", v),
+            Repeat(Some(v)) => ("REPEAT", v),
+            Repeaterror(v) => ("REPEATERROR", v),
+            Repeatok(v) => ("REPEATOK", v),
+            Qualitybar(Some(v)) => ("QUALITYBAR", v),
+            QualitybarTt(v) => ("QUALITYBAR_TT", v),
+        };
+        write!(f, "SET{name} ")?;
+        let value = match value.as_bytes().first() {
+            Some(&byte @ (b' ' | b'\t')) => {
+                write!(f, "%{byte:02X}")?;
+                &value[1..]
+            }
+            _ => value,
+        };
+        write!(f, "{}", Escaped(value))
+    }
+}
+
+impl Display for OptionReq<'_> {
+    /// Write the `OPTION` request in its wire form, percent escaping the key and value. A value
+    /// that would not be parsed back as it is, e.g. an empty one, is quoted.
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            OptionReq::Bool(k) => write!(f, "OPTION {}", Escaped(k)),
+            OptionReq::KV(k, v) if needs_quotes(v) => {
+                write!(f, "OPTION {}=\"{}\"", Escaped(k), Escaped(v))
+            }
+            OptionReq::KV(k, v) => write!(f, "OPTION {}={}", Escaped(k), Escaped(v)),
+        }
+    }
+}
+
+fn owned(s: Cow<'_, str>) -> Cow<'static, str> {
+    Cow::Owned(s.into_owned())
+}
+
+/// Percent-decode the value matched by `parser`, like gpg-agent escapes it. Escapes that aren't
+/// two hex digits are kept as they are. A value that isn't valid UTF-8 once decoded fails the
+/// whole request, rather than letting other commands try to parse it.
+fn unescaped<'a>(
+    mut parser: impl FnMut(&'a str) -> IResult<&'a str, &'a str>,
+) -> impl FnMut(&'a str) -> IResult<&'a str, Cow<'a, str>> {
+    move |s| {
+        let (rest, raw) = parser(s)?;
+        match unescape(raw) {
+            Ok(value) => Ok((rest, value)),
+            Err(_) => Err(nom::Err::Failure(NomError::new(raw, ErrorKind::MapRes))),
+        }
+    }
 }
 
-fn parse_command(s: &str) -> IResult<&str, Request> {
+fn parse_command(s: &str) -> IResult<&str, Request<'_>> {
     let (s, (cmd, _)) = tuple((
         alt((
             parse_set,
             parse_get,
             parse_confirm,
             parse_option,
+            parse_clear_passphrase,
             map(tag("MESSAGE"), |_| Request::Message),
             map(tag("BYE"), |_| Request::Bye),
             map(tag("RESET"), |_| Request::Reset),
@@ -126,7 +432,7 @@ macro_rules! gen_parse_set {
                 map(
                     preceded(
                         terminated(tag($x), space1),
-                        map_res(not_line_ending, decode),
+                        unescaped(not_line_ending),
                     ),
                     Set::[<$x:camel>],
                 )(s)
@@ -142,10 +448,19 @@ gen_parse_set!("OK");
 gen_parse_set!("CANCEL");
 gen_parse_set!("NOTOK");
 gen_parse_set!("ERROR");
-gen_parse_set!("KEYINFO");
 gen_parse_set!("GENPIN");
 gen_parse_set!("GENPIN_TT");
 
+fn parse_set_keyinfo(s: &str) -> IResult<&str, Set<'_>> {
+    map(
+        preceded(
+            terminated(tag("KEYINFO"), space1),
+            unescaped(not_line_ending),
+        ),
+        |raw| Set::Keyinfo(Keyinfo::from(raw)),
+    )(s)
+}
+
 fn parse_set_timeout(s: &str) -> IResult<&str, Set<'_>> {
     map(
         preceded(terminated(tag("TIMEOUT"), space1), u64),
@@ -157,22 +472,16 @@ fn parse_set_repeat(s: &str) -> IResult<&str, Set<'_>> {
     preceded(
         tag("REPEAT"),
         alt((
+            map(eof, |_| Set::Repeat(None)),
+            map(unescaped(preceded(space1, not_line_ending)), |val| {
+                Set::Repeat(Some(val))
+            }),
             map(
-                map_res(preceded(space1, not_line_ending), decode),
-                Set::Repeat,
-            ),
-            map(
-                map_res(
-                    preceded(terminated(tag("ERROR"), space1), not_line_ending),
-                    decode,
-                ),
+                unescaped(preceded(terminated(tag("ERROR"), space1), not_line_ending)),
                 Set::Repeaterror,
             ),
             map(
-                map_res(
-                    preceded(terminated(tag("OK"), space1), not_line_ending),
-                    decode,
-                ),
+                unescaped(preceded(terminated(tag("OK"), space1), not_line_ending)),
                 Set::Repeatok,
             ),
         )),
@@ -183,22 +492,20 @@ fn parse_set_qualitybar(s: &str) -> IResult<&str, Set<'_>> {
     preceded(
         tag("QUALITYBAR"),
         alt((
-            map(eof, |_| Set::Qualitybar(None)),
-            map(map_res(preceded(space1, not_line_ending), decode), |val| {
+            // gpg-agent may send a trailing space without a label
+            map(terminated(space0, eof), |_| Set::Qualitybar(None)),
+            map(unescaped(preceded(space1, not_line_ending)), |val| {
                 Set::Qualitybar(Some(val))
             }),
             map(
-                map_res(
-                    preceded(terminated(tag("_TT"), space1), not_line_ending),
-                    decode,
-                ),
+                unescaped(preceded(terminated(tag("_TT"), space1), not_line_ending)),
                 Set::QualitybarTt,
             ),
         )),
     )(s)
 }
 
-fn parse_set(s: &str) -> IResult<&str, Request> {
+fn parse_set(s: &str) -> IResult<&str, Request<'_>> {
     map(
         preceded(
             tag("SET"),
@@ -222,14 +529,14 @@ fn parse_set(s: &str) -> IResult<&str, Request> {
     )(s)
 }
 
-fn parse_get(s: &str) -> IResult<&str, Request> {
+fn parse_get(s: &str) -> IResult<&str, Request<'_>> {
     preceded(
         tag("GET"),
         alt((map(tag("PIN"), |_| Request::GetPin), parse_get_info)),
     )(s)
 }
 
-fn parse_get_info(s: &str) -> IResult<&str, Request> {
+fn parse_get_info(s: &str) -> IResult<&str, Request<'_>> {
     preceded(
         terminated(tag("INFO"), space1),
         alt((
@@ -241,7 +548,7 @@ fn parse_get_info(s: &str) -> IResult<&str, Request> {
     )(s)
 }
 
-fn parse_confirm(s: &str) -> IResult<&str, Request> {
+fn parse_confirm(s: &str) -> IResult<&str, Request<'_>> {
     preceded(
         tag("CONFIRM"),
         alt((
@@ -253,11 +560,34 @@ fn parse_confirm(s: &str) -> IResult<&str, Request> {
     )(s)
 }
 
+fn parse_clear_passphrase(s: &str) -> IResult<&str, Request<'_>> {
+    map(
+        preceded(
+            terminated(tag("CLEARPASSPHRASE"), space1),
+            unescaped(not_line_ending),
+        ),
+        Request::ClearPassphrase,
+    )(s)
+}
+
 fn not_whitespace_nor_char(c: char) -> impl Fn(&str) -> IResult<&str, &str> {
     move |s| take_till(|d: char| d.is_whitespace() || d == c)(s)
 }
 
-fn parse_option(s: &str) -> IResult<&str, Request> {
+/// The rest of the line without the double quotes around it, if it is quoted.
+fn quoted(s: &str) -> IResult<&str, &str> {
+    map_opt(not_line_ending, |line: &str| {
+        line.strip_prefix('"')?.strip_suffix('"')
+    })(s)
+}
+
+/// Whether an `OPTION` value has to be quoted to be parsed back as it is, because it would be
+/// taken for no value or lose its leading whitespace or quotes otherwise.
+fn needs_quotes(value: &str) -> bool {
+    value.is_empty() || value.starts_with(|c: char| c == '"' || c.is_whitespace())
+}
+
+fn parse_option(s: &str) -> IResult<&str, Request<'_>> {
     map(
         preceded(
             tuple((tag("OPTION"), space1)),
@@ -265,13 +595,19 @@ fn parse_option(s: &str) -> IResult<&str, Request> {
                 preceded(
                     opt(tag("--")),
                     separated_pair(
-                        map_res(not_whitespace_nor_char('='), decode),
+                        unescaped(not_whitespace_nor_char('=')),
                         tuple((space0, opt(tag("=")), space0)),
-                        opt(map_res(not_line_ending, decode)),
+                        // Some clients quote values with spaces, and a quoted value may be empty
+                        opt(alt((
+                            map(unescaped(quoted), |value| (value, true)),
+                            map(unescaped(not_line_ending), |value| (value, false)),
+                        ))),
                     ),
                 ),
                 |(key, value)| match value {
-                    Some(value) if !value.is_empty() => OptionReq::KV(key, value),
+                    Some((value, quoted)) if quoted || !value.is_empty() => {
+                        OptionReq::KV(key, value)
+                    }
                     _ => OptionReq::Bool(key),
                 },
             ),
@@ -310,7 +646,8 @@ mod test {
             ),
             ("SETNOTOK notok", Set(Notok(Cow::from("notok")))),
             ("SETERROR error", Set(Error(Cow::from("error")))),
-            ("SETREPEAT value", Set(Repeat(Cow::from("value")))),
+            ("SETREPEAT value", Set(Repeat(Some(Cow::from("value"))))),
+            ("SETREPEAT", Set(Repeat(None))),
             ("SETREPEATERROR value", Set(Repeaterror(Cow::from("value")))),
             ("SETREPEATOK value", Set(Repeatok(Cow::from("value")))),
             ("SETQUALITYBAR", Set(Qualitybar(None))),
@@ -329,9 +666,10 @@ mod test {
             ("MESSAGE", Message),
             (
                 "SETKEYINFO dummy-key-grip",
-                Set(Keyinfo(Cow::from("dummy-key-grip"))),
+                Set(Keyinfo(super::Keyinfo::from("dummy-key-grip"))),
             ),
             ("GETPIN", GetPin),
+            ("CLEARPASSPHRASE n/FPR", ClearPassphrase(Cow::from("n/FPR"))),
             ("BYE", Bye),
             ("RESET", Reset),
             ("END", End),
@@ -348,6 +686,213 @@ mod test {
         }
     }
 
+    #[test]
+    fn parse_keyinfo() {
+        use super::{Request, Set};
+
+        let test_cases = vec![
+            ("SETKEYINFO n/FPR", "n/FPR", true, "FPR"),
+            ("SETKEYINFO s/FPR", "s/FPR", true, "FPR"),
+            ("SETKEYINFO u/cache%2Fid", "u/cache/id", true, "cache/id"),
+            ("SETKEYINFO FPR", "FPR", false, "FPR"),
+            ("SETKEYINFO n/", "n/", false, "n/"),
+            ("SETKEYINFO --clear", "--clear", false, "--clear"),
+        ];
+
+        for (input, raw, cacheable, key) in test_cases {
+            let Ok(Request::Set(Set::Keyinfo(keyinfo))) = super::parse(input) else {
+                panic!("{input}");
+            };
+            assert_eq!(keyinfo.raw(), raw, "{input}");
+            assert_eq!(keyinfo.cacheable(), cacheable, "{input}");
+            assert_eq!(keyinfo.key(), key, "{input}");
+        }
+    }
+
+    #[test]
+    fn try_from_and_from_str() {
+        use super::{Error, OptionReq::*, Request};
+
+        let test_cases = vec![
+            "GETPIN",
+            "SETDESC a%0Ab",
+            "SETQUALITYBAR",
+            "OPTION ttyname=/dev/pts/1",
+            "CLEARPASSPHRASE n/FPR",
+        ];
+
+        for input in test_cases {
+            let borrowed = Request::try_from(input).unwrap();
+            let owned: Request<'static> = input.parse().unwrap();
+            assert_eq!(borrowed, owned, "{input}");
+            assert_eq!(super::parse(input).unwrap(), owned, "{input}");
+        }
+
+        assert!(matches!(
+            "OPTION key=value".parse::<Request>(),
+            Ok(Option(KV(Cow::Owned(_), Cow::Owned(_))))
+        ));
+        assert!(matches!(
+            "NOT A COMMAND".parse::<Request>(),
+            Err(Error::ParseError(_))
+        ));
+    }
+
+    #[test]
+    fn parse_unescapes() {
+        use super::{OptionReq::*, Set::*};
+
+        let test_cases = vec![
+            ("SETDESC a%0Ab", Some(Set(Desc(Cow::from("a\nb"))))),
+            ("SETPROMPT 100%25", Some(Set(Prompt(Cow::from("100%"))))),
+            ("SETERROR %C3%A9", Some(Set(Error(Cow::from("é"))))),
+            // Escapes that aren't two hex digits are kept as they are
+            ("SETTITLE 50%A", Some(Set(Title(Cow::from("50%A"))))),
+            ("SETTITLE %ZZ", Some(Set(Title(Cow::from("%ZZ"))))),
+            ("SETREPEATOK %", Some(Set(Repeatok(Cow::from("%"))))),
+            (
+                "OPTION ttyname=%2Fdev%2Fpts%2F1",
+                Some(Option(KV(Cow::from("ttyname"), Cow::from("/dev/pts/1")))),
+            ),
+            ("SETDESC %FF", None),
+            ("SETPROMPT a%C3", None),
+            ("SETREPEATERROR %FF", None),
+            ("SETQUALITYBAR_TT %FF", None),
+            ("SETKEYINFO n/%FF", None),
+            ("CLEARPASSPHRASE %FF", None),
+            ("OPTION %FF", None),
+            ("OPTION key=%FF", None),
+        ];
+
+        for (input, expected) in test_cases {
+            match (super::parse(input), expected) {
+                (std::result::Result::Ok(req), Some(expected)) => {
+                    assert_eq!(req, expected, "{input}");
+                }
+                (Err(super::Error::Decode { .. }), None) => {}
+                (result, _) => panic!("{input}: {result:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn error_source() {
+        use std::{error::Error, string::FromUtf8Error};
+
+        let test_cases = vec![
+            ("NOT A COMMAND", "Parse error: NOT A COMMAND"),
+            ("SETDESC %FF", "Not valid UTF-8 once decoded: %FF"),
+        ];
+
+        for (input, expected) in test_cases {
+            let e = super::parse(input).unwrap_err();
+            assert_eq!(e.to_string(), expected, "{input}");
+            assert!(e.source().is_some(), "{input}");
+        }
+
+        let e = super::parse("SETDESC %FF").unwrap_err();
+        assert!(e.source().unwrap().is::<FromUtf8Error>());
+    }
+
+    #[test]
+    fn display_round_trips() {
+        use super::{OptionReq::*, Set::*};
+
+        let values = [
+            "value",
+            "two words",
+            "50%",
+            "line 1\nline 2",
+            "é",
+            "",
+            " padded",
+            "\ttabbed",
+        ];
+        let mut test_cases = vec![
+            Confirm,
+            ConfirmOneButton,
+            Message,
+            GetPin,
+            GetInfoFlavor,
+            GetInfoVersion,
+            GetInfoTtyinfo,
+            GetInfoPid,
+            Bye,
+            Reset,
+            End,
+            Help,
+            Quit,
+            super::Request::Cancel,
+            Auth,
+            Nop,
+            Set(Timeout(10)),
+            Set(Qualitybar(None)),
+            Set(Repeat(None)),
+            Option(Bool(Cow::from("no-grab"))),
+            ClearPassphrase(Cow::from("n/FPR")),
+        ];
+        for value in values {
+            let v = || Cow::from(value);
+            test_cases.extend([
+                Set(Desc(v())),
+                Set(Prompt(v())),
+                Set(Title(v())),
+                Set(Ok(v())),
+                Set(super::Set::Cancel(v())),
+                Set(Notok(v())),
+                Set(Error(v())),
+                Set(Keyinfo(super::Keyinfo::from(v()))),
+                Set(Genpin(v())),
+                Set(GenpinTt(v())),
+                Set(Repeat(Some(v()))),
+                Set(Repeaterror(v())),
+                Set(Repeatok(v())),
+                Set(QualitybarTt(v())),
+            ]);
+            // An empty label is sent as a trailing space, which is parsed as no label
+            if !value.is_empty() {
+                test_cases.push(Set(Qualitybar(Some(v()))));
+            }
+        }
+        for value in values.into_iter().chain(["\"quoted\"", "\"", "a \"b\""]) {
+            test_cases.push(Option(KV(Cow::from("ttyname"), Cow::from(value))));
+        }
+
+        for request in test_cases {
+            let line = request.to_string();
+            assert_eq!(super::parse(&line).unwrap(), request, "{line}");
+        }
+    }
+
+    #[test]
+    fn display() {
+        use super::{OptionReq::*, Set::*};
+
+        let test_cases = vec![
+            (Set(Title(Cow::from("x"))), "SETTITLE x"),
+            (Set(Desc(Cow::from("100%\nsure"))), "SETDESC 100%25%0Asure"),
+            (Set(Prompt(Cow::from("  PIN "))), "SETPROMPT %20 PIN "),
+            (
+                Set(GenpinTt(Cow::from("Generate"))),
+                "SETGENPIN_TT Generate",
+            ),
+            (
+                Option(KV(Cow::from("lc-ctype"), Cow::from("en_AU.UTF8"))),
+                "OPTION lc-ctype=en_AU.UTF8",
+            ),
+            (
+                Option(KV(Cow::from("ttyname"), Cow::from(""))),
+                r#"OPTION ttyname="""#,
+            ),
+            (GetInfoFlavor, "GETINFO flavor"),
+            (ConfirmOneButton, "CONFIRM --one-button"),
+        ];
+
+        for (request, expected) in test_cases {
+            assert_eq!(request.to_string(), expected);
+        }
+    }
+
     #[test]
     fn parse_set_option() {
         use super::{parse_option, OptionReq::*, Request};
@@ -380,6 +925,36 @@ mod test {
                 "OPTION --key = value",
                 Ok(KV(Cow::from("key"), Cow::from("value"))),
             ),
+            (
+                r#"OPTION key="a b c""#,
+                Ok(KV(Cow::from("key"), Cow::from("a b c"))),
+            ),
+            (
+                r#"OPTION --key = "a b c""#,
+                Ok(KV(Cow::from("key"), Cow::from("a b c"))),
+            ),
+            (
+                r#"OPTION key="a%20b""#,
+                Ok(KV(Cow::from("key"), Cow::from("a b"))),
+            ),
+            (r#"OPTION key="""#, Ok(KV(Cow::from("key"), Cow::from("")))),
+            (
+                r#"OPTION key="""""#,
+                Ok(KV(Cow::from("key"), Cow::from(r#""""#))),
+            ),
+            (
+                r#"OPTION key=""#,
+                Ok(KV(Cow::from("key"), Cow::from(r#"""#))),
+            ),
+            (
+                r#"OPTION key="a" b"#,
+                Ok(KV(Cow::from("key"), Cow::from(r#""a" b"#))),
+            ),
+            (
+                r#"OPTION key=a "b""#,
+                Ok(KV(Cow::from("key"), Cow::from(r#"a "b""#))),
+            ),
+            ("OPTION key=", Ok(Bool(Cow::from("key")))),
             (
                 "OPTIONalkey",
                 Err(nom::Err::Error(Error::new("alkey", ErrorKind::Space))),
@@ -404,6 +979,8 @@ mod test {
                 Err(nom::Err::Error(Error::new("a", ErrorKind::Tag))),
             ),
             ("QUALITYBAR", Ok(Set::Qualitybar(None))),
+            ("QUALITYBAR ", Ok(Set::Qualitybar(None))),
+            ("QUALITYBAR \t", Ok(Set::Qualitybar(None))),
             (
                 "QUALITYBAR value",
                 Ok(Set::Qualitybar(Some(Cow::from("value")))),
@@ -420,6 +997,32 @@ mod test {
         }
     }
 
+    #[test]
+    fn parse_set_repeat() {
+        use super::parse_set_repeat;
+        use super::Set;
+        use nom::error::{Error, ErrorKind};
+
+        let test_cases = vec![
+            (
+                "REPEATa",
+                Err(nom::Err::Error(Error::new("a", ErrorKind::Tag))),
+            ),
+            ("REPEAT", Ok(Set::Repeat(None))),
+            ("REPEAT Again:", Ok(Set::Repeat(Some(Cow::from("Again:"))))),
+            (
+                "REPEATERROR No match",
+                Ok(Set::Repeaterror(Cow::from("No match"))),
+            ),
+            ("REPEATOK Match", Ok(Set::Repeatok(Cow::from("Match")))),
+        ];
+
+        for (input, expected) in test_cases {
+            let result = parse_set_repeat(input);
+            assert_eq!(result, expected.map(|x| ("", x)));
+        }
+    }
+
     #[test]
     fn parse_confirm() {
         use super::parse_confirm;
@@ -439,4 +1042,30 @@ mod test {
             assert_eq!(result, expected.map(|x| ("", x)));
         }
     }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() {
+        use super::{OptionReq::*, Request, Set::*};
+
+        let test_cases = vec![
+            (GetPin, r#""GetPin""#),
+            (Set(Timeout(10)), r#"{"Set":{"Timeout":10}}"#),
+            (Set(Desc(Cow::from("a\nb"))), r#"{"Set":{"Desc":"a\nb"}}"#),
+            (Set(Repeat(None)), r#"{"Set":{"Repeat":null}}"#),
+            (
+                Set(Keyinfo(super::Keyinfo::from("n/ABCD"))),
+                r#"{"Set":{"Keyinfo":"n/ABCD"}}"#,
+            ),
+            (
+                Option(KV(Cow::from("ttyname"), Cow::from("/dev/tty"))),
+                r#"{"Option":{"KV":["ttyname","/dev/tty"]}}"#,
+            ),
+        ];
+
+        for (req, expected) in test_cases {
+            assert_eq!(serde_json::to_string(&req).unwrap(), expected);
+            assert_eq!(serde_json::from_str::<Request>(expected).unwrap(), req);
+        }
+    }
 }
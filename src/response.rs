@@ -1,18 +1,85 @@
+use crate::secret::SecretString;
 use std::{
     borrow::Cow,
     fmt::{self, Display, Formatter},
+    str::FromStr,
 };
+use thiserror::Error;
 
+/// The maximum length of a line in the Assuan protocol, excluding the trailing newline.
+pub const MAX_LINE_LENGTH: usize = 1000;
+
+/// The maximum length of the escaped payload of a single `D` line.
+const MAX_DATA_LENGTH: usize = MAX_LINE_LENGTH - "D ".len();
+
+/// The status keyword telling the client that the user entered the PIN twice.
+pub const PIN_REPEATED: &str = "PIN_REPEATED";
+
+/// The status keyword telling the client that the PIN came from the cache, so it should clear
+/// the cache if the PIN is wrong.
+pub const PASSWORD_FROM_CACHE: &str = "PASSWORD_FROM_CACHE";
+
+/// A line sent to the client.
+///
+/// With the `serde` feature, a response can be serialized, e.g. to trace a session, but the PIN
+/// in a [`Response::SecretD`] is left out.
 #[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum Response {
     Ok(Option<String>),
     Err(i32, String),
     D(String),
+    /// A `D` line holding a secret, which is wiped from memory after it is written.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "redact"))]
+    SecretD(SecretString),
     Comment(String),
     S(String, String),
     Inquire(String, String),
 }
 
+impl Response {
+    /// An `OK` line without a message.
+    #[must_use]
+    pub fn ok() -> Self {
+        Response::Ok(None)
+    }
+
+    /// An `OK` line with a message, e.g. a greeting.
+    #[must_use]
+    pub fn ok_msg(msg: impl Into<String>) -> Self {
+        Response::Ok(Some(msg.into()))
+    }
+
+    /// An `ERR` line with an error code and a description.
+    #[must_use]
+    pub fn err(code: i32, msg: impl Into<String>) -> Self {
+        Response::Err(code, msg.into())
+    }
+
+    /// An `S` status line. The value is left out if it is empty.
+    #[must_use]
+    pub fn status(key: impl Into<String>, value: impl Into<String>) -> Self {
+        Response::S(key.into(), value.into())
+    }
+
+    /// The status line sent before a PIN that the user entered twice.
+    #[must_use]
+    pub fn pin_repeated() -> Self {
+        Response::status(PIN_REPEATED, "1")
+    }
+
+    /// The status line sent before a PIN that came from the cache.
+    #[must_use]
+    pub fn password_from_cache() -> Self {
+        Response::status(PASSWORD_FROM_CACHE, "")
+    }
+}
+
+#[cfg(feature = "serde")]
+fn redact<S: serde::Serializer>(_: &SecretString, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str("***")
+}
+
 impl Display for Response {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         use Response::*;
@@ -22,59 +89,259 @@ impl Display for Response {
                 "OK{}",
                 s.as_ref().map(|s| format!(" {s}")).unwrap_or_default(),
             ),
-            Err(code, msg) => write!(f, "ERR {code} {msg}"),
-            D(s) => write!(f, "D {}", escape(s)),
+            // Escaped, so that a description spanning lines can't end the response early
+            Err(code, msg) => write!(f, "ERR {code} {}", Escaped(msg)),
+            D(s) => write_data(f, s),
+            // Escaped straight to the output, so the PIN isn't copied
+            SecretD(s) => write_data(f, s.expose()),
             Comment(s) => write!(f, "# {s}"),
+            S(k, v) if v.is_empty() => write!(f, "S {k}"),
             S(k, v) => write!(f, "S {k} {v}"),
-            Inquire(k, v) => write!(f, "INQUIRE {k} {v}"),
+            Inquire(k, v) => write!(f, "INQUIRE {k} {}", Escaped(v)),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    ParseError(String),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Error::ParseError(e) => write!(f, "Parse error: {e}"),
         }
     }
 }
 
-/// Encode a string to be used in a response. It will percent escape `%`, `\n`, and `\r`.
-fn escape(s: &str) -> Cow<'_, str> {
-    // TODO: Split into lines of length at most 1000 bytes.
-    let mut s = s;
-    let mut escaped = String::with_capacity(s.len());
+impl FromStr for Response {
+    type Err = Error;
 
-    loop {
-        let unescaped_len = s
-            .chars()
-            .take_while(|c| !matches!(c, '%' | '\n' | '\r'))
-            .count();
+    /// Parse a single response line, e.g. from a pinentry. The payload of a `D` line and the
+    /// arguments of an `INQUIRE` are unescaped. A secret is parsed as `D`.
+    ///
+    /// ```
+    /// use elephantine::response::Response;
+    ///
+    /// assert_eq!(
+    ///     "D 12%2534".parse::<Response>().unwrap(),
+    ///     Response::D("12%34".to_string())
+    /// );
+    /// ```
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let (keyword, rest) = match s.split_once(' ') {
+            Some((keyword, rest)) => (keyword, Some(rest)),
+            None => (s, None),
+        };
+        let parse_error = || Error::ParseError(s.to_string());
 
-        let (unescaped, rest) = if unescaped_len >= s.len() {
-            if escaped.is_empty() {
-                return Cow::from(s);
+        let response = match keyword {
+            "OK" => Response::Ok(rest.map(ToString::to_string)),
+            "ERR" => {
+                let rest = rest.ok_or_else(parse_error)?;
+                let (code, description) = rest.split_once(' ').unwrap_or((rest, ""));
+                let code = code.parse().map_err(|_| parse_error())?;
+                Response::Err(code, description.to_string())
+            }
+            "D" => Response::D(unescape(rest.unwrap_or_default())?.into_owned()),
+            "S" => {
+                let rest = rest.ok_or_else(parse_error)?;
+                let (k, v) = rest.split_once(' ').unwrap_or((rest, ""));
+                Response::S(k.to_string(), v.to_string())
+            }
+            "INQUIRE" => {
+                let rest = rest.ok_or_else(parse_error)?;
+                let (k, v) = rest.split_once(' ').unwrap_or((rest, ""));
+                Response::Inquire(k.to_string(), unescape(v)?.into_owned())
             }
-            (s, "")
-        } else {
-            s.split_at(unescaped_len)
+            "#" => Response::Comment(rest.unwrap_or_default().to_string()),
+            _ => return Err(parse_error()),
         };
+        Ok(response)
+    }
+}
 
-        if !unescaped.is_empty() {
-            escaped.push_str(unescaped);
-        }
-        if rest.is_empty() {
-            break;
-        }
-        let (first, rest) = rest.split_at(1);
-        match first {
-            "%" => escaped.push_str("%25"),
-            "\n" => escaped.push_str("%0A"),
-            "\r" => escaped.push_str("%0D"),
-            _ => unreachable!(),
+/// Write data as `D` lines, percent escaping it as it is written.
+fn write_data(f: &mut Formatter, data: &str) -> fmt::Result {
+    f.write_str("D ")?;
+    escape_into(data, &mut DataLines { out: f, len: 0 })
+}
+
+/// Writes escaped data, starting a new `D` line whenever the next chunk wouldn't fit on the
+/// current one. Long data is split over several `D` lines which the client concatenates.
+///
+/// Chunks are split on UTF-8 character boundaries. [`escape_into`] writes each escape sequence
+/// as a chunk of its own, so they are never split.
+struct DataLines<'a, W> {
+    out: &'a mut W,
+    len: usize,
+}
+
+impl<W: fmt::Write> fmt::Write for DataLines<'_, W> {
+    fn write_str(&mut self, mut s: &str) -> fmt::Result {
+        while self.len + s.len() > MAX_DATA_LENGTH {
+            let mut end = if s.starts_with('%') {
+                0
+            } else {
+                MAX_DATA_LENGTH - self.len
+            };
+            while !s.is_char_boundary(end) {
+                end -= 1;
+            }
+            let (line, tail) = s.split_at(end);
+            self.out.write_str(line)?;
+            self.out.write_str("\nD ")?;
+            self.len = 0;
+            s = tail;
         }
-        s = rest;
+        self.out.write_str(s)?;
+        self.len += s.len();
+        Ok(())
     }
+}
 
+const HEX_DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+
+/// Whether a character must be percent escaped in a response.
+fn needs_escape(c: char) -> bool {
+    c == '%' || c.is_ascii_control()
+}
+
+/// Encode a string to be used in a response. It will percent escape `%` and all ASCII control
+/// characters, i.e. those below `0x20` and `0x7F`.
+pub(crate) fn escape(s: &str) -> Cow<'_, str> {
+    if !s.contains(needs_escape) {
+        return Cow::from(s);
+    }
+    let mut escaped = String::with_capacity(s.len() + 2);
+    // Writing to a `String` can't fail
+    let _ = escape_into(s, &mut escaped);
     Cow::from(escaped)
 }
 
+/// Percent escape `s` like [`escape`], but write it to `out` instead of allocating, e.g. to a
+/// buffer that is reused or straight to a formatter. Each escape sequence is a separate write.
+pub(crate) fn escape_into(s: &str, out: &mut impl fmt::Write) -> fmt::Result {
+    let mut rest = s;
+    while let Some(i) = rest.find(needs_escape) {
+        let (unescaped, tail) = rest.split_at(i);
+        if !unescaped.is_empty() {
+            out.write_str(unescaped)?;
+        }
+        // All characters that need escaping are ASCII, so they are a single byte
+        let byte = tail.as_bytes()[0];
+        let sequence = [
+            b'%',
+            HEX_DIGITS[usize::from(byte >> 4)],
+            HEX_DIGITS[usize::from(byte & 0xf)],
+        ];
+        out.write_str(std::str::from_utf8(&sequence).map_err(|_| fmt::Error)?)?;
+        rest = &tail[1..];
+    }
+    if rest.is_empty() {
+        Ok(())
+    } else {
+        out.write_str(rest)
+    }
+}
+
+/// Writes a string percent escaped, without allocating.
+pub(crate) struct Escaped<'a>(pub(crate) &'a str);
+
+impl Display for Escaped<'_> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        escape_into(self.0, f)
+    }
+}
+
+/// Decode a percent escaped string, the inverse of [`escape`].
+///
+/// # Errors
+/// If the decoded bytes are not valid UTF-8.
+pub(crate) fn unescape(s: &str) -> Result<Cow<'_, str>, Error> {
+    urlencoding::decode(s).map_err(|e| Error::ParseError(e.to_string()))
+}
+
 #[cfg(test)]
 mod test {
+    use super::{Response, MAX_LINE_LENGTH};
     use std::borrow::Cow;
 
+    #[test]
+    fn constructors() {
+        let test_cases = vec![
+            (Response::ok(), "OK"),
+            (
+                Response::ok_msg("closing connection"),
+                "OK closing connection",
+            ),
+            (Response::err(1, "oops"), "ERR 1 oops"),
+            (Response::err(1, "oops\ntwo"), "ERR 1 oops%0Atwo"),
+            (Response::status("PROGRESS", "1 2"), "S PROGRESS 1 2"),
+            (Response::pin_repeated(), "S PIN_REPEATED 1"),
+            (Response::password_from_cache(), "S PASSWORD_FROM_CACHE"),
+        ];
+
+        for (resp, expected) in test_cases {
+            assert_eq!(resp.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn display_secret_data() {
+        assert_eq!(
+            Response::SecretD("12%34\n".into()).to_string(),
+            "D 12%2534%0A"
+        );
+    }
+
+    #[test]
+    fn display_splits_long_data() {
+        let max = MAX_LINE_LENGTH - 2;
+        let test_cases = vec![
+            (String::new(), vec![String::new()]),
+            ("a".repeat(max - 1), vec!["a".repeat(max - 1)]),
+            ("a".repeat(max), vec!["a".repeat(max)]),
+            ("a".repeat(max + 1), vec!["a".repeat(max), "a".to_string()]),
+            (
+                "a".repeat(2 * max + 1),
+                vec!["a".repeat(max), "a".repeat(max), "a".to_string()],
+            ),
+            // Don't split an escape sequence
+            (
+                format!("{}\n", "a".repeat(max - 1)),
+                vec!["a".repeat(max - 1), "%0A".to_string()],
+            ),
+            (
+                format!("{}\n", "a".repeat(max - 2)),
+                vec!["a".repeat(max - 2), "%0A".to_string()],
+            ),
+            (
+                format!("{}\n", "a".repeat(max - 3)),
+                vec![format!("{}%0A", "a".repeat(max - 3))],
+            ),
+            // Don't split a multibyte character
+            (
+                format!("{}é", "a".repeat(max - 1)),
+                vec!["a".repeat(max - 1), "é".to_string()],
+            ),
+        ];
+
+        for (input, expected) in test_cases {
+            let output = Response::D(input).to_string();
+            let lines: Vec<_> = output.split('\n').collect();
+            assert!(lines.iter().all(|l| l.len() <= MAX_LINE_LENGTH));
+            assert_eq!(
+                lines,
+                expected
+                    .iter()
+                    .map(|l| format!("D {l}"))
+                    .collect::<Vec<_>>(),
+            );
+        }
+    }
+
     #[test]
     fn escape() {
         [
@@ -97,11 +364,126 @@ mod test {
             ("a\nb\r\nc\n", "a%0Ab%0D%0Ac%0A"),
             ("a\nb\r\nc\nd", "a%0Ab%0D%0Ac%0Ad"),
             ("a\nb\r\nc\nd\n", "a%0Ab%0D%0Ac%0Ad%0A"),
+            ("é%", "é%25"),
+            ("a\tb", "a%09b"),
+            ("a\0b", "a%00b"),
+            ("a\x7fb", "a%7Fb"),
+            ("\x1b[0m", "%1B[0m"),
+            ("a b~", "a b~"),
         ]
         .into_iter()
         .map(|(input, expected)| (input, Cow::from(expected)))
         .for_each(|(input, expected)| {
             assert_eq!(super::escape(input), *expected);
+            let mut escaped = "reused".to_string();
+            escaped.clear();
+            super::escape_into(input, &mut escaped).unwrap();
+            assert_eq!(escaped, *expected);
+            assert_eq!(super::unescape(&expected).unwrap(), input);
         });
     }
+
+    #[test]
+    fn unescape() {
+        let test_cases = vec![
+            ("", Some("")),
+            ("a%20b", Some("a b")),
+            ("%C3%A9", Some("é")),
+            ("100%", Some("100%")),
+            ("%FF", None),
+        ];
+
+        for (input, expected) in test_cases {
+            assert_eq!(super::unescape(input).ok().as_deref(), expected, "{input}");
+        }
+    }
+
+    #[test]
+    fn from_str() {
+        let test_cases = vec![
+            ("OK", Some(Response::Ok(None))),
+            (
+                "OK Greetings",
+                Some(Response::Ok(Some("Greetings".to_string()))),
+            ),
+            (
+                "ERR 83886179 Operation cancelled",
+                Some(Response::Err(83_886_179, "Operation cancelled".to_string())),
+            ),
+            ("ERR 1", Some(Response::Err(1, String::new()))),
+            ("D 1234", Some(Response::D("1234".to_string()))),
+            ("D 12%2534%0A", Some(Response::D("12%34\n".to_string()))),
+            ("D", Some(Response::D(String::new()))),
+            (
+                "S PIN_REPEATED 1",
+                Some(Response::S("PIN_REPEATED".to_string(), "1".to_string())),
+            ),
+            (
+                "S PASSWORD_FROM_CACHE",
+                Some(Response::S(
+                    "PASSWORD_FROM_CACHE".to_string(),
+                    String::new(),
+                )),
+            ),
+            (
+                "INQUIRE QUALITY x%25",
+                Some(Response::Inquire("QUALITY".to_string(), "x%".to_string())),
+            ),
+            (
+                "INQUIRE GENPIN",
+                Some(Response::Inquire("GENPIN".to_string(), String::new())),
+            ),
+            ("# comment", Some(Response::Comment("comment".to_string()))),
+            ("", None),
+            ("ERR", None),
+            ("ERR code", None),
+            ("S", None),
+            ("INQUIRE", None),
+            ("GETPIN", None),
+            ("OKAY", None),
+        ];
+
+        for (input, expected) in test_cases {
+            assert_eq!(input.parse::<Response>().ok(), expected, "{input}");
+        }
+    }
+
+    #[test]
+    fn from_str_round_trips() {
+        let test_cases = vec![
+            Response::Ok(None),
+            Response::Ok(Some("closing connection".to_string())),
+            Response::Err(536_870_981, "Unknown command".to_string()),
+            Response::D("line 1\nline 2 100%".to_string()),
+            Response::D("é".to_string()),
+            Response::S("PIN_REPEATED".to_string(), "1".to_string()),
+            Response::S("PASSWORD_FROM_CACHE".to_string(), String::new()),
+            Response::Comment("a comment".to_string()),
+            Response::Inquire("QUALITY".to_string(), "pass word%\n".to_string()),
+            Response::Inquire("GENPIN".to_string(), String::new()),
+        ];
+
+        for resp in test_cases {
+            assert_eq!(resp.to_string().parse::<Response>().unwrap(), resp);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialize() {
+        let test_cases = vec![
+            (Response::ok(), r#"{"Ok":null}"#),
+            (Response::err(99, "oops"), r#"{"Err":[99,"oops"]}"#),
+            (Response::D("x".to_string()), r#"{"D":"x"}"#),
+            (Response::SecretD("1234".into()), r#"{"SecretD":"***"}"#),
+            (
+                Response::password_from_cache(),
+                r#"{"S":["PASSWORD_FROM_CACHE",""]}"#,
+            ),
+        ];
+
+        for (resp, expected) in test_cases {
+            assert_eq!(serde_json::to_string(&resp).unwrap(), expected);
+        }
+    }
 }
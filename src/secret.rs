@@ -0,0 +1,109 @@
+//! Handling of secret values like passphrases.
+
+use std::fmt::{self, Debug, Formatter};
+use zeroize::{Zeroize, Zeroizing};
+
+/// A string holding a secret such as a passphrase. It is wiped from memory when dropped, is
+/// compared in constant time, and is never printed by `Debug`.
+#[derive(Clone, Default)]
+pub struct SecretString(Zeroizing<String>);
+
+impl SecretString {
+    /// Get the secret value.
+    #[must_use]
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    /// Remove every space (U+0020), keeping any other whitespace.
+    pub(crate) fn remove_spaces(&mut self) {
+        // In place, so that no copy is left behind
+        self.0.retain(|c| c != ' ');
+    }
+
+    /// Remove a single trailing `\n` or `\r\n`.
+    pub(crate) fn trim_trailing_newline(&mut self) {
+        if self.0.ends_with('\n') {
+            self.0.pop();
+            if self.0.ends_with('\r') {
+                self.0.pop();
+            }
+        }
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(s: String) -> Self {
+        Self(Zeroizing::new(s))
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(s: &str) -> Self {
+        Self::from(s.to_string())
+    }
+}
+
+impl TryFrom<Vec<u8>> for SecretString {
+    type Error = std::str::Utf8Error;
+
+    /// Take ownership of a buffer holding a secret, wiping it if it is not valid UTF-8.
+    fn try_from(bytes: Vec<u8>) -> Result<Self, Self::Error> {
+        String::from_utf8(bytes).map(Self::from).map_err(|e| {
+            let utf8_error = e.utf8_error();
+            e.into_bytes().zeroize();
+            utf8_error
+        })
+    }
+}
+
+impl PartialEq for SecretString {
+    fn eq(&self, other: &Self) -> bool {
+        constant_time_eq(self.0.as_bytes(), other.0.as_bytes())
+    }
+}
+
+impl Eq for SecretString {}
+
+impl Debug for SecretString {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "SecretString(***)")
+    }
+}
+
+/// Compare two byte strings in time that depends only on their lengths.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::SecretString;
+
+    #[test]
+    fn constant_time_eq() {
+        use super::constant_time_eq;
+
+        assert!(constant_time_eq(b"", b""));
+        assert!(constant_time_eq(b"1234", b"1234"));
+        assert!(!constant_time_eq(b"1234", b"1235"));
+        assert!(!constant_time_eq(b"1234", b"12345"));
+    }
+
+    #[test]
+    fn debug_is_redacted() {
+        assert_eq!(
+            format!("{:?}", SecretString::from("1234")),
+            "SecretString(***)"
+        );
+    }
+
+    #[test]
+    fn try_from_bytes() {
+        assert_eq!(
+            SecretString::try_from(b"1234".to_vec()).unwrap(),
+            SecretString::from("1234")
+        );
+        assert!(SecretString::try_from(vec![0xff, 0xfe]).is_err());
+    }
+}
@@ -0,0 +1,119 @@
+//! Getting PINs from the Secret Service, e.g. GNOME Keyring, like the libsecret support of
+//! pinentry.
+
+use crate::{
+    config::Config,
+    keyring::{self, Keyring},
+    provider::{self, ConfiguredProvider, Origin, PinProvider, Rate},
+    secret::SecretString,
+    GetPinError, State,
+};
+use secret_service::{blocking::SecretService, EncryptionType};
+use std::collections::HashMap;
+
+/// The schema of the items, the same as pinentry's, so that PINs stored by either are found.
+const SCHEMA: &str = "org.gnupg.Passphrase";
+
+/// A provider that looks the PIN up in the Secret Service by the key it unlocks, and asks `prompt`
+/// for it if it isn't stored there.
+///
+/// The Secret Service is used like every keyring, as `keyring::get_pin` describes.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SecretServiceProvider<P> {
+    prompt: P,
+    store: bool,
+}
+
+impl<P: PinProvider> SecretServiceProvider<P> {
+    #[must_use]
+    pub fn new(prompt: P) -> Self {
+        Self {
+            prompt,
+            store: false,
+        }
+    }
+
+    /// Store the PIN the user entered if it wasn't found.
+    #[must_use]
+    pub fn store(mut self, store: bool) -> Self {
+        self.store = store;
+        self
+    }
+}
+
+impl From<&Config> for SecretServiceProvider<Box<ConfiguredProvider>> {
+    fn from(config: &Config) -> Self {
+        Self::new(Box::new(keyring::prompt(config))).store(config.store_in_keyring)
+    }
+}
+
+impl<P: PinProvider> PinProvider for SecretServiceProvider<P> {
+    /// Get the PIN from the Secret Service, or else from `prompt`
+    ///
+    /// # Errors
+    /// Any error of `prompt`
+    fn get_pin(&self, state: &State) -> Result<SecretString, GetPinError> {
+        self.get_pin_with_origin(state, None).map(|(pin, _)| pin)
+    }
+
+    fn get_pin_with_quality(
+        &self,
+        state: &State,
+        quality: &mut dyn FnMut(&SecretString) -> Result<Option<i32>, GetPinError>,
+    ) -> Result<SecretString, GetPinError> {
+        self.get_pin_with_origin(state, Some(quality))
+            .map(|(pin, _)| pin)
+    }
+
+    fn get_pin_with_origin(
+        &self,
+        state: &State,
+        quality: Option<&mut Rate<'_>>,
+    ) -> Result<(SecretString, Origin), GetPinError> {
+        keyring::get_pin(&Service, state, self.store, || {
+            provider::ask(&self.prompt, state, quality)
+        })
+    }
+}
+
+struct Service;
+
+impl Keyring for Service {
+    const NAME: &'static str = "Secret Service";
+
+    type Error = secret_service::Error;
+
+    fn lookup(&self, keygrip: &str) -> Result<Option<SecretString>, Self::Error> {
+        let service = SecretService::connect(EncryptionType::Dh)?;
+        let found = service.search_items(attributes(keygrip))?;
+        let Some(item) = found.unlocked.first().or(found.locked.first()) else {
+            return Ok(None);
+        };
+        item.ensure_unlocked()?;
+        match SecretString::try_from(item.get_secret()?) {
+            Ok(pin) => Ok(Some(pin)),
+            Err(e) => {
+                log::warn!("Ignoring the PIN in the Secret Service, as it is not valid UTF-8: {e}");
+                Ok(None)
+            }
+        }
+    }
+
+    fn store(&self, keygrip: &str, pin: &SecretString) -> Result<(), Self::Error> {
+        let service = SecretService::connect(EncryptionType::Dh)?;
+        let collection = service.get_default_collection()?;
+        collection.ensure_unlocked()?;
+        collection.create_item(
+            &format!("GnuPG: {keygrip}"),
+            attributes(keygrip),
+            pin.expose().as_bytes(),
+            true,
+            "text/plain",
+        )?;
+        Ok(())
+    }
+}
+
+fn attributes(keygrip: &str) -> HashMap<&str, &str> {
+    HashMap::from([("xdg:schema", SCHEMA), ("keygrip", keygrip)])
+}
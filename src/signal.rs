@@ -0,0 +1,210 @@
+//! Shutting down cleanly when we are asked to terminate with `SIGINT` or `SIGTERM`, reloading the
+//! config on `SIGHUP` and reporting metrics on `SIGUSR1`.
+//!
+//! The signal handler only sets a flag and kills the command showing the dialog, if there is one.
+//! The listener checks the flag so it can send the client a well-formed response before exiting.
+
+use std::{
+    io::{self, Read},
+    sync::atomic::{AtomicBool, AtomicI32, Ordering},
+};
+
+/// Whether we received a signal asking us to terminate.
+static TERMINATING: AtomicBool = AtomicBool::new(false);
+
+/// Whether we received `SIGHUP` since the config was last reloaded.
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+/// Whether we received `SIGUSR1` since the metrics were last reported.
+static REPORT: AtomicBool = AtomicBool::new(false);
+
+/// The process ID of the command showing the dialog, or 0 if there is none.
+static CHILD: AtomicI32 = AtomicI32::new(0);
+
+/// Handle `SIGINT` and `SIGTERM` by killing the command showing the dialog and interrupting
+/// blocked reads, instead of exiting immediately.
+///
+/// # Errors
+/// If the signal handlers could not be installed.
+#[cfg(unix)]
+pub fn install() -> io::Result<()> {
+    set_handler(&[libc::SIGINT, libc::SIGTERM], handle)
+}
+
+/// Handle `SIGHUP` by asking for the config to be reloaded, instead of exiting. A blocked
+/// `accept` is interrupted, so the reload can happen before the next connection arrives.
+///
+/// # Errors
+/// If the signal handler could not be installed.
+#[cfg(unix)]
+pub fn install_reload() -> io::Result<()> {
+    set_handler(&[libc::SIGHUP], handle_reload)
+}
+
+/// Handle `SIGUSR1` by asking for the metrics to be reported, instead of exiting. Like `SIGHUP`,
+/// it interrupts a blocked `accept`.
+///
+/// # Errors
+/// If the signal handler could not be installed.
+#[cfg(unix)]
+pub fn install_report() -> io::Result<()> {
+    set_handler(&[libc::SIGUSR1], handle_report)
+}
+
+#[cfg(unix)]
+fn set_handler(signals: &[libc::c_int], handler: extern "C" fn(libc::c_int)) -> io::Result<()> {
+    // SAFETY: the handlers only do async-signal-safe things, and all of the fields of a
+    // `sigaction` are valid when zeroed
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handler as libc::sighandler_t;
+        // No `SA_RESTART`, so a blocked read is interrupted
+        action.sa_flags = 0;
+        libc::sigemptyset(&raw mut action.sa_mask);
+        for &signal in signals {
+            if libc::sigaction(signal, &raw const action, std::ptr::null_mut()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+extern "C" fn handle(_signal: libc::c_int) {
+    TERMINATING.store(true, Ordering::SeqCst);
+    kill(CHILD.load(Ordering::SeqCst));
+}
+
+#[cfg(unix)]
+extern "C" fn handle_reload(_signal: libc::c_int) {
+    RELOAD.store(true, Ordering::SeqCst);
+}
+
+#[cfg(unix)]
+extern "C" fn handle_report(_signal: libc::c_int) {
+    REPORT.store(true, Ordering::SeqCst);
+}
+
+/// Whether we received a signal asking us to terminate.
+#[must_use]
+pub fn terminating() -> bool {
+    TERMINATING.load(Ordering::SeqCst)
+}
+
+/// Whether we received `SIGHUP` since the last call, asking us to reload the config.
+#[must_use]
+pub fn take_reload() -> bool {
+    RELOAD.swap(false, Ordering::SeqCst)
+}
+
+/// Whether we received `SIGUSR1` since the last call, asking us to report the metrics.
+#[must_use]
+pub fn take_report() -> bool {
+    REPORT.swap(false, Ordering::SeqCst)
+}
+
+/// Remembers a command showing a dialog so it can be killed if we are asked to terminate, until
+/// it is dropped.
+pub(crate) struct TrackedChild(());
+
+impl TrackedChild {
+    pub(crate) fn new(child: &std::process::Child) -> Self {
+        let pid = i32::try_from(child.id()).unwrap_or_default();
+        CHILD.store(pid, Ordering::SeqCst);
+        // The signal may have arrived before the child was remembered
+        if terminating() {
+            kill(pid);
+        }
+        Self(())
+    }
+}
+
+impl Drop for TrackedChild {
+    fn drop(&mut self) {
+        CHILD.store(0, Ordering::SeqCst);
+    }
+}
+
+/// Ask the process `pid` to terminate, if it is positive.
+pub(crate) fn kill(pid: i32) {
+    #[cfg(unix)]
+    if pid > 0 {
+        // SAFETY: `kill` is async-signal-safe and doesn't access any memory of ours
+        unsafe {
+            libc::kill(pid, libc::SIGTERM);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = pid;
+}
+
+/// A reader whose reads fail if they are interrupted by a signal asking us to terminate, instead
+/// of being retried, so that waiting for a request doesn't keep us from shutting down.
+#[derive(Debug)]
+pub struct Interruptible<R>(pub R);
+
+impl<R: Read> Read for Interruptible<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.read(buf) {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted && terminating() => {
+                Err(io::Error::other("Terminated by a signal"))
+            }
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Interruptible;
+    use std::io::{self, BufRead, BufReader, Read};
+
+    /// A reader that is interrupted before every read.
+    struct Interrupted<'a> {
+        data: &'a [u8],
+        interrupt: bool,
+    }
+
+    impl Read for Interrupted<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.interrupt = !self.interrupt;
+            if self.interrupt {
+                Err(io::ErrorKind::Interrupted.into())
+            } else {
+                self.data.read(buf)
+            }
+        }
+    }
+
+    #[test]
+    fn reload() {
+        super::install_reload().unwrap();
+        assert!(!super::take_reload());
+        // SAFETY: `raise` doesn't access any memory of ours, and the handler only sets a flag
+        assert_eq!(unsafe { libc::raise(libc::SIGHUP) }, 0);
+        assert!(super::take_reload());
+        assert!(!super::take_reload());
+    }
+
+    #[test]
+    fn report() {
+        super::install_report().unwrap();
+        assert!(!super::take_report());
+        // SAFETY: `raise` doesn't access any memory of ours, and the handler only sets a flag
+        assert_eq!(unsafe { libc::raise(libc::SIGUSR1) }, 0);
+        assert!(super::take_report());
+        assert!(!super::take_report());
+    }
+
+    #[test]
+    fn interruptible_retries_without_signal() {
+        let mut reader = BufReader::new(Interruptible(Interrupted {
+            data: b"GETPIN\nBYE\n",
+            interrupt: false,
+        }));
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "GETPIN\n");
+    }
+}
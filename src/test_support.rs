@@ -0,0 +1,108 @@
+//! Helpers for testing dialogs by driving whole Assuan sessions, or a listener one request at a
+//! time.
+
+use crate::{
+    config::Config, connection::NoInquire, provider::PinProvider, request, response::Response,
+    secret::SecretString, serve, Action, GetPinError, Listener, State,
+};
+
+/// A provider that always answers with the same PIN, without showing a dialog.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FixedPin(pub &'static str);
+
+impl PinProvider for FixedPin {
+    fn get_pin(&self, _state: &State) -> Result<SecretString, GetPinError> {
+        Ok(SecretString::from(self.0))
+    }
+}
+
+/// Serve a session in which the client sends `input` and then hangs up, getting PINs from
+/// `provider`, and return everything sent to the client.
+///
+/// ```
+/// use elephantine::config::Config;
+/// use elephantine::test_support::{run_session, FixedPin};
+///
+/// assert_eq!(
+///     run_session(Config::default(), FixedPin("1234"), "GETPIN\nBYE\n"),
+///     "OK Greetings from Elephantine\nD 1234\nOK\nOK closing connection\n",
+/// );
+/// ```
+///
+/// # Panics
+/// If the session fails, which can't happen as the input and output are in memory, or if the
+/// output isn't valid UTF-8.
+#[must_use]
+pub fn run_session(config: Config, provider: impl PinProvider, input: &str) -> String {
+    let mut output = vec![];
+    serve(config, provider, input.as_bytes(), &mut output).expect("Failed to serve the session");
+    String::from_utf8(output).expect("The output is not valid UTF-8")
+}
+
+/// Handle each of `requests` in turn with one listener getting PINs from `provider`, and return
+/// what it would reply to each and whether it would close the connection afterwards.
+///
+/// Unlike [`run_session`], every request is handled, even after one that closes the connection.
+/// Inquiries are answered with [`NoInquire`].
+///
+/// ```
+/// use elephantine::{config::Config, provider::PinProvider, response::Response};
+/// use elephantine::{secret::SecretString, test_support::handle_requests};
+/// use elephantine::{Action, GetPinError, State};
+///
+/// #[derive(Debug)]
+/// struct Canceled;
+///
+/// impl PinProvider for Canceled {
+///     fn get_pin(&self, _state: &State) -> Result<SecretString, GetPinError> {
+///         Err(GetPinError::Canceled)
+///     }
+/// }
+///
+/// assert_eq!(
+///     handle_requests(Config::default(), Canceled, &["SETDESC Unlock", "BYE"]),
+///     vec![
+///         Action::Next(vec![Response::ok()]),
+///         Action::Stop(vec![Response::ok_msg("closing connection")]),
+///     ],
+/// );
+/// ```
+///
+/// # Panics
+/// If one of `requests` can't be parsed.
+#[must_use]
+pub fn handle_requests<P: PinProvider>(
+    config: Config,
+    provider: P,
+    requests: &[&str],
+) -> Vec<Action<Vec<Response>>> {
+    let mut listener = Listener::new(config, provider);
+    requests
+        .iter()
+        .map(|req| {
+            let req = request::parse(req).unwrap_or_else(|e| panic!("Invalid request {req}: {e}"));
+            listener.handle_req(req, &mut NoInquire)
+        })
+        .collect()
+}
+
+/// The state of a listener with the default config after the client sent `requests`, to describe
+/// a dialog to a provider.
+///
+/// ```
+/// use elephantine::test_support::state;
+///
+/// assert_eq!(state(&["SETDESC Unlock%0Athe key"]).desc(), Some("Unlock\nthe key"));
+/// ```
+///
+/// # Panics
+/// If one of `requests` can't be parsed.
+#[must_use]
+pub fn state(requests: &[&str]) -> State {
+    let mut listener = Listener::from(Config::default());
+    for req in requests {
+        let req = request::parse(req).unwrap_or_else(|e| panic!("Invalid request {req}: {e}"));
+        listener.handle_req(req, &mut NoInquire);
+    }
+    listener.state
+}
@@ -0,0 +1,454 @@
+//! Reading PINs from a terminal, like `pinentry-tty`.
+
+use crate::{
+    config::{Config, TtyAlert},
+    provider::PinProvider,
+    secret::SecretString,
+    signal, GetPinError, State,
+};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    mem::MaybeUninit,
+    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+    thread,
+    time::{Duration, Instant},
+};
+use zeroize::Zeroizing;
+
+/// The terminal used if none is set or in `GPG_TTY`.
+const DEFAULT_TTY: &str = "/dev/tty";
+
+/// The variable gpg exports with the terminal it runs in.
+pub(crate) const GPG_TTY: &str = "GPG_TTY";
+
+/// The prompt shown if the client didn't set one with `SETPROMPT` or `OPTION default-prompt`.
+const DEFAULT_PROMPT: &str = "PIN:";
+
+/// The initial capacity of the buffer the PIN is read into, so that reading a PIN does not leave
+/// copies behind in memory freed by reallocating it.
+const PIN_BUFFER_CAPACITY: usize = 256;
+
+/// Turns on reverse video, which terminals show as their visual bell.
+const FLASH_ON: &[u8] = b"\x1b[?5h";
+
+/// Turns off reverse video.
+const FLASH_OFF: &[u8] = b"\x1b[?5l";
+
+/// How long the screen is flashed for.
+const FLASH_DURATION: Duration = Duration::from_millis(100);
+
+/// A provider that reads the PIN from the client's terminal with echo turned off.
+///
+/// The terminal is the one set with `OPTION ttyname` or `--ttyname`, or else the one in
+/// `GPG_TTY`, or else the controlling terminal. The description, any error and the prompt are
+/// printed before the PIN is read.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TtyProvider {
+    timeout: Option<Duration>,
+}
+
+impl TtyProvider {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Give up if the user has not entered the PIN after `timeout`.
+    #[must_use]
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+impl From<&Config> for TtyProvider {
+    fn from(config: &Config) -> Self {
+        Self::new().timeout(config.timeout)
+    }
+}
+
+impl PinProvider for TtyProvider {
+    /// Get the PIN from the terminal
+    ///
+    /// # Errors
+    /// `GetPinError::Setup` if the terminal could not be opened, configured or read
+    /// `GetPinError::Output` if the PIN is not valid UTF-8
+    /// `GetPinError::Timeout` if the user did not enter the PIN before the timeout
+    /// `GetPinError::Canceled` if the input ended before a whole line was entered
+    fn get_pin(&self, state: &State) -> Result<SecretString, GetPinError> {
+        let gpg_tty = std::env::var(GPG_TTY).ok();
+        let path = path(state, gpg_tty.as_deref());
+        let setup_err = |e| GetPinError::Setup(e, vec![path.to_string()]);
+        let timeout = state.timeout(self.timeout);
+
+        let tty = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(path)
+            .map_err(setup_err)?;
+        let echo_off = EchoOff::new(&tty).map_err(setup_err)?;
+        prompt(&tty, state).map_err(setup_err)?;
+        let line = read_line(&tty, timeout.map(|t| Instant::now() + t));
+        drop(echo_off);
+        // The newline the user typed was not echoed
+        let _ = writeln!(&tty);
+
+        match line {
+            Ok(Some(line)) => SecretString::try_from(line.to_vec()).map_err(GetPinError::Output),
+            Ok(None) => Err(GetPinError::Timeout(timeout.unwrap_or_default())),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Err(GetPinError::Canceled),
+            // Interrupted by a signal asking us to terminate
+            Err(_) if signal::terminating() => Err(GetPinError::Canceled),
+            Err(e) => Err(setup_err(e)),
+        }
+    }
+}
+
+/// The terminal to use: the one set with `OPTION ttyname`, which overrides `--ttyname`, or else
+/// `gpg_tty`, the value of `GPG_TTY`, or else the controlling terminal.
+pub(crate) fn path<'a>(state: &'a State, gpg_tty: Option<&'a str>) -> &'a str {
+    state
+        .option("ttyname")
+        .or(gpg_tty.filter(|tty| !tty.is_empty()))
+        .unwrap_or(DEFAULT_TTY)
+}
+
+/// Alert the user on the terminal at `path` by ringing the bell or flashing the screen.
+pub(crate) fn alert(mode: TtyAlert, path: &str) -> io::Result<()> {
+    if mode == TtyAlert::None {
+        return Ok(());
+    }
+    let mut tty = OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(path)?;
+    match mode {
+        TtyAlert::None => {}
+        TtyAlert::Beep => tty.write_all(b"\x07")?,
+        TtyAlert::Flash => {
+            tty.write_all(FLASH_ON)?;
+            tty.flush()?;
+            thread::sleep(FLASH_DURATION);
+            tty.write_all(FLASH_OFF)?;
+        }
+    }
+    tty.flush()
+}
+
+/// Turns off echo on a terminal until it is dropped.
+struct EchoOff<'a> {
+    tty: &'a File,
+    original: libc::termios,
+}
+
+impl<'a> EchoOff<'a> {
+    fn new(tty: &'a File) -> io::Result<Self> {
+        let mut original = MaybeUninit::uninit();
+        // SAFETY: `tcgetattr` only writes to the termios passed to it
+        if unsafe { libc::tcgetattr(tty.as_raw_fd(), original.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `tcgetattr` succeeded, so it initialised the termios
+        let original = unsafe { original.assume_init() };
+
+        let mut termios = original;
+        termios.c_lflag &= !libc::ECHO;
+        // Discard anything typed before the prompt is shown
+        set_attr(tty, libc::TCSAFLUSH, &termios)?;
+        Ok(Self { tty, original })
+    }
+}
+
+impl Drop for EchoOff<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = set_attr(self.tty, libc::TCSANOW, &self.original) {
+            log::warn!("Failed to restore the terminal: {e}");
+        }
+    }
+}
+
+fn set_attr(tty: &File, action: libc::c_int, termios: &libc::termios) -> io::Result<()> {
+    // SAFETY: `tcsetattr` only reads the termios passed to it
+    if unsafe { libc::tcsetattr(tty.as_raw_fd(), action, termios) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Show the description, any error from the last attempt and the prompt.
+fn prompt(mut tty: &File, state: &State) -> io::Result<()> {
+    if let Some(desc) = &state.desc {
+        writeln!(tty, "{desc}")?;
+    }
+    if let Some(error) = state.repeat_error().or(state.error.as_deref()) {
+        writeln!(tty, "{error}")?;
+    }
+    write!(
+        tty,
+        "{} ",
+        state.effective_labels().prompt.unwrap_or(DEFAULT_PROMPT)
+    )?;
+    tty.flush()
+}
+
+/// Read a line from `tty` without its line ending.
+///
+/// Returns `None` if `deadline` passed before a whole line was read, and an `UnexpectedEof`
+/// error if the input ended, e.g. because the user pressed Ctrl-D.
+fn read_line(mut tty: &File, deadline: Option<Instant>) -> io::Result<Option<Zeroizing<Vec<u8>>>> {
+    let mut line = Zeroizing::new(Vec::with_capacity(PIN_BUFFER_CAPACITY));
+    // Read a byte at a time, as a buffered reader would keep copies of the PIN
+    let mut byte = Zeroizing::new([0]);
+    loop {
+        if !wait_readable(tty, deadline)? {
+            return Ok(None);
+        }
+        if tty.read(&mut *byte)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        match byte[0] {
+            b'\n' => break,
+            b => line.push(b),
+        }
+    }
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+/// Wait until there is input on `tty`. Returns false if `deadline` passed first.
+fn wait_readable(tty: &File, deadline: Option<Instant>) -> io::Result<bool> {
+    let Some(deadline) = deadline else {
+        return Ok(true);
+    };
+    let mut fd = libc::pollfd {
+        fd: tty.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let millis = libc::c_int::try_from(remaining.as_millis()).unwrap_or(libc::c_int::MAX);
+        // SAFETY: `poll` only accesses the one `pollfd` passed to it
+        match unsafe { libc::poll(&raw mut fd, 1, millis) } {
+            0 => return Ok(false),
+            n if n > 0 => return Ok(true),
+            _ => {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted || signal::terminating() {
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TtyProvider;
+    use crate::{
+        config::{Config, TtyAlert},
+        connection::NoInquire,
+        provider::ConfiguredProvider,
+        provider::PinProvider,
+        request::parse,
+        test_support::{run_session, state},
+        GetPinError, Listener,
+    };
+    use std::{
+        ffi::CStr,
+        fs::File,
+        io::{Read, Write},
+        mem::MaybeUninit,
+        os::fd::{AsRawFd, FromRawFd},
+        thread,
+        time::Duration,
+    };
+
+    /// Open a pseudoterminal, returning its master and the path of its slave.
+    fn pty() -> (File, String) {
+        // SAFETY: the fd is checked before it is used and owned by the returned file
+        unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            assert!(fd >= 0);
+            assert_eq!(libc::grantpt(fd), 0);
+            assert_eq!(libc::unlockpt(fd), 0);
+            let name = CStr::from_ptr(libc::ptsname(fd))
+                .to_string_lossy()
+                .into_owned();
+            (File::from_raw_fd(fd), name)
+        }
+    }
+
+    fn echo_on(master: &File) -> bool {
+        let mut termios = MaybeUninit::uninit();
+        // SAFETY: `tcgetattr` initialises the termios if it succeeds
+        unsafe {
+            assert_eq!(libc::tcgetattr(master.as_raw_fd(), termios.as_mut_ptr()), 0);
+            termios.assume_init().c_lflag & libc::ECHO != 0
+        }
+    }
+
+    #[test]
+    fn get_pin() {
+        let test_cases = vec![
+            (Some("1234\n"), Ok("1234")),
+            (Some("12 34\r"), Ok("12 34")),
+            (Some("\n"), Ok("")),
+            // Ctrl-D
+            (Some("\x04"), Err("canceled")),
+            (None, Err("timeout")),
+        ];
+
+        for (input, expected) in test_cases {
+            let (mut master, slave) = pty();
+            // Keep the terminal open, so the master can be read after the provider closes it
+            let _slave = File::options().read(true).write(true).open(&slave).unwrap();
+            let state = state(&[
+                &format!("OPTION ttyname={slave}"),
+                "SETDESC Enter%0Athe PIN",
+                "SETPROMPT Passphrase:",
+            ]);
+
+            let typist = thread::spawn(move || {
+                // Wait for the prompt, as anything typed before it is discarded
+                let mut shown = vec![];
+                let mut buf = [0; 64];
+                while !shown.ends_with(b"Passphrase: ") {
+                    let n = master.read(&mut buf).unwrap();
+                    shown.extend_from_slice(&buf[..n]);
+                }
+                if let Some(input) = input {
+                    master.write_all(input.as_bytes()).unwrap();
+                }
+                (master, shown)
+            });
+            let timeout = Duration::from_millis(if input.is_some() { 5000 } else { 100 });
+            let pin = TtyProvider::new().timeout(Some(timeout)).get_pin(&state);
+            let (mut master, shown) = typist.join().unwrap();
+
+            match expected {
+                Ok(expected) => assert_eq!(pin.unwrap().expose(), expected, "{input:?}"),
+                Err("canceled") => assert!(matches!(pin, Err(GetPinError::Canceled)), "{input:?}"),
+                Err(_) => assert!(matches!(pin, Err(GetPinError::Timeout(_))), "{input:?}"),
+            }
+            assert_eq!(shown, b"Enter\r\nthe PIN\r\nPassphrase: ");
+            // Only the newline after the PIN, which wasn't echoed
+            let mut rest = [0; 64];
+            let n = master.read(&mut rest).unwrap();
+            assert_eq!(&rest[..n], b"\r\n", "{input:?}");
+            assert!(echo_on(&master), "{input:?}");
+        }
+    }
+
+    /// What was written to the terminal, read from its master until a marker written to `tty`,
+    /// as reading blocks until there is output.
+    fn written(master: &mut File, tty: &mut File) -> Vec<u8> {
+        tty.write_all(b".").unwrap();
+        let mut written = vec![];
+        let mut buf = [0; 64];
+        while !written.ends_with(b".") {
+            let n = master.read(&mut buf).unwrap();
+            written.extend_from_slice(&buf[..n]);
+        }
+        written.pop();
+        written
+    }
+
+    #[test]
+    fn path() {
+        let test_cases = vec![
+            (
+                Some("/dev/pts/1"),
+                Some("/dev/pts/2"),
+                Some("/dev/pts/3"),
+                "/dev/pts/1",
+            ),
+            (Some("/dev/pts/1"), None, Some("/dev/pts/3"), "/dev/pts/1"),
+            (None, Some("/dev/pts/2"), Some("/dev/pts/3"), "/dev/pts/2"),
+            (None, None, Some("/dev/pts/3"), "/dev/pts/3"),
+            (None, None, Some(""), "/dev/tty"),
+            (None, None, None, "/dev/tty"),
+        ];
+
+        for (option, ttyname, gpg_tty, expected) in test_cases {
+            let mut listener = Listener::from(Config {
+                ttyname: ttyname.map(ToString::to_string),
+                ..Default::default()
+            });
+            if let Some(option) = option {
+                let req = format!("OPTION ttyname={option}");
+                listener.handle_req(parse(&req).unwrap(), &mut NoInquire);
+            }
+            assert_eq!(
+                super::path(&listener.state, gpg_tty),
+                expected,
+                "{option:?} {ttyname:?} {gpg_tty:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn alert() {
+        let test_cases = vec![
+            (TtyAlert::None, ""),
+            (TtyAlert::Beep, "\x07"),
+            (TtyAlert::Flash, "\x1b[?5h\x1b[?5l"),
+        ];
+
+        for (mode, expected) in test_cases {
+            let (mut master, slave) = pty();
+            let mut tty = File::options().write(true).open(&slave).unwrap();
+            super::alert(mode, &slave).unwrap();
+            assert_eq!(
+                written(&mut master, &mut tty),
+                expected.as_bytes(),
+                "{mode:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn listen_alerts_on_error() {
+        let test_cases = vec![
+            ("GETPIN\n", TtyAlert::Beep, ""),
+            ("SETERROR Bad passphrase\nGETPIN\n", TtyAlert::None, ""),
+            ("SETERROR Bad passphrase\nGETPIN\n", TtyAlert::Beep, "\x07"),
+        ];
+
+        for (input, ttyalert, expected) in test_cases {
+            let (mut master, slave) = pty();
+            let mut tty = File::options().write(true).open(&slave).unwrap();
+            let config = Config {
+                command: vec!["echo".to_string(), "1234".to_string()].into(),
+                ttyalert,
+                ..Default::default()
+            };
+            let provider = ConfiguredProvider::from(&config);
+            let output = run_session(
+                config,
+                provider,
+                &format!("OPTION ttyname={slave}\n{input}"),
+            );
+            assert!(output.ends_with("D 1234\nOK\n"), "{input:?}");
+            assert_eq!(
+                written(&mut master, &mut tty),
+                expected.as_bytes(),
+                "{input:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn get_pin_without_tty() {
+        let state = state(&["OPTION ttyname=/nonexistent/tty"]);
+        assert!(matches!(
+            TtyProvider::new().get_pin(&state),
+            Err(GetPinError::Setup(..))
+        ));
+    }
+}